@@ -1348,6 +1348,11 @@ impl Thread {
             tool_choice: None,
             stop: Vec::new(),
             temperature: AgentSettings::temperature_for_model(&model, cx),
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: true,
         };
 
@@ -1514,6 +1519,11 @@ impl Thread {
             tool_choice: None,
             stop: Vec::new(),
             temperature: AgentSettings::temperature_for_model(model, cx),
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: false,
         };
 
@@ -1850,6 +1860,17 @@ impl Thread {
                                     cx,
                                 );
                             }
+                            LanguageModelCompletionEvent::ToolUseIncomplete {
+                                id,
+                                tool_name,
+                                raw_input,
+                            } => {
+                                // Without an ID and a name, the tool can't be run or
+                                // answered, so the call is left out of the thread.
+                                log::error!(
+                                    "The model returned an incomplete tool call (id: {id:?}, tool: {tool_name:?}): {raw_input}"
+                                );
+                            }
                             LanguageModelCompletionEvent::StatusUpdate(status_update) => {
                                 if let Some(completion) = thread
                                     .pending_completions
@@ -2188,9 +2209,13 @@ impl Thread {
         // - If it's a time-based issue (e.g. server overloaded, rate limit exceeded), retry up to 4 times with exponential backoff.
         // - If it's an issue that *might* be fixed by retrying (e.g. internal server error), retry up to 3 times.
         match error {
+            // Without a Retry-After delay from the provider, back off exponentially.
             HttpResponseError {
                 status_code: StatusCode::TOO_MANY_REQUESTS,
                 ..
+            }
+            | RateLimitExceeded {
+                retry_after: None, ..
             } => Some(RetryStrategy::ExponentialBackoff {
                 initial_delay: BASE_RETRY_DELAY,
                 max_attempts: MAX_RETRY_ATTEMPTS,
@@ -1509,6 +1509,11 @@ impl ActiveThread {
                             &configured_model.model,
                             cx,
                         ),
+                        top_p: None,
+                        frequency_penalty: None,
+                        presence_penalty: None,
+                        response_schema: None,
+                        continue_last_message: false,
                         thinking_allowed: true,
                     };
 
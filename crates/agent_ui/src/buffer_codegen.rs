@@ -475,6 +475,11 @@ impl CodegenAlternative {
                 stop: Vec::new(),
                 temperature,
                 messages: vec![request_message],
+                top_p: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+                response_schema: None,
+                continue_last_message: false,
                 thinking_allowed: false,
             }
         }))
@@ -1458,6 +1458,11 @@ impl MessageEditor {
                         tool_choice: None,
                         stop: vec![],
                         temperature: AgentSettings::temperature_for_model(&model.model, cx),
+                        top_p: None,
+                        frequency_penalty: None,
+                        presence_penalty: None,
+                        response_schema: None,
+                        continue_last_message: false,
                         thinking_allowed: true,
                     };
 
@@ -297,6 +297,11 @@ impl TerminalInlineAssistant {
                 tool_choice: None,
                 stop: Vec::new(),
                 temperature,
+                top_p: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+                response_schema: None,
+                continue_last_message: false,
                 thinking_allowed: false,
             }
         }))
@@ -2141,6 +2141,7 @@ impl AssistantContext {
                                     }
                                     LanguageModelCompletionEvent::ToolUse(_) |
                                     LanguageModelCompletionEvent::ToolUseJsonParseError { .. } |
+                                    LanguageModelCompletionEvent::ToolUseIncomplete { .. } |
                                     LanguageModelCompletionEvent::UsageUpdate(_) => {}
                                 }
                             });
@@ -2293,6 +2294,11 @@ impl AssistantContext {
             tool_choice: None,
             stop: Vec::new(),
             temperature: model.and_then(|model| AgentSettings::temperature_for_model(model, cx)),
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: true,
         };
         for message in self.messages(cx) {
@@ -719,6 +719,11 @@ impl EditAgent {
             tools,
             stop: Vec::new(),
             temperature: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: true,
         };
 
@@ -6,7 +6,7 @@ use std::sync::{Arc, LazyLock};
 
 use anyhow::Result;
 use futures::FutureExt as _;
-use gpui::{App, AsyncApp};
+use gpui::{App, AsyncApp, Global};
 use release_channel::ReleaseChannel;
 
 /// An environment variable whose presence indicates that the system keychain
@@ -51,15 +51,29 @@ pub trait CredentialsProvider: Send + Sync {
     ) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
 }
 
+struct GlobalCredentialsProvider(Arc<dyn CredentialsProvider>);
+
+impl Global for GlobalCredentialsProvider {}
+
 impl dyn CredentialsProvider {
     /// Returns the global [`CredentialsProvider`].
     pub fn global(cx: &App) -> Arc<Self> {
+        if let Some(provider) = cx.try_global::<GlobalCredentialsProvider>() {
+            return provider.0.clone();
+        }
+
         // The `CredentialsProvider` trait has `Send + Sync` bounds on it, so it
         // seems like this is a false positive from Clippy.
         #[allow(clippy::arc_with_non_send_sync)]
         Self::new(cx)
     }
 
+    /// Replaces the provider returned by [`Self::global`], such as with one that keeps
+    /// credentials in memory in tests.
+    pub fn set_global(provider: Arc<Self>, cx: &mut App) {
+        cx.set_global(GlobalCredentialsProvider(provider));
+    }
+
     fn new(cx: &App) -> Arc<Self> {
         let use_development_provider = match ReleaseChannel::try_global(cx) {
             Some(ReleaseChannel::Dev) => {
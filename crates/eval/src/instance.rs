@@ -594,6 +594,11 @@ impl ExampleInstance {
                 tools: Vec::new(),
                 tool_choice: None,
                 stop: Vec::new(),
+                top_p: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+                response_schema: None,
+                continue_last_message: false,
                 thinking_allowed: true,
             };
 
@@ -1064,6 +1069,13 @@ pub fn response_events_to_markdown(
                     json_parse_error
                 ));
             }
+            Ok(LanguageModelCompletionEvent::ToolUseIncomplete { raw_input, .. }) => {
+                flush_buffers(&mut response, &mut text_buffer, &mut thinking_buffer);
+                response.push_str(&format!(
+                    "**Error**: incomplete tool use: {}\n\n",
+                    raw_input
+                ));
+            }
             Err(error) => {
                 flush_buffers(&mut response, &mut text_buffer, &mut thinking_buffer);
                 response.push_str(&format!("**Error**: {}\n\n", error));
@@ -1153,6 +1165,14 @@ impl ThreadDialog {
                     )));
                 }
 
+                Ok(LanguageModelCompletionEvent::ToolUseIncomplete { raw_input, .. }) => {
+                    flush_text(&mut current_text, &mut content);
+                    content.push(MessageContent::Text(format!(
+                        "ERROR: incomplete tool use: {}",
+                        raw_input
+                    )));
+                }
+
                 Err(error) => {
                     flush_text(&mut current_text, &mut content);
                     content.push(MessageContent::Text(format!("ERROR: {}", error)));
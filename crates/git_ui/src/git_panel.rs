@@ -1949,6 +1949,11 @@ impl GitPanel {
                     tool_choice: None,
                     stop: Vec::new(),
                     temperature,
+                    top_p: None,
+                    frequency_penalty: None,
+                    presence_penalty: None,
+                    response_schema: None,
+                    continue_last_message: false,
                     thinking_allowed: false,
                 };
 
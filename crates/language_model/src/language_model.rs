@@ -89,6 +89,13 @@ pub enum LanguageModelCompletionEvent {
         raw_input: Arc<str>,
         json_parse_error: String,
     },
+    /// A tool call that ended without the ID or name needed to run it. Other tool calls in
+    /// the same response are unaffected.
+    ToolUseIncomplete {
+        id: Option<LanguageModelToolUseId>,
+        tool_name: Option<Arc<str>>,
+        raw_input: Arc<str>,
+    },
     StartMessage {
         message_id: String,
     },
@@ -98,7 +105,11 @@ pub enum LanguageModelCompletionEvent {
 #[derive(Error, Debug)]
 pub enum LanguageModelCompletionError {
     #[error("prompt too large for context window")]
-    PromptTooLarge { tokens: Option<u64> },
+    PromptTooLarge {
+        tokens: Option<u64>,
+        /// The model's context window, when the provider reports it.
+        max_tokens: Option<u64>,
+    },
     #[error("missing {provider} API key")]
     NoApiKey { provider: LanguageModelProviderName },
     #[error("{provider}'s API rate limit exceeded")]
@@ -211,6 +222,7 @@ impl LanguageModelCompletionError {
             // token limit has been exceeded.
             Self::PromptTooLarge {
                 tokens: Some(tokens),
+                max_tokens: None,
             }
         } else if code == "upstream_http_error" {
             if let Some((upstream_status, inner_message)) =
@@ -252,6 +264,7 @@ impl LanguageModelCompletionError {
             StatusCode::NOT_FOUND => Self::ApiEndpointNotFound { provider },
             StatusCode::PAYLOAD_TOO_LARGE => Self::PromptTooLarge {
                 tokens: parse_prompt_too_long(&message),
+                max_tokens: None,
             },
             StatusCode::TOO_MANY_REQUESTS => Self::RateLimitExceeded {
                 provider,
@@ -328,6 +341,7 @@ impl From<anthropic::ApiError> for LanguageModelCompletionError {
                 NotFoundError => Self::ApiEndpointNotFound { provider },
                 RequestTooLarge => Self::PromptTooLarge {
                     tokens: parse_prompt_too_long(&error.message),
+                    max_tokens: None,
                 },
                 RateLimitError => Self::RateLimitExceeded {
                     provider,
@@ -347,6 +361,19 @@ impl From<anthropic::ApiError> for LanguageModelCompletionError {
     }
 }
 
+/// The features a language model supports, as returned by [`LanguageModel::capabilities`].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct LanguageModelCapabilities {
+    pub tools: bool,
+    pub images: bool,
+    /// Whether the model streams its reasoning separately from its answer.
+    pub thinking: bool,
+    /// Whether responses can be constrained to a JSON schema.
+    pub structured_output: bool,
+    /// Whether the model can fill in the middle of a document, as for inline code completion.
+    pub fim: bool,
+}
+
 /// Indicates the format used to define the input schema for a language model tool.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum LanguageModelToolSchemaFormat {
@@ -494,6 +521,16 @@ pub trait LanguageModel: Send + Sync {
         LanguageModelToolSchemaFormat::JsonSchema
     }
 
+    /// Returns everything this model supports at once, for gating features on the model.
+    /// Capabilities without their own method are only reported by providers that know them.
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        LanguageModelCapabilities {
+            tools: self.supports_tools(),
+            images: self.supports_images(),
+            ..Default::default()
+        }
+    }
+
     fn max_token_count(&self) -> u64;
     /// Returns the maximum token count for this model in burn mode (If `supports_burn_mode` is `false` this returns `None`)
     fn max_token_count_in_burn_mode(&self) -> Option<u64> {
@@ -564,6 +601,7 @@ pub trait LanguageModel: Send + Sync {
                                 Ok(LanguageModelCompletionEvent::ToolUseJsonParseError {
                                     ..
                                 }) => None,
+                                Ok(LanguageModelCompletionEvent::ToolUseIncomplete { .. }) => None,
                                 Ok(LanguageModelCompletionEvent::UsageUpdate(token_usage)) => {
                                     *last_token_usage.lock() = token_usage;
                                     None
@@ -3,7 +3,10 @@ use smol::lock::{Semaphore, SemaphoreGuardArc};
 use std::{
     future::Future,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
     task::{Context, Poll},
 };
 
@@ -12,11 +15,34 @@ use crate::LanguageModelCompletionError;
 #[derive(Clone)]
 pub struct RateLimiter {
     semaphore: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
 }
 
 pub struct RateLimitGuard<T> {
     inner: T,
+    _permit: Permit,
+}
+
+struct Permit {
     _guard: SemaphoreGuardArc,
+    _in_flight: Counted,
+}
+
+/// Counts itself in a counter for as long as it's alive.
+struct Counted(Arc<AtomicUsize>);
+
+impl Counted {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl<T> Stream for RateLimitGuard<T>
@@ -34,6 +60,39 @@ impl RateLimiter {
     pub fn new(limit: usize) -> Self {
         Self {
             semaphore: Arc::new(Semaphore::new(limit)),
+            queued: Arc::default(),
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// The number of requests waiting for another request to finish before they can start.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests currently running, including streams that are still open.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn acquire(&self) -> impl Future<Output = Permit> + use<> {
+        let semaphore = self.semaphore.clone();
+        let queued = self.queued.clone();
+        let in_flight = self.in_flight.clone();
+        async move {
+            // A request only counts as queued while it's waiting, not before it's first polled
+            // or when a permit is free.
+            let guard = match semaphore.try_acquire_arc() {
+                Some(guard) => guard,
+                None => {
+                    let _queued = Counted::new(queued);
+                    semaphore.acquire_arc().await
+                }
+            };
+            Permit {
+                _guard: guard,
+                _in_flight: Counted::new(in_flight),
+            }
         }
     }
 
@@ -44,11 +103,11 @@ impl RateLimiter {
     where
         Fut: 'a + Future<Output = Result<T, LanguageModelCompletionError>>,
     {
-        let guard = self.semaphore.acquire_arc();
+        let permit = self.acquire();
         async move {
-            let guard = guard.await;
+            let permit = permit.await;
             let result = future.await?;
-            drop(guard);
+            drop(permit);
             Ok(result)
         }
     }
@@ -64,14 +123,52 @@ impl RateLimiter {
         Fut: 'a + Future<Output = Result<T, LanguageModelCompletionError>>,
         T: Stream,
     {
-        let guard = self.semaphore.acquire_arc();
+        let permit = self.acquire();
         async move {
-            let guard = guard.await;
+            let permit = permit.await;
             let inner = future.await?;
             Ok(RateLimitGuard {
                 inner,
-                _guard: guard,
+                _permit: permit,
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt as _, StreamExt as _, stream::FuturesUnordered};
+
+    #[test]
+    fn test_queue_depth() {
+        let rate_limiter = RateLimiter::new(4);
+        let (mut senders, receivers): (Vec<_>, Vec<_>) = (0..6)
+            .map(|_| futures::channel::oneshot::channel::<()>())
+            .unzip();
+        let mut requests = receivers
+            .into_iter()
+            .map(|receiver| {
+                rate_limiter.run(async move {
+                    receiver.await.ok();
+                    Ok(())
+                })
+            })
+            .collect::<FuturesUnordered<_>>();
+        assert_eq!(rate_limiter.queued(), 0);
+
+        assert!(requests.next().now_or_never().is_none());
+        assert_eq!(rate_limiter.in_flight(), 4);
+        assert_eq!(rate_limiter.queued(), 2);
+
+        senders.remove(0).send(()).unwrap();
+        assert!(matches!(requests.next().now_or_never(), Some(Some(Ok(())))));
+        assert!(requests.next().now_or_never().is_none());
+        assert_eq!(rate_limiter.in_flight(), 4);
+        assert_eq!(rate_limiter.queued(), 1);
+
+        drop(requests);
+        assert_eq!(rate_limiter.in_flight(), 0);
+        assert_eq!(rate_limiter.queued(), 0);
+    }
+}
@@ -2,6 +2,7 @@ use std::io::{Cursor, Write};
 use std::sync::Arc;
 
 use anyhow::Result;
+use base64::Engine as _;
 use base64::write::EncoderWriter;
 use cloud_llm_client::{CompletionIntent, CompletionMode};
 use gpui::{
@@ -154,6 +155,32 @@ impl LanguageModelImage {
     pub fn to_base64_url(&self) -> String {
         format!("data:image/png;base64,{}", self.source)
     }
+
+    /// Returns this image scaled down to fit within `max_dimension` pixels in both
+    /// dimensions, preserving its aspect ratio. Images that already fit are returned as is.
+    pub fn scale_down(&self, max_dimension: u32) -> Result<Self> {
+        let width = self.size.width.0.unsigned_abs();
+        let height = self.size.height.0.unsigned_abs();
+        if width <= max_dimension && height <= max_dimension {
+            return Ok(self.clone());
+        }
+
+        let png = base64::engine::general_purpose::STANDARD.decode(self.source.as_bytes())?;
+        let image = image::load_from_memory_with_format(&png, image::ImageFormat::Png)?.resize(
+            max_dimension,
+            max_dimension,
+            image::imageops::FilterType::Triangle,
+        );
+        let mut png = Vec::new();
+        image.write_with_encoder(PngEncoder::new(&mut png))?;
+        Ok(Self {
+            source: base64::engine::general_purpose::STANDARD.encode(png).into(),
+            size: size(
+                DevicePixels(image.width() as i32),
+                DevicePixels(image.height() as i32),
+            ),
+        })
+    }
 }
 
 fn encode_as_base64(data: Arc<Image>, image: image::DynamicImage) -> Result<Vec<u8>> {
@@ -374,6 +401,13 @@ pub struct LanguageModelRequestTool {
     pub input_schema: serde_json::Value,
 }
 
+/// A JSON schema that the model's response should conform to.
+#[derive(Debug, PartialEq, Hash, Clone, Serialize, Deserialize)]
+pub struct LanguageModelResponseSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
 #[derive(Debug, PartialEq, Hash, Clone, Serialize, Deserialize)]
 pub enum LanguageModelToolChoice {
     Auto,
@@ -392,6 +426,11 @@ pub struct LanguageModelRequest {
     pub tool_choice: Option<LanguageModelToolChoice>,
     pub stop: Vec<String>,
     pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub response_schema: Option<LanguageModelResponseSchema>,
+    pub continue_last_message: bool,
     pub thinking_allowed: bool,
 }
 
@@ -405,6 +444,28 @@ pub struct LanguageModelResponseMessage {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_scale_down_image() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(400, 200)
+            .write_with_encoder(PngEncoder::new(&mut png))
+            .unwrap();
+        let image = LanguageModelImage {
+            source: base64::engine::general_purpose::STANDARD.encode(png).into(),
+            size: size(DevicePixels(400), DevicePixels(200)),
+        };
+
+        assert_eq!(image.scale_down(400).unwrap(), image);
+
+        let scaled = image.scale_down(100).unwrap();
+        assert_eq!(scaled.size, size(DevicePixels(100), DevicePixels(50)));
+        let png = base64::engine::general_purpose::STANDARD
+            .decode(scaled.source.as_bytes())
+            .unwrap();
+        let decoded = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+    }
+
     #[test]
     fn test_language_model_tool_result_content_deserialization() {
         let json = r#""This is plain text""#;
@@ -1110,6 +1110,11 @@ mod tests {
             temperature: None,
             tools: vec![],
             tool_choice: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: true,
         };
 
@@ -1,27 +1,46 @@
 use anyhow::{Context as _, Result, anyhow};
-use collections::BTreeMap;
+use base64::Engine as _;
+use cloud_llm_client::CompletionIntent;
+use collections::{BTreeMap, HashMap, HashSet};
 use credentials_provider::CredentialsProvider;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{FutureExt, Stream, StreamExt, future::BoxFuture, stream::BoxStream};
+use fs::Fs;
+use futures::{
+    FutureExt, SinkExt as _, Stream, StreamExt,
+    channel::{mpsc, oneshot},
+    future::{BoxFuture, Either},
+    stream::BoxStream,
+};
 use gpui::{
-    AnyView, App, AsyncApp, Context, Entity, FontStyle, Subscription, Task, TextStyle, WhiteSpace,
+    AnyView, App, AsyncApp, BackgroundExecutor, Context, Entity, EventEmitter, FontStyle,
+    Subscription, Task, TextStyle, WhiteSpace,
 };
-use http_client::HttpClient;
+use http_client::{HttpClient, StatusCode, Url};
 use language_model::{
-    AuthenticateError, LanguageModel, LanguageModelCompletionError, LanguageModelCompletionEvent,
-    LanguageModelId, LanguageModelName, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelProviderName, LanguageModelProviderState, LanguageModelRequest,
-    LanguageModelToolChoice, LanguageModelToolResultContent, LanguageModelToolUse, MessageContent,
-    RateLimiter, Role, StopReason, TokenUsage,
+    AuthenticateError, LanguageModel, LanguageModelCapabilities, LanguageModelCompletionError,
+    LanguageModelCompletionEvent, LanguageModelId, LanguageModelImage, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelProviderState, LanguageModelRequest, LanguageModelRequestTool,
+    LanguageModelToolChoice, LanguageModelToolResult, LanguageModelToolResultContent,
+    LanguageModelToolUse, MessageContent, RateLimiter, Role, StopReason, TokenUsage,
 };
 use mistral::StreamResponse;
+use parking_lot::Mutex;
+use ring::aead;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom as _, SystemRandom};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use settings::{Settings, SettingsStore};
-use std::collections::HashMap;
-use std::pin::Pin;
+use settings::{Settings, SettingsStore, update_settings_file};
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::io;
+use std::num::NonZeroU32;
+use std::pin::{Pin, pin};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use strum::IntoEnumIterator;
 use theme::ThemeSettings;
 use ui::{Icon, IconName, List, Tooltip, prelude::*};
@@ -32,13 +51,309 @@ use crate::{AllLanguageModelSettings, ui::InstructionListItem};
 const PROVIDER_ID: LanguageModelProviderId = LanguageModelProviderId::new("mistral");
 const PROVIDER_NAME: LanguageModelProviderName = LanguageModelProviderName::new("Mistral");
 
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+const DEFAULT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const TOKEN_COUNT_CACHE_CAPACITY: usize = 32;
+const DEFAULT_MAX_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// The longest delay between retries that back off exponentially.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const DUPLICATE_REQUEST_WINDOW: Duration = Duration::from_secs(2);
+/// How long a rate limited API key is avoided, unless the API says how long to wait.
+const RATE_LIMITED_KEY_COOLDOWN: Duration = Duration::from_secs(60);
+/// The shortest time a rate limited API key is avoided, however soon the API says to retry.
+const MIN_RATE_LIMITED_KEY_COOLDOWN: Duration = Duration::from_secs(1);
+/// The rough number of characters per token, for estimates that can't use a tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct MistralSettings {
     pub api_url: String,
     pub available_models: Vec<AvailableModel>,
+    pub safe_prompt: bool,
+    pub random_seed: Option<u64>,
+    pub max_concurrent_requests: Option<usize>,
+    pub organization_id: Option<String>,
+    /// Headers added to every request, after the standard ones.
+    pub extra_headers: BTreeMap<String, String>,
+    /// Models to send a completion request to, in order, when the model it was made for is
+    /// overloaded or rate limited.
+    pub fallback_models: Vec<String>,
+    pub parallel_tool_calls: Option<bool>,
+    pub min_tokens: Option<u64>,
+    /// How long to wait for the next chunk of a streamed completion before giving up.
+    pub stream_idle_timeout: Option<Duration>,
+    /// How long to wait for a streamed completion request to be answered before giving up.
+    pub request_timeout: Option<Duration>,
+    pub max_retry_attempts: Option<u32>,
+    pub retry_base_delay: Option<Duration>,
+    /// A system prompt sent ahead of the assistant's own in every request.
+    pub default_system_prompt: Option<String>,
+    /// Whether to log the raw chunks of streamed responses, for debugging.
+    pub log_raw_responses: bool,
+    /// Whether a request identical to one sent moments ago shares its response instead of
+    /// being sent again.
+    pub deduplicate_requests: bool,
+    /// Whether to send the reasoning of earlier assistant messages back to the model.
+    pub include_reasoning_in_history: bool,
+    /// The most tokens of a tool's text result to send. Longer results are truncated.
+    pub max_tool_result_tokens: Option<u64>,
+    /// Whether to repair common mistakes in the JSON of tool call arguments, rather than
+    /// failing the tool call.
+    pub lenient_json: bool,
+    /// The largest width or height of images to send. Larger images are scaled down.
+    pub max_image_dimension: Option<u32>,
+    /// How closely the model looks at images, when not left to the API's default.
+    pub image_detail: Option<mistral::ImageDetail>,
+    /// Whether to send requests in the subset of the format that OpenAI-compatible APIs
+    /// accept, for gateways that reject Mistral's extensions.
+    pub openai_compat: bool,
+    /// Whether to tag requests with the IDs of the thread and prompt they belong to and what
+    /// they're for, so that usage can be traced back to features.
+    pub send_request_metadata: bool,
+    /// Temperatures to use, by request intent such as `edit_file`, for requests that don't
+    /// set one.
+    pub temperature_by_intent: BTreeMap<String, f32>,
+    /// Whether to stream completions, rather than request each as a single response.
+    pub stream: bool,
+    /// IDs of built-in or discovered models to hide, such as those the account can't access.
+    pub disabled_models: Vec<String>,
+    /// The ID of a model to use instead of [`mistral::Model::default`].
+    pub default_model: Option<String>,
+    /// The ID of a model to use instead of [`mistral::Model::default_fast`].
+    pub default_fast_model: Option<String>,
+    /// The scheme the API key is sent with, when not [`mistral::DEFAULT_AUTH_SCHEME`].
+    pub auth_scheme: Option<String>,
+}
+
+impl MistralSettings {
+    pub fn auth_scheme(&self) -> &str {
+        self.auth_scheme
+            .as_deref()
+            .unwrap_or(mistral::DEFAULT_AUTH_SCHEME)
+    }
+
+    /// The connection to send requests authenticated with `api_key` over.
+    pub fn connection(&self, api_key: &str) -> mistral::Connection {
+        mistral::Connection {
+            api_url: self.api_url.clone(),
+            api_key: api_key.to_string(),
+            auth_scheme: self.auth_scheme().to_string(),
+            organization_id: self.organization_id.clone(),
+            extra_headers: self.extra_headers.clone(),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self
+                .max_retry_attempts
+                .unwrap_or(DEFAULT_MAX_RETRY_ATTEMPTS)
+                .max(1),
+            base_delay: self.retry_base_delay.unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+        }
+    }
+
+    /// Resolves the request parameters for `model`, preferring overrides from the
+    /// matching entry in `available_models` over the provider-wide values.
+    pub fn request_settings(&self, model: &mistral::Model) -> MistralRequestSettings {
+        let available_model = self
+            .available_models
+            .iter()
+            .find(|available_model| available_model.name == model.id());
+        MistralRequestSettings {
+            safe_prompt: available_model
+                .and_then(|model| model.safe_prompt)
+                .unwrap_or(self.safe_prompt),
+            random_seed: available_model
+                .and_then(|model| model.random_seed)
+                .or(self.random_seed),
+            parallel_tool_calls: available_model
+                .and_then(|model| model.parallel_tool_calls)
+                .or(self.parallel_tool_calls)
+                .unwrap_or(false),
+            min_tokens: available_model
+                .and_then(|model| model.min_tokens)
+                .or(self.min_tokens),
+            agent_id: available_model.and_then(|model| model.agent_id.clone()),
+            default_system_prompt: self.default_system_prompt.clone(),
+            include_reasoning_in_history: self.include_reasoning_in_history,
+            max_tool_result_tokens: self.max_tool_result_tokens,
+            max_image_dimension: self.max_image_dimension,
+            image_detail: self.image_detail,
+            openai_compat: self.openai_compat,
+            send_metadata: self.send_request_metadata,
+            metadata: BTreeMap::default(),
+            temperature_by_intent: self.temperature_by_intent.clone(),
+            stream: self.stream,
+            n: None,
+            file_ids: Vec::new(),
+            prediction: None,
+        }
+    }
+
+    /// The models among `models` to fall back to from `model`, in order, along with the
+    /// request parameters to use for each.
+    fn fallback_models(
+        &self,
+        models: &[mistral::Model],
+        model: &mistral::Model,
+    ) -> Vec<(mistral::Model, MistralRequestSettings)> {
+        self.fallback_models
+            .iter()
+            .filter(|id| id.as_str() != model.id())
+            .filter_map(|id| {
+                let fallback = models.iter().find(|model| model.id() == id);
+                if fallback.is_none() {
+                    log::warn!("Unknown {PROVIDER_NAME} fallback model {id:?}");
+                }
+                fallback
+            })
+            .map(|model| (model.clone(), self.request_settings(model)))
+            .collect()
+    }
+}
+
+/// Parameters of a Mistral request that aren't part of the [`LanguageModelRequest`], most
+/// of which come from settings. Callers of [`MistralLanguageModel::stream_choices`] can set
+/// the rest.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct MistralRequestSettings {
+    pub safe_prompt: bool,
+    pub random_seed: Option<u64>,
+    pub parallel_tool_calls: bool,
+    pub min_tokens: Option<u64>,
+    /// A server-side agent to send requests to instead of the model.
+    pub agent_id: Option<String>,
+    pub default_system_prompt: Option<String>,
+    /// Whether to keep the thinking content of assistant messages in the history, rather
+    /// than only their final answers.
+    pub include_reasoning_in_history: bool,
+    /// The most tokens, approximately, of each text tool result to send.
+    pub max_tool_result_tokens: Option<u64>,
+    /// The largest width or height, in pixels, of images to send. Images are scaled with
+    /// [`scale_down_images`] before the request is converted.
+    pub max_image_dimension: Option<u32>,
+    /// The detail to request for every image, trading image tokens for accuracy.
+    pub image_detail: Option<mistral::ImageDetail>,
+    /// Whether to only use the parts of the request format that OpenAI-compatible APIs
+    /// accept. See [`make_openai_compatible`].
+    pub openai_compat: bool,
+    /// Whether to tag the request with the thread, prompt, and intent it belongs to.
+    pub send_metadata: bool,
+    /// Further tags to send when `send_metadata` is set, such as the name of the feature
+    /// making the request.
+    pub metadata: BTreeMap<String, String>,
+    /// The temperature to use for a request that doesn't set one, by the name of its intent.
+    pub temperature_by_intent: BTreeMap<String, f32>,
+    /// Whether to stream the completion. Otherwise it's sent in a single response.
+    pub stream: bool,
+    /// The number of completions to generate, for callers that offer several variations
+    /// of a response. Their events can be told apart with [`MistralEventMapper::map_choices`].
+    pub n: Option<u32>,
+    /// Files uploaded with [`MistralLanguageModelProvider::upload_file`] to attach to the
+    /// last user message, so that large documents aren't sent with every request.
+    pub file_ids: Vec<String>,
+    /// Output the model is expected to mostly reproduce, such as the original text of an
+    /// edit. Only sent to models that [support it](mistral::Model::supports_prediction).
+    pub prediction: Option<String>,
+}
+
+/// How the request that starts a completion is retried after transient failures: server
+/// errors and dropped connections. Nothing is retried once the response has started
+/// streaming, so that no output is duplicated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The most times to send the request, including the first attempt.
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles after each further attempt up to
+    /// [`MAX_RETRY_DELAY`] unless the server asks for a specific delay with `Retry-After`.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    async fn retry<T, F>(&self, mut request: impl FnMut() -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(error) if attempt < self.max_attempts && is_transient_error(&error) => {
+                    let delay = error
+                        .downcast_ref::<mistral::ApiError>()
+                        .and_then(|error| error.retry_after)
+                        .unwrap_or_else(|| self.backoff(attempt));
+                    log::warn!(
+                        "Mistral request failed (attempt {attempt} of {}), retrying in {delay:?}: {error}",
+                        self.max_attempts
+                    );
+                    smol::Timer::after(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The delay before retrying after `attempt` failed, without overflowing however many
+    /// attempts are allowed.
+    fn backoff(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+}
+
+/// Whether `error` means the API key was rate limited, so that another key may be able to
+/// make the request instead.
+fn is_rate_limit_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<mistral::ApiError>()
+        .is_some_and(|error| error.status_code == StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Whether `error` means the model can't take more requests right now, so that another
+/// model may be able to answer instead. This includes rate limits, which only lead to a
+/// fallback model once every API key is rate limited: see [`ApiKeyRotation`].
+fn is_capacity_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<mistral::ApiError>()
+        .is_some_and(|error| {
+            matches!(
+                error.status_code,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+            ) || error.status_code.as_u16() == 529
+        })
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Whether `model` can answer `request`, so that it can stand in for the model the request
+/// was made for.
+fn supports_request(model: &mistral::Model, request: &LanguageModelRequest) -> bool {
+    let has_images = request.messages.iter().any(|message| {
+        message
+            .content
+            .iter()
+            .any(|content| matches!(content, MessageContent::Image(_)))
+    });
+    (!has_images || model.supports_images()) && (request.tools.is_empty() || model.supports_tools())
+}
+
+fn is_transient_error(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<mistral::ApiError>() {
+        return error.status_code.is_server_error();
+    }
+    error.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|error| {
+            matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            )
+        })
+    })
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct AvailableModel {
     pub name: String,
     pub display_name: Option<String>,
@@ -47,19 +362,285 @@ pub struct AvailableModel {
     pub max_completion_tokens: Option<u64>,
     pub supports_tools: Option<bool>,
     pub supports_images: Option<bool>,
+    pub supports_structured_output: Option<bool>,
+    pub supports_thinking: Option<bool>,
+    pub supports_prompt_cache: Option<bool>,
+    /// Clamps the requested temperature to at most this value.
+    pub max_temperature: Option<f32>,
+    pub supports_prediction: Option<bool>,
+    /// Overrides the price used to estimate spend, e.g. for self-hosted deployments.
+    pub price_per_token: Option<mistral::TokenPrice>,
+    /// Overrides when the model's training data ends, such as `2024-10`.
+    pub knowledge_cutoff: Option<String>,
+    /// Overrides the summary of the model shown in the model list.
+    pub description: Option<String>,
+    /// Stop sequences to send with every request to the model, along with those of the
+    /// request.
+    pub default_stop_sequences: Option<Vec<String>>,
+    pub safe_prompt: Option<bool>,
+    pub random_seed: Option<u64>,
+    pub parallel_tool_calls: Option<bool>,
+    pub min_tokens: Option<u64>,
+    /// The ID of an agent created in La Plateforme to run instead of this model.
+    pub agent_id: Option<String>,
 }
 
 pub struct MistralLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Entity<State>,
+    request_deduplicator: RequestDeduplicator,
 }
 
 pub struct State {
-    api_key: Option<String>,
+    http_client: Arc<dyn HttpClient>,
+    api_key: Option<ApiKey>,
     api_key_from_env: bool,
+    /// The API URL that `api_key` is for.
+    api_url: String,
+    /// Keys read from or written to the keychain, by API URL, so that switching `api_url`
+    /// back restores the matching key.
+    api_keys_by_url: HashMap<String, ApiKey>,
+    discovered_models: Vec<mistral::Model>,
+    /// The IDs and aliases of the models the API listed, or `None` if it hasn't answered.
+    listed_model_ids: Option<HashSet<String>>,
+    fetch_models_task: Option<Task<Result<()>>>,
+    /// Receives the token usage of every completion, if registered.
+    usage_sink: Option<UsageSink>,
+    /// Receives how long every completion took to start, if registered.
+    latency_sink: Option<LatencySink>,
+    /// Which of the keys to use next, when `api_key` holds several.
+    api_key_rotation: Arc<Mutex<ApiKeyRotation>>,
     _subscription: Subscription,
 }
 
+/// A callback that receives the token usage of each completion, such as for tracking costs.
+pub type UsageSink = Arc<dyn Fn(&UsageReport) + Send + Sync>;
+
+/// The tokens used by a completion, as reported to a [`UsageSink`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageReport {
+    /// The ID of the model that generated the completion, as reported by the API.
+    pub model_id: String,
+    pub usage: TokenUsage,
+    /// When the usage was reported, at the end of the completion.
+    pub timestamp: SystemTime,
+}
+
+/// A callback that receives how long each completion took to start responding, such as for
+/// comparing the responsiveness of models.
+pub type LatencySink = Arc<dyn Fn(&LatencyReport) + Send + Sync>;
+
+/// How long a completion took to start, as reported to a [`LatencySink`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyReport {
+    /// The ID of the model that generated the completion, as reported by the API.
+    pub model_id: String,
+    /// The time from sending the request to receiving the first text, reasoning, or tool
+    /// call, including any retries.
+    pub first_token_latency: Duration,
+}
+
+/// A Mistral API key. Its `Debug` and `Display` output is redacted so that it can't end up
+/// in logs or error messages.
+///
+/// Several keys can be given separated by commas or whitespace, so that completions are spread
+/// across them. Other requests use the first key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    /// The key as it's stored, with all of its keys if it has several.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The first of the keys, which requests other than completions are made with.
+    pub fn first(&self) -> &str {
+        self.keys().next().unwrap_or_default()
+    }
+
+    /// Each of the keys, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|key| !key.is_empty())
+    }
+
+    /// Encrypts the key with a key derived from `passphrase`, for moving it to another
+    /// machine. The result is base64 and can only be read with [`Self::decrypt`].
+    pub fn encrypt(&self, passphrase: &str) -> Result<String> {
+        anyhow::ensure!(
+            !passphrase.is_empty(),
+            "a passphrase is required to export credentials"
+        );
+        let rng = SystemRandom::new();
+        let mut salt = [0; CREDENTIALS_SALT_LEN];
+        let mut nonce = [0; aead::NONCE_LEN];
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| anyhow!("failed to generate random bytes"))?;
+
+        let mut ciphertext = self.0.as_bytes().to_vec();
+        credentials_key(passphrase, &salt)
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from([CREDENTIALS_VERSION]),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("failed to encrypt credentials"))?;
+
+        let mut blob = vec![CREDENTIALS_VERSION];
+        blob.extend_from_slice(&salt);
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+        Ok(base64::engine::general_purpose::STANDARD.encode(blob))
+    }
+
+    /// Decrypts a key encrypted with [`Self::encrypt`].
+    pub fn decrypt(blob: &str, passphrase: &str) -> Result<Self> {
+        let blob = base64::engine::general_purpose::STANDARD
+            .decode(blob.trim())
+            .context("credentials aren't valid base64")?;
+        let Some((&version, blob)) = blob.split_first() else {
+            anyhow::bail!("credentials are empty");
+        };
+        anyhow::ensure!(
+            version == CREDENTIALS_VERSION,
+            "unsupported credentials version {version}"
+        );
+        anyhow::ensure!(
+            blob.len() >= CREDENTIALS_SALT_LEN + aead::NONCE_LEN,
+            "credentials are truncated"
+        );
+        let (salt, blob) = blob.split_at(CREDENTIALS_SALT_LEN);
+        let (nonce, ciphertext) = blob.split_at(aead::NONCE_LEN);
+
+        let mut ciphertext = ciphertext.to_vec();
+        let plaintext = credentials_key(passphrase, salt)
+            .open_in_place(
+                aead::Nonce::try_assume_unique_for_key(nonce)
+                    .map_err(|_| anyhow!("invalid credentials nonce"))?,
+                aead::Aad::from([version]),
+                &mut ciphertext,
+            )
+            .map_err(|_| anyhow!("wrong passphrase, or the credentials are corrupted"))?;
+        Ok(Self(
+            String::from_utf8(plaintext.to_vec()).context("invalid Mistral API key")?,
+        ))
+    }
+}
+
+/// The format of exported credentials: a version byte, the salt, the nonce, and then the
+/// encrypted key and its authentication tag.
+const CREDENTIALS_VERSION: u8 = 1;
+const CREDENTIALS_SALT_LEN: usize = 16;
+/// PBKDF2 rounds used to derive the encryption key, making passphrases slow to guess.
+const CREDENTIALS_KDF_ITERATIONS: NonZeroU32 = NonZeroU32::new(600_000).unwrap();
+
+fn credentials_key(passphrase: &str, salt: &[u8]) -> aead::LessSafeKey {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        CREDENTIALS_KDF_ITERATIONS,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    // The key has the length ChaCha20-Poly1305 requires, so this can't fail.
+    let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap();
+    aead::LessSafeKey::new(key)
+}
+
+impl From<String> for ApiKey {
+    fn from(api_key: String) -> Self {
+        Self(api_key)
+    }
+}
+
+impl From<&str> for ApiKey {
+    fn from(api_key: &str) -> Self {
+        Self(api_key.to_string())
+    }
+}
+
+/// Picks which of the keys of an [`ApiKey`] to send each completion with, so that they share
+/// the load of per-key rate limits. The least recently used key is picked, except that keys
+/// that were recently rate limited are only picked when all of them were.
+#[derive(Default)]
+struct ApiKeyRotation {
+    uses: u64,
+    last_used: HashMap<String, u64>,
+    rate_limited_until: HashMap<String, Instant>,
+}
+
+/// Times are passed in from the executor's clock, so that cooldowns can be tested with fake
+/// timers.
+impl ApiKeyRotation {
+    fn next_key(&mut self, api_key: &ApiKey, now: Instant) -> String {
+        let key = api_key
+            .keys()
+            .min_by_key(|key| {
+                (
+                    self.is_rate_limited(key, now),
+                    self.last_used.get(*key).copied(),
+                )
+            })
+            .unwrap_or_default()
+            .to_string();
+        self.uses += 1;
+        self.last_used.insert(key.clone(), self.uses);
+        key
+    }
+
+    fn is_rate_limited(&self, key: &str, now: Instant) -> bool {
+        self.rate_limited_until
+            .get(key)
+            .is_some_and(|until| *until > now)
+    }
+
+    /// Deprioritizes `key` if `error` means it was rate limited, for as long as the API asked
+    /// or [`RATE_LIMITED_KEY_COOLDOWN`], but at least [`MIN_RATE_LIMITED_KEY_COOLDOWN`].
+    fn report_error(&mut self, key: &str, error: &anyhow::Error, now: Instant) {
+        if !is_rate_limit_error(error) {
+            return;
+        }
+        let cooldown = error
+            .downcast_ref::<mistral::ApiError>()
+            .and_then(|error| error.retry_after)
+            .unwrap_or(RATE_LIMITED_KEY_COOLDOWN)
+            .max(MIN_RATE_LIMITED_KEY_COOLDOWN);
+        self.rate_limited_until
+            .insert(key.to_string(), now + cooldown);
+    }
+
+    /// Whether any of the keys of `api_key` can be used without waiting for a rate limit.
+    fn has_available_key(&self, api_key: &ApiKey, now: Instant) -> bool {
+        api_key.keys().any(|key| !self.is_rate_limited(key, now))
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(***)")
+    }
+}
+
+impl fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Emitted by [`State`] when an API key is set or removed, such as to refresh anything that
+/// depends on the account. See [`MistralLanguageModelProvider::subscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MistralEvent {
+    Authenticated,
+    Deauthenticated,
+}
+
+impl EventEmitter<MistralEvent> for State {}
+
 const MISTRAL_API_KEY_VAR: &str = "MISTRAL_API_KEY";
 
 impl State {
@@ -79,26 +660,57 @@ impl State {
                 .await
                 .log_err();
             this.update(cx, |this, cx| {
-                this.api_key = None;
-                this.api_key_from_env = false;
-                cx.notify();
+                this.api_keys_by_url.remove(&api_url);
+                if this.api_url == api_url {
+                    this.update_api_key(None, false, cx);
+                }
             })
         })
     }
 
+    /// Encrypts the API key with `passphrase`, so that it can be moved to another machine
+    /// and restored there with [`Self::import_credentials`]. Deriving the encryption key is
+    /// slow by design, so it happens in the background.
+    pub fn export_credentials(&self, passphrase: String, cx: &App) -> Task<Result<String>> {
+        let Some(api_key) = self.api_key.clone() else {
+            return Task::ready(Err(anyhow!("no Mistral API key to export")));
+        };
+        cx.background_spawn(async move { api_key.encrypt(&passphrase) })
+    }
+
+    /// Decrypts credentials from [`Self::export_credentials`] and saves the key for the
+    /// configured API URL, as if it had been entered.
+    pub fn import_credentials(
+        &self,
+        credentials: String,
+        passphrase: String,
+        cx: &mut Context<Self>,
+    ) -> Task<Result<()>> {
+        let api_key =
+            cx.background_spawn(async move { ApiKey::decrypt(&credentials, &passphrase) });
+        cx.spawn(async move |this, cx| {
+            let api_key = api_key.await?;
+            this.update(cx, |this, cx| this.set_api_key(api_key.0, cx))?
+                .await
+        })
+    }
+
     fn set_api_key(&mut self, api_key: String, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let api_key = ApiKey::from(api_key);
         let credentials_provider = <dyn CredentialsProvider>::global(cx);
-        let api_url = AllLanguageModelSettings::get_global(cx)
-            .mistral
-            .api_url
-            .clone();
+        let settings = &AllLanguageModelSettings::get_global(cx).mistral;
+        let api_url = settings.api_url.clone();
+        let auth_scheme = settings.auth_scheme().to_string();
         cx.spawn(async move |this, cx| {
             credentials_provider
-                .write_credentials(&api_url, "Bearer", api_key.as_bytes(), &cx)
+                .write_credentials(&api_url, &auth_scheme, api_key.as_str().as_bytes(), &cx)
                 .await?;
             this.update(cx, |this, cx| {
-                this.api_key = Some(api_key);
-                cx.notify();
+                this.api_keys_by_url
+                    .insert(api_url.clone(), api_key.clone());
+                if this.api_url == api_url {
+                    this.update_api_key(Some(api_key), false, cx);
+                }
             })
         })
     }
@@ -109,666 +721,3198 @@ impl State {
         }
 
         let credentials_provider = <dyn CredentialsProvider>::global(cx);
-        let api_url = AllLanguageModelSettings::get_global(cx)
-            .mistral
-            .api_url
-            .clone();
+        let settings = &AllLanguageModelSettings::get_global(cx).mistral;
+        let api_url = settings.api_url.clone();
+        let auth_scheme = settings.auth_scheme().to_string();
         cx.spawn(async move |this, cx| {
             let (api_key, from_env) = if let Ok(api_key) = std::env::var(MISTRAL_API_KEY_VAR) {
-                (api_key, true)
+                (ApiKey::from(api_key), true)
             } else {
-                let (_, api_key) = credentials_provider
+                let (saved_auth_scheme, api_key) = credentials_provider
                     .read_credentials(&api_url, &cx)
                     .await?
                     .ok_or(AuthenticateError::CredentialsNotFound)?;
+                // The key is sent with the configured scheme, whichever it was saved with.
+                if saved_auth_scheme != auth_scheme {
+                    log::info!(
+                        "{PROVIDER_NAME} API key was saved for the {saved_auth_scheme:?} auth scheme, using {auth_scheme:?}"
+                    );
+                }
                 (
-                    String::from_utf8(api_key).context("invalid {PROVIDER_NAME} API key")?,
+                    ApiKey::from(
+                        String::from_utf8(api_key).context("invalid {PROVIDER_NAME} API key")?,
+                    ),
                     false,
                 )
             };
             this.update(cx, |this, cx| {
-                this.api_key = Some(api_key);
-                this.api_key_from_env = from_env;
-                cx.notify();
+                if !from_env {
+                    this.api_keys_by_url
+                        .insert(api_url.clone(), api_key.clone());
+                }
+                if this.api_url == api_url || from_env {
+                    this.update_api_key(Some(api_key), from_env, cx);
+                }
             })?;
 
             Ok(())
         })
     }
-}
-
-impl MistralLanguageModelProvider {
-    pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut App) -> Self {
-        let state = cx.new(|cx| State {
-            api_key: None,
-            api_key_from_env: false,
-            _subscription: cx.observe_global::<SettingsStore>(|_this: &mut State, cx| {
-                cx.notify();
-            }),
-        });
 
-        Self { http_client, state }
+    fn update_api_key(&mut self, api_key: Option<ApiKey>, from_env: bool, cx: &mut Context<Self>) {
+        let was_authenticated = self.is_authenticated();
+        self.api_key = api_key;
+        self.api_key_from_env = from_env;
+        // Which keys were used or rate limited says nothing about the new ones.
+        *self.api_key_rotation.lock() = ApiKeyRotation::default();
+        if self.is_authenticated() {
+            self.restart_fetch_models_task(cx);
+            cx.emit(MistralEvent::Authenticated);
+        } else {
+            self.discovered_models.clear();
+            self.listed_model_ids = None;
+            if was_authenticated {
+                cx.emit(MistralEvent::Deauthenticated);
+            }
+        }
+        cx.notify();
     }
 
-    fn create_language_model(&self, model: mistral::Model) -> Arc<dyn LanguageModel> {
-        Arc::new(MistralLanguageModel {
-            id: LanguageModelId::from(model.id().to_string()),
-            model,
-            state: self.state.clone(),
-            http_client: self.http_client.clone(),
-            request_limiter: RateLimiter::new(4),
-        })
+    /// Discards the current key and reads it again from the environment or the keychain, so that
+    /// rotated keys are picked up without restarting.
+    fn reload_credentials(
+        &mut self,
+        cx: &mut Context<Self>,
+    ) -> Task<Result<(), AuthenticateError>> {
+        self.api_keys_by_url.remove(&self.api_url);
+        self.update_api_key(None, false, cx);
+        self.authenticate(cx)
     }
-}
 
-impl LanguageModelProviderState for MistralLanguageModelProvider {
-    type ObservableEntity = State;
+    fn fetch_models(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let Some(api_key) = self.api_key.clone() else {
+            return Task::ready(Ok(()));
+        };
+        let http_client = self.http_client.clone();
+        let connection = mistral::Connection {
+            // Models are listed from the URL that the key was loaded for.
+            api_url: self.api_url.clone(),
+            ..AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .connection(api_key.first())
+        };
 
-    fn observable_entity(&self) -> Option<gpui::Entity<Self::ObservableEntity>> {
-        Some(self.state.clone())
-    }
-}
+        cx.spawn(async move |this, cx| {
+            // The built-in models are always available, so failing to reach the models
+            // endpoint only means we don't offer any models released since.
+            let (models, listed_model_ids) =
+                match mistral::list_models(http_client.as_ref(), &connection).await {
+                    Ok(cards) => (
+                        cards.iter().filter_map(discovered_model).collect(),
+                        Some(
+                            cards
+                                .iter()
+                                .flat_map(|card| std::iter::once(&card.id).chain(&card.aliases))
+                                .cloned()
+                                .collect(),
+                        ),
+                    ),
+                    Err(error) => {
+                        log::warn!("Failed to fetch {PROVIDER_NAME} models: {error:#}");
+                        (Vec::new(), None)
+                    }
+                };
 
-impl LanguageModelProvider for MistralLanguageModelProvider {
-    fn id(&self) -> LanguageModelProviderId {
-        PROVIDER_ID
+            this.update(cx, |this, cx| {
+                this.discovered_models = models;
+                this.listed_model_ids = listed_model_ids;
+                cx.notify();
+            })
+        })
     }
 
-    fn name(&self) -> LanguageModelProviderName {
-        PROVIDER_NAME
+    /// Persists `api_url` to the user's settings. A key saved in the keychain is copied
+    /// over, since credentials are stored per API URL.
+    fn set_api_url(&self, api_url: String, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let fs = <dyn Fs>::global(cx);
+        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+        let api_key = self.api_key.clone().filter(|_| !self.api_key_from_env);
+        let auth_scheme = AllLanguageModelSettings::get_global(cx)
+            .mistral
+            .auth_scheme()
+            .to_string();
+        cx.spawn(async move |_, cx| {
+            if let Some(api_key) = api_key {
+                credentials_provider
+                    .write_credentials(&api_url, &auth_scheme, api_key.as_str().as_bytes(), &cx)
+                    .await?;
+            }
+            cx.update(|cx| {
+                update_settings_file::<AllLanguageModelSettings>(fs, cx, move |settings, _| {
+                    settings.mistral.get_or_insert_default().api_url = Some(api_url);
+                });
+            })
+        })
     }
 
-    fn icon(&self) -> IconName {
-        IconName::AiMistral
+    /// Switches to the key stored for `api_url`, returning whether the key needs to be read
+    /// from the keychain. A key from the environment is used for every API URL.
+    fn switch_api_url(&mut self, api_url: String) -> bool {
+        if api_url == self.api_url {
+            return false;
+        }
+        self.api_url = api_url;
+        self.discovered_models.clear();
+        self.listed_model_ids = None;
+        if !self.api_key_from_env {
+            self.api_key = self.api_keys_by_url.get(&self.api_url).cloned();
+            *self.api_key_rotation.lock() = ApiKeyRotation::default();
+        }
+        self.api_key.is_none()
     }
 
-    fn default_model(&self, _cx: &App) -> Option<Arc<dyn LanguageModel>> {
-        Some(self.create_language_model(mistral::Model::default()))
+    fn api_url_changed(&mut self, cx: &mut Context<Self>) {
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .mistral
+            .api_url
+            .clone();
+        if api_url == self.api_url {
+            return;
+        }
+        let was_authenticated = self.is_authenticated();
+        if self.switch_api_url(api_url) {
+            if was_authenticated {
+                cx.emit(MistralEvent::Deauthenticated);
+            }
+            self.authenticate(cx).detach();
+        } else {
+            self.restart_fetch_models_task(cx);
+            cx.emit(MistralEvent::Authenticated);
+        }
     }
 
-    fn default_fast_model(&self, _cx: &App) -> Option<Arc<dyn LanguageModel>> {
-        Some(self.create_language_model(mistral::Model::default_fast()))
+    fn restart_fetch_models_task(&mut self, cx: &mut Context<Self>) {
+        let task = self.fetch_models(cx);
+        self.fetch_models_task.replace(task);
     }
 
-    fn provided_models(&self, cx: &App) -> Vec<Arc<dyn LanguageModel>> {
-        let mut models = BTreeMap::default();
-
-        // Add base models from mistral::Model::iter()
-        for model in mistral::Model::iter() {
-            if !matches!(model, mistral::Model::Custom { .. }) {
-                models.insert(model.id().to_string(), model);
-            }
-        }
-
-        // Override with available models from settings
-        for model in &AllLanguageModelSettings::get_global(cx)
-            .mistral
-            .available_models
-        {
-            models.insert(
-                model.name.clone(),
-                mistral::Model::Custom {
-                    name: model.name.clone(),
-                    display_name: model.display_name.clone(),
-                    max_tokens: model.max_tokens,
-                    max_output_tokens: model.max_output_tokens,
-                    max_completion_tokens: model.max_completion_tokens,
-                    supports_tools: model.supports_tools,
-                    supports_images: model.supports_images,
-                },
-            );
-        }
+    fn models(&self, cx: &App) -> Vec<mistral::Model> {
+        merge_models(
+            &self.discovered_models,
+            &AllLanguageModelSettings::get_global(cx).mistral,
+        )
+    }
 
-        models
-            .into_values()
+    fn models_with_status(&self, cx: &App) -> Vec<(mistral::Model, ModelStatus)> {
+        self.models(cx)
+            .into_iter()
             .map(|model| {
-                Arc::new(MistralLanguageModel {
-                    id: LanguageModelId::from(model.id().to_string()),
-                    model,
-                    state: self.state.clone(),
-                    http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
-                }) as Arc<dyn LanguageModel>
+                let status = model_status(&model, self.listed_model_ids.as_ref());
+                (model, status)
             })
             .collect()
     }
+}
 
-    fn is_authenticated(&self, cx: &App) -> bool {
-        self.state.read(cx).is_authenticated()
+/// Whether a model can be used with the configured API key, as last reported by the API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelStatus {
+    Available,
+    /// The API didn't list the model, such as because the account can't access it.
+    Unavailable,
+    /// The API hasn't listed its models yet, or couldn't be reached.
+    Unknown,
+}
+
+fn model_status(model: &mistral::Model, listed_model_ids: Option<&HashSet<String>>) -> ModelStatus {
+    match listed_model_ids {
+        Some(ids) if ids.contains(model.id()) => ModelStatus::Available,
+        Some(_) => ModelStatus::Unavailable,
+        None => ModelStatus::Unknown,
     }
+}
 
-    fn authenticate(&self, cx: &mut App) -> Task<Result<(), AuthenticateError>> {
-        self.state.update(cx, |state, cx| state.authenticate(cx))
+/// The built-in, discovered, and configured models, with settings taking precedence. Models
+/// listed in `disabled_models` are left out unless they are configured in `available_models`.
+fn merge_models(
+    discovered_models: &[mistral::Model],
+    settings: &MistralSettings,
+) -> Vec<mistral::Model> {
+    let mut models = BTreeMap::default();
+
+    // Add base models from mistral::Model::iter()
+    for model in mistral::Model::iter() {
+        if !matches!(model, mistral::Model::Custom { .. }) {
+            models.insert(model.id().to_string(), model);
+        }
     }
 
-    fn configuration_view(&self, window: &mut Window, cx: &mut App) -> AnyView {
-        cx.new(|cx| ConfigurationView::new(self.state.clone(), window, cx))
-            .into()
+    for model in discovered_models {
+        models
+            .entry(model.id().to_string())
+            .or_insert_with(|| model.clone());
     }
 
-    fn reset_credentials(&self, cx: &mut App) -> Task<Result<()>> {
-        self.state.update(cx, |state, cx| state.reset_api_key(cx))
+    models.retain(|id, _| !settings.disabled_models.contains(id));
+
+    // Override with available models from settings
+    for model in &settings.available_models {
+        models.insert(
+            model.name.clone(),
+            mistral::Model::Custom {
+                name: model.name.clone(),
+                display_name: model.display_name.clone(),
+                max_tokens: model.max_tokens,
+                max_output_tokens: model.max_output_tokens,
+                max_completion_tokens: model.max_completion_tokens,
+                supports_tools: model.supports_tools,
+                supports_images: model.supports_images,
+                supports_structured_output: model.supports_structured_output,
+                supports_thinking: model.supports_thinking,
+                supports_prompt_cache: model.supports_prompt_cache,
+                max_temperature: model.max_temperature,
+                supports_prediction: model.supports_prediction,
+                price_per_token: model.price_per_token.or_else(|| {
+                    mistral::Model::from_id(&model.name)
+                        .ok()
+                        .and_then(|model| model.price_per_token())
+                }),
+                knowledge_cutoff: model.knowledge_cutoff.clone().or_else(|| {
+                    mistral::Model::from_id(&model.name)
+                        .ok()
+                        .and_then(|model| model.knowledge_cutoff().map(str::to_string))
+                }),
+                description: model.description.clone().or_else(|| {
+                    mistral::Model::from_id(&model.name)
+                        .ok()
+                        .and_then(|model| model.description().map(str::to_string))
+                }),
+                default_stop_sequences: model.default_stop_sequences.clone(),
+            },
+        );
     }
-}
 
-pub struct MistralLanguageModel {
-    id: LanguageModelId,
-    model: mistral::Model,
-    state: gpui::Entity<State>,
-    http_client: Arc<dyn HttpClient>,
-    request_limiter: RateLimiter,
+    models.into_values().collect()
 }
 
-impl MistralLanguageModel {
-    fn stream_completion(
-        &self,
-        request: mistral::Request,
-        cx: &AsyncApp,
-    ) -> BoxFuture<
-        'static,
-        Result<futures::stream::BoxStream<'static, Result<mistral::StreamResponse>>>,
-    > {
-        let http_client = self.http_client.clone();
-        let Ok((api_key, api_url)) = cx.read_entity(&self.state, |state, cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).mistral;
-            (state.api_key.clone(), settings.api_url.clone())
-        }) else {
-            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
-        };
-
-        let future = self.request_limiter.stream(async move {
-            let api_key = api_key.context("Missing Mistral API Key")?;
-            let request =
-                mistral::stream_completion(http_client.as_ref(), &api_url, &api_key, request);
-            let response = request.await?;
-            Ok(response)
-        });
+/// The context window of the built-in model that `model` overrides, if its configured
+/// `max_tokens` is off by more than a quarter, which is more likely a typo than intended.
+fn mismatched_max_tokens(model: &AvailableModel) -> Option<u64> {
+    let built_in_max_tokens = mistral::Model::from_id(&model.name).ok()?.max_token_count();
+    (model.max_tokens.abs_diff(built_in_max_tokens) > built_in_max_tokens / 4)
+        .then_some(built_in_max_tokens)
+}
 
-        async move { Ok(future.await?.boxed()) }.boxed()
+/// Warns about configured models whose `max_tokens` is likely a typo. Called when settings
+/// are loaded or change, rather than whenever the models are listed.
+fn warn_about_mismatched_max_tokens(settings: &MistralSettings) {
+    for model in &settings.available_models {
+        if let Some(built_in_max_tokens) = mismatched_max_tokens(model) {
+            log::warn!(
+                "{PROVIDER_NAME} model {} is configured with max_tokens of {}, but its \
+                context window is {built_in_max_tokens} tokens",
+                model.name,
+                model.max_tokens,
+            );
+        }
     }
 }
 
-impl LanguageModel for MistralLanguageModel {
-    fn id(&self) -> LanguageModelId {
-        self.id.clone()
+/// The model among `models` with the configured `id`, or `fallback` if there is none.
+fn resolve_model(
+    models: &[mistral::Model],
+    id: Option<&str>,
+    fallback: mistral::Model,
+) -> mistral::Model {
+    let Some(id) = id else {
+        return fallback;
+    };
+    match models.iter().find(|model| model.id() == id) {
+        Some(model) => model.clone(),
+        None => {
+            log::warn!(
+                "Unknown {PROVIDER_NAME} model {id:?}, using {}",
+                fallback.id()
+            );
+            fallback
+        }
     }
+}
 
-    fn name(&self) -> LanguageModelName {
-        LanguageModelName::from(self.model.display_name().to_string())
+/// The status of a batch job submitted with [`MistralLanguageModelProvider::submit_batch`].
+pub struct MistralBatch {
+    pub job: mistral::BatchJob,
+    /// The results, once the job has finished and produced an output file.
+    pub results: Option<Vec<mistral::BatchResult>>,
+}
+
+async fn poll_batch(
+    client: &dyn HttpClient,
+    connection: &mistral::Connection,
+    job_id: &str,
+) -> Result<MistralBatch> {
+    let job = mistral::get_batch_job(client, connection, job_id).await?;
+    let results = match job.output_file.as_deref() {
+        Some(output_file) if job.status.is_finished() => {
+            Some(mistral::get_batch_results(client, connection, output_file).await?)
+        }
+        _ => None,
+    };
+    Ok(MistralBatch { job, results })
+}
+
+async fn upload_file(
+    client: &dyn HttpClient,
+    connection: &mistral::Connection,
+    filename: &str,
+    contents: Vec<u8>,
+    purpose: mistral::FilePurpose,
+) -> Result<String> {
+    match mistral::upload_file(client, connection, filename, purpose, contents).await {
+        Ok(file) => Ok(file.id),
+        Err(error) => match error.downcast_ref::<mistral::ApiError>() {
+            Some(api_error) if api_error.status_code == StatusCode::PAYLOAD_TOO_LARGE => Err(
+                anyhow!("{filename} is larger than Mistral accepts for uploaded files"),
+            ),
+            _ => Err(error),
+        },
     }
+}
 
-    fn provider_id(&self) -> LanguageModelProviderId {
-        PROVIDER_ID
+fn discovered_model(card: &mistral::ModelCard) -> Option<mistral::Model> {
+    if !card.capabilities.completion_chat {
+        return None;
     }
 
-    fn provider_name(&self) -> LanguageModelProviderName {
-        PROVIDER_NAME
+    // Dated releases are listed alongside the `-latest` aliases we already know about.
+    let is_built_in = std::iter::once(&card.id)
+        .chain(&card.aliases)
+        .any(|id| mistral::Model::from_id(id).is_ok());
+    if is_built_in {
+        return None;
     }
 
-    fn supports_tools(&self) -> bool {
-        self.model.supports_tools()
+    Some(mistral::Model::Custom {
+        name: card.id.clone(),
+        display_name: None,
+        max_tokens: card.max_context_length?,
+        max_output_tokens: None,
+        max_completion_tokens: None,
+        supports_tools: Some(card.capabilities.function_calling),
+        supports_images: Some(card.capabilities.vision),
+        supports_structured_output: None,
+        supports_thinking: None,
+        supports_prompt_cache: None,
+        max_temperature: None,
+        supports_prediction: None,
+        price_per_token: None,
+        knowledge_cutoff: None,
+        description: None,
+        default_stop_sequences: None,
+    })
+}
+
+/// Estimates the cost in US dollars of `usage`, if the price of `model` is known.
+pub fn usage_cost(model: &mistral::Model, usage: &TokenUsage) -> Option<f64> {
+    let price = model.price_per_token()?;
+    Some(price.cost(usage.input_tokens, usage.output_tokens))
+}
+
+impl MistralLanguageModelProvider {
+    pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut App) -> Self {
+        let state = cx.new(|cx| State {
+            http_client: http_client.clone(),
+            api_key: None,
+            api_key_from_env: false,
+            api_url: AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .api_url
+                .clone(),
+            api_keys_by_url: HashMap::default(),
+            discovered_models: Vec::new(),
+            listed_model_ids: None,
+            fetch_models_task: None,
+            usage_sink: None,
+            latency_sink: None,
+            api_key_rotation: Arc::default(),
+            _subscription: cx.observe_global::<SettingsStore>(|this: &mut State, cx| {
+                warn_about_mismatched_max_tokens(&AllLanguageModelSettings::get_global(cx).mistral);
+                this.api_url_changed(cx);
+                cx.notify();
+            }),
+        });
+        warn_about_mismatched_max_tokens(&AllLanguageModelSettings::get_global(cx).mistral);
+
+        Self {
+            http_client,
+            state,
+            request_deduplicator: RequestDeduplicator::default(),
+        }
     }
 
-    fn supports_tool_choice(&self, _choice: LanguageModelToolChoice) -> bool {
-        self.model.supports_tools()
+    /// Calls `callback` whenever an API key is set or removed, until the returned
+    /// subscription is dropped.
+    pub fn subscribe(
+        &self,
+        mut callback: impl FnMut(MistralEvent, &mut App) + 'static,
+        cx: &mut App,
+    ) -> Subscription {
+        cx.subscribe(&self.state, move |_, event: &MistralEvent, cx| {
+            callback(*event, cx)
+        })
     }
 
-    fn supports_images(&self) -> bool {
-        self.model.supports_images()
+    /// Registers `sink` to receive the token usage of every completion made with this
+    /// provider's models, replacing any sink registered before.
+    pub fn set_usage_sink(&self, sink: Option<UsageSink>, cx: &mut App) {
+        self.state.update(cx, |state, _| state.usage_sink = sink);
     }
 
-    fn telemetry_id(&self) -> String {
-        format!("mistral/{}", self.model.id())
+    /// Registers `sink` to receive the time to first token of every chat completion made
+    /// with this provider's models, replacing any sink registered before.
+    pub fn set_latency_sink(&self, sink: Option<LatencySink>, cx: &mut App) {
+        self.state.update(cx, |state, _| state.latency_sink = sink);
     }
 
-    fn max_token_count(&self) -> u64 {
-        self.model.max_token_count()
+    /// The connection to send requests over, if an API key is set.
+    fn connection(&self, cx: &App) -> Option<mistral::Connection> {
+        let api_key = self.state.read(cx).api_key.as_ref()?;
+        Some(
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .connection(api_key.first()),
+        )
     }
 
-    fn max_output_tokens(&self) -> Option<u64> {
-        self.model.max_output_tokens()
+    /// Computes embeddings for `texts` using the `mistral-embed` model.
+    pub fn embed(&self, texts: Vec<String>, cx: &App) -> Task<Result<Vec<Vec<f32>>>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
+
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            mistral::embed(http_client.as_ref(), &connection, &texts).await
+        })
     }
 
-    fn count_tokens(
+    /// Submits `requests` to `model` as a batch job, which runs asynchronously at a reduced
+    /// price. Each result's `custom_id` is the index of its request.
+    pub fn submit_batch(
         &self,
-        request: LanguageModelRequest,
+        model: mistral::Model,
+        requests: Vec<LanguageModelRequest>,
         cx: &App,
-    ) -> BoxFuture<'static, Result<u64>> {
+    ) -> Task<Result<mistral::BatchJob>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
+        let request_settings = AllLanguageModelSettings::get_global(cx)
+            .mistral
+            .request_settings(&model);
+
         cx.background_spawn(async move {
-            let messages = request
-                .messages
+            let requests = requests
                 .into_iter()
-                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
-                    role: match message.role {
-                        Role::User => "user".into(),
-                        Role::Assistant => "assistant".into(),
-                        Role::System => "system".into(),
-                    },
-                    content: Some(message.string_contents()),
-                    name: None,
-                    function_call: None,
+                .enumerate()
+                .map(|(ix, mut request)| {
+                    if let Some(max_dimension) = request_settings.max_image_dimension {
+                        scale_down_images(&mut request, max_dimension);
+                    }
+                    let mut body = into_mistral(
+                        request,
+                        &model,
+                        model.max_completion_tokens(),
+                        &request_settings,
+                    );
+                    body.stream = false;
+                    mistral::BatchRequest {
+                        custom_id: ix.to_string(),
+                        body,
+                    }
                 })
-                .collect::<Vec<_>>();
+                .collect();
+            let connection = connection.context("Missing Mistral API Key")?;
+            mistral::create_batch_job(http_client.as_ref(), &connection, model.id(), requests).await
+        })
+    }
+
+    /// Uploads `contents` with Mistral's files API and returns the file's ID, which can be
+    /// attached to requests with [`MistralRequestSettings::file_ids`].
+    pub fn upload_file(
+        &self,
+        filename: String,
+        contents: Vec<u8>,
+        purpose: mistral::FilePurpose,
+        cx: &App,
+    ) -> Task<Result<String>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
 
-            tiktoken_rs::num_tokens_from_messages("gpt-4", &messages).map(|tokens| tokens as u64)
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            upload_file(
+                http_client.as_ref(),
+                &connection,
+                &filename,
+                contents,
+                purpose,
+            )
+            .await
         })
-        .boxed()
     }
 
-    fn stream_completion(
+    /// Fetches the status of a batch job, along with its results once it has finished.
+    pub fn poll_batch(&self, job_id: String, cx: &App) -> Task<Result<MistralBatch>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
+
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            poll_batch(http_client.as_ref(), &connection, &job_id).await
+        })
+    }
+
+    /// Runs the fine-tuned classifier `model` on each of `inputs`, such as to detect spam or
+    /// intent. Use [`mistral::ClassifierScores::top_label`] for single-label classifiers and
+    /// [`mistral::ClassifierScores::labels_above`] for multi-label ones.
+    pub fn classify(
         &self,
-        request: LanguageModelRequest,
-        cx: &AsyncApp,
-    ) -> BoxFuture<
-        'static,
-        Result<
-            BoxStream<'static, Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>,
-            LanguageModelCompletionError,
-        >,
-    > {
-        let request = into_mistral(
-            request,
-            self.model.id().to_string(),
-            self.max_output_tokens(),
-        );
-        let stream = self.stream_completion(request, cx);
+        model: String,
+        inputs: Vec<String>,
+        cx: &App,
+    ) -> Task<Result<Vec<mistral::Classification>>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
 
-        async move {
-            let stream = stream.await?;
-            let mapper = MistralEventMapper::new();
-            Ok(mapper.map_stream(stream).boxed())
-        }
-        .boxed()
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            mistral::classify(http_client.as_ref(), &connection, &model, &inputs).await
+        })
     }
-}
 
-pub fn into_mistral(
-    request: LanguageModelRequest,
-    model: String,
-    max_output_tokens: Option<u64>,
-) -> mistral::Request {
-    let stream = true;
+    /// Extracts the text of the PDF or image at `document_url` as markdown, page by page.
+    /// Base64 `data:` URLs are accepted, such as those of attached images. `page_limit` and
+    /// `image_limit` bound how much of the document is processed; `None` means no limit.
+    pub fn ocr(
+        &self,
+        document_url: String,
+        page_limit: Option<u32>,
+        image_limit: Option<u32>,
+        cx: &App,
+    ) -> Task<Result<mistral::OcrResponse>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
 
-    let mut messages = Vec::new();
-    for message in &request.messages {
-        match message.role {
-            Role::User => {
-                let mut message_content = mistral::MessageContent::empty();
-                for content in &message.content {
-                    match content {
-                        MessageContent::Text(text) => {
-                            message_content
-                                .push_part(mistral::MessagePart::Text { text: text.clone() });
-                        }
-                        MessageContent::Image(image_content) => {
-                            message_content.push_part(mistral::MessagePart::ImageUrl {
-                                image_url: image_content.to_base64_url(),
-                            });
-                        }
-                        MessageContent::Thinking { text, .. } => {
-                            message_content
-                                .push_part(mistral::MessagePart::Text { text: text.clone() });
-                        }
-                        MessageContent::RedactedThinking(_) => {}
-                        MessageContent::ToolUse(_) => {
-                            // Tool use is not supported in User messages for Mistral
-                        }
-                        MessageContent::ToolResult(tool_result) => {
-                            let tool_content = match &tool_result.content {
-                                LanguageModelToolResultContent::Text(text) => text.to_string(),
-                                LanguageModelToolResultContent::Image(_) => {
-                                    "[Tool responded with an image, but Zed doesn't support these in Mistral models yet]".to_string()
-                                }
-                            };
-                            messages.push(mistral::RequestMessage::Tool {
-                                content: tool_content,
-                                tool_call_id: tool_result.tool_use_id.to_string(),
-                            });
-                        }
-                    }
-                }
-                if !matches!(message_content, mistral::MessageContent::Plain { ref content } if content.is_empty())
-                {
-                    messages.push(mistral::RequestMessage::User {
-                        content: message_content,
-                    });
-                }
+        let document = if document_url.starts_with("data:image/") {
+            mistral::OcrDocument::ImageUrl {
+                image_url: document_url,
             }
-            Role::Assistant => {
-                for content in &message.content {
-                    match content {
-                        MessageContent::Text(text) | MessageContent::Thinking { text, .. } => {
-                            messages.push(mistral::RequestMessage::Assistant {
-                                content: Some(text.clone()),
-                                tool_calls: Vec::new(),
-                            });
-                        }
-                        MessageContent::RedactedThinking(_) => {}
-                        MessageContent::Image(_) => {}
-                        MessageContent::ToolUse(tool_use) => {
-                            let tool_call = mistral::ToolCall {
-                                id: tool_use.id.to_string(),
-                                content: mistral::ToolCallContent::Function {
-                                    function: mistral::FunctionContent {
-                                        name: tool_use.name.to_string(),
-                                        arguments: serde_json::to_string(&tool_use.input)
-                                            .unwrap_or_default(),
-                                    },
-                                },
-                            };
+        } else {
+            mistral::OcrDocument::DocumentUrl { document_url }
+        };
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            mistral::ocr(
+                http_client.as_ref(),
+                &connection,
+                document,
+                page_limit,
+                image_limit,
+            )
+            .await
+        })
+    }
 
-                            if let Some(mistral::RequestMessage::Assistant { tool_calls, .. }) =
-                                messages.last_mut()
-                            {
-                                tool_calls.push(tool_call);
-                            } else {
-                                messages.push(mistral::RequestMessage::Assistant {
-                                    content: None,
-                                    tool_calls: vec![tool_call],
-                                });
-                            }
-                        }
-                        MessageContent::ToolResult(_) => {
-                            // Tool results are not supported in Assistant messages
-                        }
-                    }
-                }
-            }
-            Role::System => {
-                for content in &message.content {
-                    match content {
-                        MessageContent::Text(text) | MessageContent::Thinking { text, .. } => {
-                            messages.push(mistral::RequestMessage::System {
-                                content: text.clone(),
-                            });
-                        }
-                        MessageContent::RedactedThinking(_) => {}
-                        MessageContent::Image(_)
-                        | MessageContent::ToolUse(_)
-                        | MessageContent::ToolResult(_) => {
-                            // Images and tools are not supported in System messages
-                        }
-                    }
-                }
-            }
-        }
+    /// Every model offered, with whether the API reported it as available when it was last
+    /// asked, so that a model picker can set apart models that can't be used.
+    pub fn models_with_status(&self, cx: &App) -> Vec<(mistral::Model, ModelStatus)> {
+        self.state.read(cx).models_with_status(cx)
     }
 
-    // The Mistral API requires that tool messages be followed by assistant messages,
-    // not user messages. When we have a tool->user sequence in the conversation,
-    // we need to insert a placeholder assistant message to maintain proper conversation
-    // flow and prevent API errors. This is a Mistral-specific requirement that differs
-    // from other language model APIs.
-    let messages = {
-        let mut fixed_messages = Vec::with_capacity(messages.len());
-        let mut messages_iter = messages.into_iter().peekable();
+    /// Checks that Mistral is reachable at the configured API URL and accepts the API key,
+    /// such as for testing the connection from the configuration view.
+    pub fn check_health(&self, cx: &App) -> Task<Result<(), HealthCheckError>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
+
+        cx.background_spawn(async move {
+            let connection = connection.ok_or(HealthCheckError::NoApiKey)?;
+            check_health(http_client.as_ref(), &connection).await
+        })
+    }
 
-        while let Some(message) = messages_iter.next() {
-            let is_tool_message = matches!(message, mistral::RequestMessage::Tool { .. });
-            fixed_messages.push(message);
+    /// Classifies `text` with Mistral's moderation model, so that content can be screened before
+    /// it is sent to a model.
+    pub fn moderate(&self, text: String, cx: &App) -> Task<Result<mistral::ModerationResult>> {
+        let http_client = self.http_client.clone();
+        let connection = self.connection(cx);
 
-            // Insert assistant message between tool and user messages
-            if is_tool_message {
-                if let Some(next_msg) = messages_iter.peek() {
-                    if matches!(next_msg, mistral::RequestMessage::User { .. }) {
-                        fixed_messages.push(mistral::RequestMessage::Assistant {
-                            content: Some(" ".to_string()),
-                            tool_calls: Vec::new(),
-                        });
+        cx.background_spawn(async move {
+            let connection = connection.context("Missing Mistral API Key")?;
+            mistral::moderate(http_client.as_ref(), &connection, &text)
+                .await
+                .map_err(|error| match error.downcast_ref::<mistral::ApiError>() {
+                    Some(api_error)
+                        if matches!(
+                            api_error.status_code,
+                            StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+                        ) =>
+                    {
+                        anyhow!("This Mistral account doesn't have access to the moderation API")
                     }
-                }
-            }
-        }
+                    _ => error,
+                })
+        })
+    }
 
-        fixed_messages
-    };
+    fn create_language_model(&self, model: mistral::Model, cx: &App) -> Arc<dyn LanguageModel> {
+        let max_concurrent_requests = AllLanguageModelSettings::get_global(cx)
+            .mistral
+            .max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+            .max(1);
+        Arc::new(MistralLanguageModel {
+            id: LanguageModelId::from(model.id().to_string()),
+            model,
+            state: self.state.clone(),
+            http_client: self.http_client.clone(),
+            request_limiter: RateLimiter::new(max_concurrent_requests),
+            request_deduplicator: self.request_deduplicator.clone(),
+            token_count_cache: Arc::new(Mutex::new(TokenCountCache::new(
+                TOKEN_COUNT_CACHE_CAPACITY,
+            ))),
+        })
+    }
+}
 
-    mistral::Request {
-        model,
-        messages,
-        stream,
-        max_tokens: max_output_tokens,
-        temperature: request.temperature,
-        response_format: None,
-        tool_choice: match request.tool_choice {
-            Some(LanguageModelToolChoice::Auto) if !request.tools.is_empty() => {
-                Some(mistral::ToolChoice::Auto)
-            }
-            Some(LanguageModelToolChoice::Any) if !request.tools.is_empty() => {
-                Some(mistral::ToolChoice::Any)
+#[cfg(any(test, feature = "test-support"))]
+impl MistralLanguageModelProvider {
+    /// Creates a provider that needs no network access: each completion request is answered
+    /// by streaming the chunks of the next of `responses`, and other requests fail with a 404
+    /// status. The provider is authenticated with a placeholder API key. Its settings must
+    /// already be registered.
+    pub fn fake(responses: Vec<Vec<StreamResponse>>, cx: &mut App) -> Self {
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+        let http_client = http_client::FakeHttpClient::create(move |request| {
+            let response = request
+                .uri()
+                .path()
+                .ends_with("/chat/completions")
+                .then(|| responses.lock().pop_front());
+            async move {
+                let Some(response) = response else {
+                    return Ok(http_client::Response::builder()
+                        .status(404)
+                        .body(Default::default())?);
+                };
+                let chunks = response.context("No scripted Mistral responses left")?;
+                let mut body = String::new();
+                for chunk in chunks {
+                    body.push_str(&format!("data: {}\n\n", serde_json::to_string(&chunk)?));
+                }
+                body.push_str("data: [DONE]\n\n");
+                Ok(http_client::Response::builder()
+                    .status(200)
+                    .body(body.into())?)
             }
-            Some(LanguageModelToolChoice::None) => Some(mistral::ToolChoice::None),
-            _ if !request.tools.is_empty() => Some(mistral::ToolChoice::Auto),
-            _ => None,
-        },
-        parallel_tool_calls: if !request.tools.is_empty() {
-            Some(false)
-        } else {
-            None
-        },
-        tools: request
-            .tools
-            .into_iter()
-            .map(|tool| mistral::ToolDefinition::Function {
-                function: mistral::FunctionDefinition {
-                    name: tool.name,
-                    description: Some(tool.description),
-                    parameters: Some(tool.input_schema),
-                },
-            })
-            .collect(),
+        });
+
+        let provider = Self::new(http_client, cx);
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("fake-api-key".into());
+        });
+        provider
     }
 }
 
-pub struct MistralEventMapper {
-    tool_calls_by_index: HashMap<usize, RawToolCall>,
-}
+impl LanguageModelProviderState for MistralLanguageModelProvider {
+    type ObservableEntity = State;
 
-impl MistralEventMapper {
-    pub fn new() -> Self {
-        Self {
-            tool_calls_by_index: HashMap::default(),
-        }
+    fn observable_entity(&self) -> Option<gpui::Entity<Self::ObservableEntity>> {
+        Some(self.state.clone())
     }
+}
 
-    pub fn map_stream(
-        mut self,
-        events: Pin<Box<dyn Send + Stream<Item = Result<StreamResponse>>>>,
-    ) -> impl Stream<Item = Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>
-    {
-        events.flat_map(move |event| {
-            futures::stream::iter(match event {
-                Ok(event) => self.map_event(event),
-                Err(error) => vec![Err(LanguageModelCompletionError::from(error))],
-            })
-        })
+impl LanguageModelProvider for MistralLanguageModelProvider {
+    fn id(&self) -> LanguageModelProviderId {
+        PROVIDER_ID
     }
 
-    pub fn map_event(
-        &mut self,
-        event: mistral::StreamResponse,
-    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
-        let Some(choice) = event.choices.first() else {
-            return vec![Err(LanguageModelCompletionError::from(anyhow!(
-                "Response contained no choices"
-            )))];
-        };
-
-        let mut events = Vec::new();
-        if let Some(content) = choice.delta.content.clone() {
-            events.push(Ok(LanguageModelCompletionEvent::Text(content)));
-        }
-
-        if let Some(tool_calls) = choice.delta.tool_calls.as_ref() {
-            for tool_call in tool_calls {
-                let entry = self.tool_calls_by_index.entry(tool_call.index).or_default();
-
-                if let Some(tool_id) = tool_call.id.clone() {
-                    entry.id = tool_id;
-                }
-
-                if let Some(function) = tool_call.function.as_ref() {
-                    if let Some(name) = function.name.clone() {
-                        entry.name = name;
-                    }
-
-                    if let Some(arguments) = function.arguments.clone() {
-                        entry.arguments.push_str(&arguments);
-                    }
-                }
-            }
-        }
+    fn name(&self) -> LanguageModelProviderName {
+        PROVIDER_NAME
+    }
 
-        if let Some(usage) = event.usage {
-            events.push(Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
-                input_tokens: usage.prompt_tokens,
-                output_tokens: usage.completion_tokens,
-                cache_creation_input_tokens: 0,
-                cache_read_input_tokens: 0,
-            })));
-        }
+    fn icon(&self) -> IconName {
+        IconName::AiMistral
+    }
 
-        if let Some(finish_reason) = choice.finish_reason.as_deref() {
-            match finish_reason {
-                "stop" => {
-                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
-                }
-                "tool_calls" => {
-                    events.extend(self.process_tool_calls());
-                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)));
-                }
-                unexpected => {
-                    log::error!("Unexpected Mistral stop_reason: {unexpected:?}");
-                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
-                }
-            }
-        }
+    fn default_model(&self, cx: &App) -> Option<Arc<dyn LanguageModel>> {
+        let model = resolve_model(
+            &self.state.read(cx).models(cx),
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .default_model
+                .as_deref(),
+            mistral::Model::default(),
+        );
+        Some(self.create_language_model(model, cx))
+    }
 
-        events
+    fn default_fast_model(&self, cx: &App) -> Option<Arc<dyn LanguageModel>> {
+        let model = resolve_model(
+            &self.state.read(cx).models(cx),
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .default_fast_model
+                .as_deref(),
+            mistral::Model::default_fast(),
+        );
+        Some(self.create_language_model(model, cx))
     }
 
-    fn process_tool_calls(
-        &mut self,
-    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
-        let mut results = Vec::new();
+    fn provided_models(&self, cx: &App) -> Vec<Arc<dyn LanguageModel>> {
+        self.state
+            .read(cx)
+            .models(cx)
+            .into_iter()
+            .map(|model| self.create_language_model(model, cx))
+            .collect()
+    }
 
-        for (_, tool_call) in self.tool_calls_by_index.drain() {
-            if tool_call.id.is_empty() || tool_call.name.is_empty() {
-                results.push(Err(LanguageModelCompletionError::from(anyhow!(
-                    "Received incomplete tool call: missing id or name"
-                ))));
-                continue;
-            }
+    fn is_authenticated(&self, cx: &App) -> bool {
+        self.state.read(cx).is_authenticated()
+    }
 
-            match serde_json::Value::from_str(&tool_call.arguments) {
-                Ok(input) => results.push(Ok(LanguageModelCompletionEvent::ToolUse(
-                    LanguageModelToolUse {
-                        id: tool_call.id.into(),
-                        name: tool_call.name.into(),
-                        is_input_complete: true,
-                        input,
-                        raw_input: tool_call.arguments,
-                    },
-                ))),
-                Err(error) => {
-                    results.push(Ok(LanguageModelCompletionEvent::ToolUseJsonParseError {
-                        id: tool_call.id.into(),
-                        tool_name: tool_call.name.into(),
-                        raw_input: tool_call.arguments.into(),
-                        json_parse_error: error.to_string(),
-                    }))
-                }
-            }
-        }
+    fn authenticate(&self, cx: &mut App) -> Task<Result<(), AuthenticateError>> {
+        self.state.update(cx, |state, cx| state.authenticate(cx))
+    }
 
-        results
+    fn configuration_view(&self, window: &mut Window, cx: &mut App) -> AnyView {
+        cx.new(|cx| ConfigurationView::new(self.state.clone(), window, cx))
+            .into()
     }
-}
 
-#[derive(Default)]
-struct RawToolCall {
-    id: String,
-    name: String,
-    arguments: String,
+    fn reset_credentials(&self, cx: &mut App) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.reset_api_key(cx))
+    }
 }
 
-struct ConfigurationView {
-    api_key_editor: Entity<Editor>,
+pub struct MistralLanguageModel {
+    id: LanguageModelId,
+    model: mistral::Model,
     state: gpui::Entity<State>,
-    load_credentials_task: Option<Task<()>>,
+    http_client: Arc<dyn HttpClient>,
+    request_limiter: RateLimiter,
+    request_deduplicator: RequestDeduplicator,
+    token_count_cache: Arc<Mutex<TokenCountCache>>,
 }
 
-impl ConfigurationView {
-    fn new(state: gpui::Entity<State>, window: &mut Window, cx: &mut Context<Self>) -> Self {
-        let api_key_editor = cx.new(|cx| {
-            let mut editor = Editor::single_line(window, cx);
-            editor.set_placeholder_text("0aBCDEFGhIjKLmNOpqrSTUVwxyzabCDE1f2", cx);
-            editor
+/// The requests to send in turn if the model of a request is unavailable, converted only as
+/// they're needed.
+type FallbackRequests = Box<dyn Iterator<Item = mistral::Request> + Send>;
+
+impl MistralLanguageModel {
+    /// The number of this model's requests waiting for others to finish, since at most
+    /// `max_concurrent_requests` run at once. Useful for showing that a prompt is queued.
+    pub fn queued_requests(&self) -> usize {
+        self.request_limiter.queued()
+    }
+
+    /// The number of this model's requests that are currently running.
+    pub fn in_flight_requests(&self) -> usize {
+        self.request_limiter.in_flight()
+    }
+
+    /// Streams the response to `request` like [`LanguageModel::stream_completion`], after
+    /// `configure` sets the parameters that a [`LanguageModelRequest`] can't carry, such as
+    /// the [number of completions](MistralRequestSettings::n) to generate or the uploaded
+    /// [files](MistralRequestSettings::file_ids) to attach. Each event is paired with the
+    /// index of the completion it belongs to.
+    pub fn stream_choices(
+        &self,
+        request: LanguageModelRequest,
+        configure: impl Fn(&mut MistralRequestSettings),
+        cx: &AsyncApp,
+    ) -> BoxFuture<
+        'static,
+        Result<
+            BoxStream<
+                'static,
+                (
+                    u32,
+                    Result<LanguageModelCompletionEvent, LanguageModelCompletionError>,
+                ),
+            >,
+            LanguageModelCompletionError,
+        >,
+    > {
+        let Ok((request_settings, idle_timeout, raw_response_secrets, lenient_json, fallbacks)) =
+            cx.update(|cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).mistral;
+                let mut request_settings = settings.request_settings(&self.model);
+                configure(&mut request_settings);
+                (
+                    request_settings,
+                    settings
+                        .stream_idle_timeout
+                        .unwrap_or(DEFAULT_STREAM_IDLE_TIMEOUT),
+                    settings.log_raw_responses.then(|| {
+                        self.state
+                            .read(cx)
+                            .api_key
+                            .iter()
+                            .flat_map(|api_key| api_key.keys())
+                            .map(str::to_string)
+                            .collect::<Vec<_>>()
+                    }),
+                    settings.lenient_json,
+                    settings.fallback_models(&self.state.read(cx).models(cx), &self.model),
+                )
+            })
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped").into())).boxed();
+        };
+        let fallbacks = fallbacks
+            .into_iter()
+            .filter(|(model, _)| supports_request(model, &request))
+            .map(|(model, mut request_settings)| {
+                configure(&mut request_settings);
+                (model, request_settings)
+            })
+            .collect::<Vec<_>>();
+        let model = self.model.clone();
+        let requests = cx.background_spawn(async move {
+            let mut request = request;
+            // Images are scaled once here, rather than for each model the request is for.
+            if let Some(max_dimension) = request_settings.max_image_dimension {
+                scale_down_images(&mut request, max_dimension);
+            }
+            // Fallback requests are only converted once the models before them fail.
+            let fallback_requests: FallbackRequests = if fallbacks.is_empty() {
+                Box::new(std::iter::empty())
+            } else {
+                let request = request.clone();
+                Box::new(fallbacks.into_iter().map(move |(model, request_settings)| {
+                    into_mistral(
+                        request.clone(),
+                        &model,
+                        model.max_completion_tokens(),
+                        &request_settings,
+                    )
+                }))
+            };
+            let request = into_mistral(
+                request,
+                &model,
+                model.max_completion_tokens(),
+                &request_settings,
+            );
+            (request, fallback_requests)
         });
+        let stream = self.stream_completion(requests, cx);
+        let mut mapper = MistralEventMapper::new(&self.model).lenient_json(lenient_json);
+        if let Some(secrets) = raw_response_secrets {
+            mapper = mapper.log_raw_responses(secrets);
+        }
 
-        cx.observe(&state, |_, _, cx| {
-            cx.notify();
+        async move {
+            // The timeout applies to the chunks rather than the events, so that the mapper
+            // sees it like any other error and still reports the usage of what streamed.
+            let stream = with_idle_timeout(stream.await?, idle_timeout);
+            Ok(mapper.map_choice_stream(stream).boxed())
+        }
+        .boxed()
+    }
+
+    /// Streams the response to the request that `requests` resolves to, sending the fallback
+    /// requests it resolves to in turn if the model it's for is overloaded or rate limited.
+    fn stream_completion(
+        &self,
+        requests: impl 'static + Send + Future<Output = (mistral::Request, FallbackRequests)>,
+        cx: &AsyncApp,
+    ) -> BoxFuture<
+        'static,
+        Result<
+            futures::stream::BoxStream<'static, Result<mistral::StreamResponse>>,
+            LanguageModelCompletionError,
+        >,
+    > {
+        let http_client = self.http_client.clone();
+        let Ok((
+            api_key,
+            connection,
+            retry_policy,
+            request_timeout,
+            deduplicate_requests,
+            usage_sink,
+            latency_sink,
+            api_key_rotation,
+        )) = cx.read_entity(&self.state, |state, cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).mistral;
+            (
+                state.api_key.clone(),
+                // The key is picked for each attempt.
+                settings.connection(""),
+                settings.retry_policy(),
+                settings.request_timeout,
+                settings.deduplicate_requests,
+                state.usage_sink.clone(),
+                state.latency_sink.clone(),
+                state.api_key_rotation.clone(),
+            )
         })
-        .detach();
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped").into())).boxed();
+        };
 
-        let load_credentials_task = Some(cx.spawn_in(window, {
-            let state = state.clone();
-            async move |this, cx| {
-                if let Some(task) = state
-                    .update(cx, |state, cx| state.authenticate(cx))
-                    .log_err()
-                {
-                    // We don't log an error, because "not signed in" is also an error.
-                    let _ = task.await;
+        let request_limiter = self.request_limiter.clone();
+        let executor = cx.background_executor().clone();
+        let send = move |mut request: mistral::Request, mut fallback_requests: FallbackRequests| {
+            let executor = executor.clone();
+            // The returned stream owns the response body and the rate limiter's permit, so
+            // dropping it to cancel a completion closes the connection and frees the permit.
+            let future = request_limiter.stream(async move {
+                let sent_at = executor.now();
+                let api_key = api_key.context("Missing Mistral API Key")?;
+                let http_client = http_client.as_ref();
+                let connection = &connection;
+                let api_key_rotation = api_key_rotation.as_ref();
+                let executor = &executor;
+                // Each key is tried once before giving up on a rate limited request, even if
+                // the API asks for keys to be retried sooner than they're picked again.
+                let mut key_rotations_left = api_key.keys().count().saturating_sub(1);
+                loop {
+                    let result = retry_policy
+                        .retry(|| {
+                            // Each attempt picks a key, so that retries can use another one.
+                            let connection = mistral::Connection {
+                                api_key: api_key_rotation.lock().next_key(&api_key, executor.now()),
+                                ..connection.clone()
+                            };
+                            // A completion that isn't streamed is only answered once all of it
+                            // has been generated, so waiting for it isn't limited.
+                            let request_timeout = request_timeout.filter(|_| request.stream);
+                            let request = request.clone();
+                            async move {
+                                let result = with_request_timeout(
+                                    mistral::stream_completion(http_client, &connection, request),
+                                    request_timeout,
+                                )
+                                .await;
+                                if let Err(error) = &result {
+                                    api_key_rotation.lock().report_error(
+                                        &connection.api_key,
+                                        error,
+                                        executor.now(),
+                                    );
+                                }
+                                result
+                            }
+                        })
+                        .await;
+                    // A rate limited request is retried with each of the other keys before
+                    // falling back to another model, since the limit may be the key's rather
+                    // than the model's.
+                    match result {
+                        Err(error)
+                            if is_rate_limit_error(&error)
+                                && key_rotations_left > 0
+                                && api_key_rotation
+                                    .lock()
+                                    .has_available_key(&api_key, executor.now()) =>
+                        {
+                            key_rotations_left -= 1;
+                            log::warn!(
+                                "Retrying {PROVIDER_NAME} request with another API key: {error}"
+                            );
+                        }
+                        Err(error) if is_capacity_error(&error) => {
+                            let Some(fallback_request) = fallback_requests.next() else {
+                                return Err(into_completion_error(error));
+                            };
+                            log::warn!(
+                                "{} is unavailable, falling back to {}: {error}",
+                                request.model,
+                                fallback_request.model
+                            );
+                            request = fallback_request;
+                        }
+                        result => {
+                            let stream = result.map_err(into_completion_error)?;
+                            return Ok(match latency_sink {
+                                Some(sink) => report_first_token_latency(
+                                    stream,
+                                    executor.clone(),
+                                    sent_at,
+                                    sink,
+                                ),
+                                None => stream,
+                            });
+                        }
+                    }
                 }
-
-                this.update(cx, |this, cx| {
-                    this.load_credentials_task = None;
-                    cx.notify();
+            });
+            async move {
+                let stream = future.await?.boxed();
+                Ok(match usage_sink {
+                    Some(sink) => report_usage(stream, sink),
+                    None => stream,
                 })
-                .log_err();
             }
-        }));
+        };
 
-        Self {
-            api_key_editor,
-            state,
-            load_credentials_task,
+        if deduplicate_requests {
+            // Hashing reads the whole request, including any images, so it's done in the
+            // background.
+            let key = cx.background_spawn(async move {
+                let (request, fallback_requests) = requests.await;
+                let key = RequestDeduplicator::key(&request);
+                (key, request, fallback_requests)
+            });
+            let request_deduplicator = self.request_deduplicator.clone();
+            let executor = cx.background_executor().clone();
+            async move {
+                let (key, request, fallback_requests) = key.await;
+                request_deduplicator
+                    .stream(key, &executor, || send(request, fallback_requests))
+                    .await
+            }
+            .boxed()
+        } else {
+            async move {
+                let (request, fallback_requests) = requests.await;
+                send(request, fallback_requests).await
+            }
+            .boxed()
         }
     }
 
-    fn save_api_key(&mut self, _: &menu::Confirm, window: &mut Window, cx: &mut Context<Self>) {
-        let api_key = self.api_key_editor.read(cx).text(cx);
-        if api_key.is_empty() {
-            return;
+    /// Streams a fill-in-the-middle completion of the code between `prompt` and `suffix`.
+    /// Only models that [support FIM](mistral::Model::supports_fim) can be used.
+    pub fn stream_fim_completion(
+        &self,
+        prompt: String,
+        suffix: Option<String>,
+        stop: Vec<String>,
+        cx: &AsyncApp,
+    ) -> BoxFuture<
+        'static,
+        Result<
+            BoxStream<'static, Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>,
+            LanguageModelCompletionError,
+        >,
+    > {
+        if !self.model.supports_fim() {
+            return futures::future::ready(Err(anyhow!(
+                "{} does not support fill-in-the-middle completions",
+                self.model.id()
+            )
+            .into()))
+            .boxed();
         }
 
-        let state = self.state.clone();
-        cx.spawn_in(window, async move |_, cx| {
-            state
-                .update(cx, |state, cx| state.set_api_key(api_key, cx))?
+        let http_client = self.http_client.clone();
+        let Ok((api_key, connection, usage_sink, api_key_rotation)) =
+            cx.read_entity(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).mistral;
+                (
+                    state.api_key.clone(),
+                    // The key is picked when the request is sent.
+                    settings.connection(""),
+                    state.usage_sink.clone(),
+                    state.api_key_rotation.clone(),
+                )
+            })
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped").into())).boxed();
+        };
+
+        let request = mistral::FimRequest {
+            model: self.model.id().to_string(),
+            prompt,
+            suffix,
+            max_tokens: self.max_output_tokens(),
+            stop,
+            stream: true,
+        };
+        let executor = cx.background_executor().clone();
+        let future = self.request_limiter.stream(async move {
+            let api_key = api_key.context("Missing Mistral API Key")?;
+            let connection = mistral::Connection {
+                api_key: api_key_rotation.lock().next_key(&api_key, executor.now()),
+                ..connection
+            };
+            mistral::stream_fim_completion(http_client.as_ref(), &connection, request)
                 .await
-        })
-        .detach_and_log_err(cx);
+                .map_err(into_completion_error)
+        });
 
-        cx.notify();
+        async move {
+            let mut stream = future.await?.boxed();
+            if let Some(sink) = usage_sink {
+                stream = report_usage(stream, sink);
+            }
+            Ok(MistralFimEventMapper::map_stream(stream).boxed())
+        }
+        .boxed()
     }
+}
 
-    fn reset_api_key(&mut self, window: &mut Window, cx: &mut Context<Self>) {
-        self.api_key_editor
-            .update(cx, |editor, cx| editor.set_text("", window, cx));
-
-        let state = self.state.clone();
-        cx.spawn_in(window, async move |_, cx| {
-            state.update(cx, |state, cx| state.reset_api_key(cx))?.await
-        })
-        .detach_and_log_err(cx);
+impl LanguageModel for MistralLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.id.clone()
+    }
 
-        cx.notify();
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(self.model.display_name().to_string())
     }
 
-    fn render_api_key_editor(&self, cx: &mut Context<Self>) -> impl IntoElement {
-        let settings = ThemeSettings::get_global(cx);
+    fn provider_id(&self) -> LanguageModelProviderId {
+        PROVIDER_ID
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        PROVIDER_NAME
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.model.supports_tools()
+    }
+
+    fn supports_tool_choice(&self, _choice: LanguageModelToolChoice) -> bool {
+        self.model.supports_tools()
+    }
+
+    fn supports_images(&self) -> bool {
+        self.model.supports_images()
+    }
+
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        LanguageModelCapabilities {
+            tools: self.supports_tools(),
+            images: self.supports_images(),
+            thinking: self.model.supports_thinking(),
+            structured_output: self.model.supports_structured_output(),
+            fim: self.model.supports_fim(),
+        }
+    }
+
+    fn telemetry_id(&self) -> String {
+        format!("mistral/{}", self.model.id())
+    }
+
+    fn max_token_count(&self) -> u64 {
+        self.model.max_token_count()
+    }
+
+    fn max_output_tokens(&self) -> Option<u64> {
+        self.model.max_output_tokens()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &App,
+    ) -> BoxFuture<'static, Result<u64>> {
+        let model = self.model.clone();
+        let cache = self.token_count_cache.clone();
+        cx.background_spawn(async move {
+            let key = TokenCountCache::key(&request);
+            if let Some(count) = cache.lock().get(key) {
+                return Ok(count);
+            }
+            // The cache is unlocked while tokenizing, so that counts of other requests
+            // aren't held up behind this one.
+            let count = count_mistral_tokens(request, &model)?;
+            cache.lock().insert(key, count);
+            Ok(count)
+        })
+        .boxed()
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncApp,
+    ) -> BoxFuture<
+        'static,
+        Result<
+            BoxStream<'static, Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>,
+            LanguageModelCompletionError,
+        >,
+    > {
+        let stream = self.stream_choices(request, |_| {}, cx);
+        async move { Ok(stream.await?.map(|(_, event)| event).boxed()) }.boxed()
+    }
+}
+
+/// Passes on the chunks of `stream`, reporting to `sink` how long after `sent_at` the first
+/// one with any output arrived, as measured by `executor`'s clock.
+fn report_first_token_latency(
+    stream: BoxStream<'static, Result<StreamResponse>>,
+    executor: BackgroundExecutor,
+    sent_at: Instant,
+    sink: LatencySink,
+) -> BoxStream<'static, Result<StreamResponse>> {
+    let mut sink = Some(sink);
+    stream
+        .inspect(move |chunk| {
+            let Ok(chunk) = chunk else {
+                return;
+            };
+            if has_output(chunk) {
+                if let Some(sink) = sink.take() {
+                    sink(&LatencyReport {
+                        model_id: chunk.model.clone(),
+                        first_token_latency: executor.now().saturating_duration_since(sent_at),
+                    });
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Whether `chunk` carries any of the completion's output, rather than only its role, finish
+/// reason, or usage.
+fn has_output(chunk: &StreamResponse) -> bool {
+    chunk.choices.iter().any(|choice| {
+        let delta = &choice.delta;
+        delta.content.as_ref().is_some_and(|text| !text.is_empty())
+            || delta
+                .reasoning_content
+                .as_ref()
+                .is_some_and(|text| !text.is_empty())
+            || delta
+                .tool_calls
+                .as_ref()
+                .is_some_and(|tool_calls| !tool_calls.is_empty())
+    })
+}
+
+/// Passes on the chunks of `stream` and, once it ends, reports the usage of the completion
+/// to `sink`. Nothing is reported for a completion that's dropped before it ends.
+fn report_usage(
+    stream: BoxStream<'static, Result<StreamResponse>>,
+    sink: UsageSink,
+) -> BoxStream<'static, Result<StreamResponse>> {
+    futures::stream::unfold(
+        (stream, None::<UsageReport>),
+        move |(mut stream, mut report)| {
+            let sink = sink.clone();
+            async move {
+                let Some(chunk) = stream.next().await else {
+                    if let Some(report) = &report {
+                        sink(report);
+                    }
+                    return None;
+                };
+                // Mistral reports the usage of the whole completion in its last chunk.
+                if let Ok(StreamResponse {
+                    model,
+                    usage: Some(usage),
+                    ..
+                }) = &chunk
+                {
+                    report = Some(UsageReport {
+                        model_id: model.clone(),
+                        usage: token_usage(usage),
+                        timestamp: SystemTime::now(),
+                    });
+                }
+                Some((chunk, (stream, report)))
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Ends `stream` with an error if no chunk arrives within `timeout`, so that a stalled
+/// connection doesn't leave the completion pending forever.
+fn with_idle_timeout<T: Send + 'static>(
+    stream: BoxStream<'static, Result<T>>,
+    timeout: Duration,
+) -> BoxStream<'static, Result<T>> {
+    futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        let event = match futures::future::select(stream.next(), smol::Timer::after(timeout)).await
+        {
+            Either::Left((event, _)) => event,
+            Either::Right(_) => {
+                let error = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no response received for {} seconds", timeout.as_secs()),
+                );
+                return Some((Err(error.into()), None));
+            }
+        };
+        event.map(|event| (event, Some(stream)))
+    })
+    .boxed()
+}
+
+/// Fails `request` if it doesn't complete within `timeout`. Without a timeout, the request
+/// waits as long as the HTTP client allows.
+async fn with_request_timeout<T>(
+    request: impl Future<Output = Result<T>>,
+    timeout: Option<Duration>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return request.await;
+    };
+    match futures::future::select(pin!(request), smol::Timer::after(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("request timed out after {} seconds", timeout.as_secs()),
+        )
+        .into()),
+    }
+}
+
+/// Converts errors from the `mistral` crate, preserving the status code and `Retry-After` delay
+/// of unsuccessful API responses so that callers can decide whether and when to retry.
+fn into_completion_error(error: anyhow::Error) -> LanguageModelCompletionError {
+    match error.downcast::<mistral::ApiError>() {
+        Ok(error) => api_error_to_completion_error(error),
+        Err(error) => error.into(),
+    }
+}
+
+/// Converts an error that ended a response stream, reporting I/O errors such as a stalled
+/// connection as failures to read the response.
+fn into_stream_error(error: anyhow::Error) -> LanguageModelCompletionError {
+    match error.downcast::<io::Error>() {
+        Ok(error) => LanguageModelCompletionError::ApiReadResponseError {
+            provider: PROVIDER_NAME,
+            error,
+        },
+        Err(error) => error.into(),
+    }
+}
+
+fn api_error_to_completion_error(error: mistral::ApiError) -> LanguageModelCompletionError {
+    if let Some(prompt_too_large) = mistral::parse_prompt_too_large(&error.message) {
+        return LanguageModelCompletionError::PromptTooLarge {
+            tokens: Some(prompt_too_large.tokens),
+            max_tokens: prompt_too_large.max_tokens,
+        };
+    }
+
+    match error.status_code {
+        // Mistral reports schema validation failures as 422s.
+        StatusCode::UNPROCESSABLE_ENTITY => LanguageModelCompletionError::BadRequestFormat {
+            provider: PROVIDER_NAME,
+            message: error.message,
+        },
+        status_code
+            if status_code.is_server_error()
+                && status_code != StatusCode::SERVICE_UNAVAILABLE
+                && status_code.as_u16() != 529 =>
+        {
+            LanguageModelCompletionError::ApiInternalServerError {
+                provider: PROVIDER_NAME,
+                message: error.message,
+            }
+        }
+        status_code => LanguageModelCompletionError::from_http_status(
+            PROVIDER_NAME,
+            status_code,
+            error.message,
+            error.retry_after,
+        ),
+    }
+}
+
+/// The OpenAI model whose tokenizer most closely matches `model`'s. Mistral's newer models use
+/// Tekken, a tiktoken-based tokenizer with a vocabulary comparable to `o200k_base`, while older
+/// models use a SentencePiece tokenizer that splits text more like `cl100k_base`.
+fn tiktoken_model(model: &mistral::Model) -> &'static str {
+    match model {
+        mistral::Model::OpenCodestralMamba => "gpt-4",
+        _ => "gpt-4o",
+    }
+}
+
+/// Estimates the tokens Mistral's vision encoder uses for `image`. Images are downscaled to fit
+/// within 1024x1024 and split into 16x16 pixel patches, each costing one token, plus one token at
+/// the end of every row of patches.
+fn image_tokens(image: &LanguageModelImage) -> u64 {
+    const PATCH_SIZE: f64 = 16.;
+    const MAX_IMAGE_DIMENSION: f64 = 1024.;
+
+    let width = image.size.width.0.unsigned_abs() as f64;
+    let height = image.size.height.0.unsigned_abs() as f64;
+    if width == 0. || height == 0. {
+        return 0;
+    }
+
+    let scale = (MAX_IMAGE_DIMENSION / width.max(height)).min(1.);
+    let columns = (width * scale / PATCH_SIZE).ceil() as u64;
+    let rows = (height * scale / PATCH_SIZE).ceil() as u64;
+    rows * columns + rows
+}
+
+pub fn count_mistral_tokens(request: LanguageModelRequest, model: &mistral::Model) -> Result<u64> {
+    Ok(count_tokens(&request, tiktoken_model(model)))
+}
+
+/// Counts the tokens of `request` with the `tokenizer_model` tokenizer, or estimates them
+/// if tokenization fails, so that the token indicator keeps working.
+fn count_tokens(request: &LanguageModelRequest, tokenizer_model: &str) -> u64 {
+    let image_tokens = request
+        .messages
+        .iter()
+        .flat_map(|message| &message.content)
+        .map(|content| match content {
+            MessageContent::Image(image) => image_tokens(image),
+            _ => 0,
+        })
+        .sum::<u64>();
+    let text_tokens = tokenize(request, tokenizer_model).unwrap_or_else(|error| {
+        log::warn!("Failed to tokenize {PROVIDER_NAME} request, estimating instead: {error:#}");
+        estimate_text_tokens(request)
+    });
+    text_tokens + image_tokens
+}
+
+fn tokenize(request: &LanguageModelRequest, tokenizer_model: &str) -> Result<u64> {
+    // Tool definitions are sent as JSON alongside the messages.
+    let tool_tokens = if request.tools.is_empty() {
+        0
+    } else {
+        let bpe = tiktoken_rs::get_bpe_from_model(tokenizer_model)?;
+        request
+            .tools
+            .iter()
+            .map(|tool| {
+                let definition = serde_json::to_string(&tool_definition(tool.clone()))?;
+                Ok(bpe.encode_with_special_tokens(&definition).len() as u64)
+            })
+            .sum::<Result<u64>>()?
+    };
+
+    let messages = request
+        .messages
+        .iter()
+        .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+            role: match message.role {
+                Role::User => "user".into(),
+                Role::Assistant => "assistant".into(),
+                Role::System => "system".into(),
+            },
+            content: Some(message.string_contents()),
+            name: None,
+            function_call: None,
+        })
+        .collect::<Vec<_>>();
+
+    let text_tokens = tiktoken_rs::num_tokens_from_messages(tokenizer_model, &messages)?;
+    Ok(text_tokens as u64 + tool_tokens)
+}
+
+/// Estimates the number of tokens `chars` characters of text encode to, at
+/// [`CHARS_PER_TOKEN`].
+fn estimate_tokens(chars: usize) -> u64 {
+    chars.div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// Estimates the text tokens of `request` with [`estimate_tokens`].
+fn estimate_text_tokens(request: &LanguageModelRequest) -> u64 {
+    let message_chars = request
+        .messages
+        .iter()
+        .map(|message| message.string_contents().chars().count())
+        .sum::<usize>();
+    let tool_chars = request
+        .tools
+        .iter()
+        .map(|tool| {
+            serde_json::to_string(&tool_definition(tool.clone()))
+                .map_or(0, |definition| definition.chars().count())
+        })
+        .sum::<usize>();
+    estimate_tokens(message_chars + tool_chars)
+}
+
+/// Memoizes token counts by a hash of the counted parts of a request, since the same
+/// conversation is counted repeatedly while the user types. The least recently used
+/// count is evicted once `capacity` is reached.
+struct TokenCountCache {
+    capacity: usize,
+    /// Pairs of request hash and token count, from least to most recently used.
+    entries: VecDeque<(u64, u64)>,
+}
+
+impl TokenCountCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn key(request: &LanguageModelRequest) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        request.messages.hash(&mut hasher);
+        request.tools.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The count for `key`, which becomes the most recently used.
+    fn get(&mut self, key: u64) -> Option<u64> {
+        let entry = self.remove(key)?;
+        self.entries.push_back(entry);
+        Some(entry.1)
+    }
+
+    /// Records the count for `key`, replacing any recorded while it was being counted.
+    fn insert(&mut self, key: u64, count: u64) {
+        self.remove(key);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, count));
+    }
+
+    fn remove(&mut self, key: u64) -> Option<(u64, u64)> {
+        let ix = self
+            .entries
+            .iter()
+            .position(|(entry_key, _)| *entry_key == key)?;
+        self.entries.remove(ix)
+    }
+}
+
+/// Shares the response to a request with identical requests made shortly after it, such as
+/// when a prompt is accidentally submitted twice, so that only one of them is sent and billed.
+#[derive(Clone, Default)]
+struct RequestDeduplicator {
+    in_flight: Arc<Mutex<HashMap<u64, Arc<Mutex<SharedResponse>>>>>,
+}
+
+struct SharedResponse {
+    started_at: Instant,
+    /// The chunks received so far, replayed to requests that join late. Errors are kept as
+    /// messages, since they can't be cloned.
+    chunks: Vec<Result<mistral::StreamResponse, String>>,
+    subscribers: Vec<mpsc::UnboundedSender<Result<mistral::StreamResponse>>>,
+    /// The task that streams the response and publishes its chunks. Every subscriber shares
+    /// ownership of it, so the request is only cancelled once all of them have been dropped.
+    forwarder: Weak<Task<()>>,
+}
+
+impl SharedResponse {
+    fn publish(&mut self, chunk: Result<mistral::StreamResponse, String>) {
+        self.subscribers.retain(|subscriber| {
+            subscriber
+                .unbounded_send(chunk.clone().map_err(|error| anyhow!(error)))
+                .is_ok()
+        });
+        self.chunks.push(chunk);
+    }
+}
+
+/// Publishes the chunks of a response to the requests that subscribed to it, and stops sharing
+/// the response once dropped.
+struct ResponsePublisher {
+    key: u64,
+    response: Arc<Mutex<SharedResponse>>,
+    in_flight: Arc<Mutex<HashMap<u64, Arc<Mutex<SharedResponse>>>>>,
+    /// Whether the response ended, rather than being cancelled.
+    finished: bool,
+}
+
+impl ResponsePublisher {
+    fn publish(&self, chunk: &Result<mistral::StreamResponse>) {
+        let chunk = match chunk {
+            Ok(chunk) => Ok(chunk.clone()),
+            Err(error) => Err(format!("{error:#}")),
+        };
+        self.response.lock().publish(chunk);
+    }
+}
+
+impl Drop for ResponsePublisher {
+    fn drop(&mut self) {
+        // The subscribers would otherwise end as if the response were complete.
+        if !self.finished {
+            self.response.lock().publish(Err(format!(
+                "the {PROVIDER_NAME} request whose response was shared was cancelled"
+            )));
+        }
+        let mut in_flight = self.in_flight.lock();
+        // A later request may have replaced this one after the window passed.
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|response| Arc::ptr_eq(response, &self.response))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl RequestDeduplicator {
+    fn key(request: &mistral::Request) -> u64 {
+        /// Hashes the serialized request as it's written, rather than buffering a copy of
+        /// it first.
+        struct HashWriter(DefaultHasher);
+
+        impl io::Write for HashWriter {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.write(bytes);
+                Ok(bytes.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = HashWriter(DefaultHasher::new());
+        serde_json::to_writer(&mut writer, request).log_err();
+        writer.0.finish()
+    }
+
+    /// Joins the response to an identical request if one started within
+    /// [`DUPLICATE_REQUEST_WINDOW`] and is still streaming, or otherwise sends the request
+    /// with `send` and shares its response. The response is streamed on `executor` until it
+    /// ends or every request sharing it has been dropped, so that dropping the request that
+    /// sent it doesn't cut off those that joined.
+    fn stream<F>(
+        &self,
+        key: u64,
+        executor: &BackgroundExecutor,
+        send: impl FnOnce() -> F,
+    ) -> BoxFuture<
+        'static,
+        Result<BoxStream<'static, Result<mistral::StreamResponse>>, LanguageModelCompletionError>,
+    >
+    where
+        F: 'static
+            + Send
+            + Future<
+                Output = Result<
+                    BoxStream<'static, Result<mistral::StreamResponse>>,
+                    LanguageModelCompletionError,
+                >,
+            >,
+    {
+        let mut in_flight = self.in_flight.lock();
+        if let Some(response) = in_flight.get(&key) {
+            let mut response = response.lock();
+            // The forwarder is gone if the response was cancelled but hasn't been removed yet.
+            if response.started_at.elapsed() < DUPLICATE_REQUEST_WINDOW
+                && let Some(forwarder) = response.forwarder.upgrade()
+            {
+                log::debug!("Sharing the response to a duplicate {PROVIDER_NAME} request");
+                let (subscriber, chunks) = mpsc::unbounded();
+                for chunk in &response.chunks {
+                    subscriber
+                        .unbounded_send(chunk.clone().map_err(|error| anyhow!(error)))
+                        .ok();
+                }
+                response.subscribers.push(subscriber);
+                return futures::future::ready(Ok(subscribe(chunks, forwarder))).boxed();
+            }
+        }
+
+        let (subscriber, chunks) = mpsc::unbounded();
+        let response = Arc::new(Mutex::new(SharedResponse {
+            started_at: Instant::now(),
+            chunks: Vec::new(),
+            subscribers: vec![subscriber],
+            forwarder: Weak::new(),
+        }));
+        in_flight.insert(key, response.clone());
+        let mut publisher = ResponsePublisher {
+            key,
+            response: response.clone(),
+            in_flight: self.in_flight.clone(),
+            finished: false,
+        };
+
+        let future = send();
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let forwarder = Arc::new(executor.spawn(async move {
+            match future.await {
+                Ok(mut stream) => {
+                    connected_tx.send(Ok(())).ok();
+                    while let Some(chunk) = stream.next().await {
+                        publisher.publish(&chunk);
+                    }
+                }
+                Err(error) => {
+                    publisher.publish(&Err(anyhow!("{error}")));
+                    connected_tx.send(Err(error)).ok();
+                }
+            }
+            publisher.finished = true;
+        }));
+        // Set before the response can be joined, while `in_flight` is still locked.
+        response.lock().forwarder = Arc::downgrade(&forwarder);
+        drop(in_flight);
+
+        async move {
+            match connected_rx.await {
+                Ok(Ok(())) => Ok(subscribe(chunks, forwarder)),
+                Ok(Err(error)) => Err(error),
+                Err(oneshot::Canceled) => {
+                    Err(anyhow!("the {PROVIDER_NAME} request was cancelled").into())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Streams the `chunks` of a shared response, keeping the `forwarder` that publishes them
+/// alive until the stream is dropped.
+fn subscribe(
+    chunks: mpsc::UnboundedReceiver<Result<mistral::StreamResponse>>,
+    forwarder: Arc<Task<()>>,
+) -> BoxStream<'static, Result<mistral::StreamResponse>> {
+    futures::stream::unfold((chunks, forwarder), |(mut chunks, forwarder)| async move {
+        let chunk = chunks.next().await?;
+        Some((chunk, (chunks, forwarder)))
+    })
+    .boxed()
+}
+
+fn tool_definition(tool: LanguageModelRequestTool) -> mistral::ToolDefinition {
+    mistral::ToolDefinition::Function {
+        function: mistral::FunctionDefinition {
+            name: tool.name,
+            description: Some(tool.description),
+            parameters: Some(tool.input_schema),
+        },
+    }
+}
+
+pub fn into_mistral(
+    request: LanguageModelRequest,
+    model: &mistral::Model,
+    max_output_tokens: Option<u64>,
+    settings: &MistralRequestSettings,
+) -> mistral::Request {
+    let stream = settings.stream;
+    let metadata = if settings.send_metadata {
+        request_metadata(&request, settings)
+    } else {
+        BTreeMap::default()
+    };
+
+    let mut messages = Vec::new();
+    if let Some(prompt) = settings.default_system_prompt.clone() {
+        messages.push(mistral::RequestMessage::System { content: prompt });
+    }
+    let cache_prompt = model.supports_prompt_cache();
+    for message in &request.messages {
+        let message_start = messages.len();
+        match message.role {
+            Role::User => {
+                let mut message_content = mistral::MessageContent::empty();
+                for content in &message.content {
+                    match content {
+                        MessageContent::Text(text) => {
+                            message_content.push_part(mistral::MessagePart::Text {
+                                text: text.clone(),
+                                cache_control: None,
+                            });
+                        }
+                        MessageContent::Image(image_content) => {
+                            message_content.push_part(mistral::MessagePart::ImageUrl {
+                                image_url: image_url(image_content, settings),
+                                cache_control: None,
+                            });
+                        }
+                        MessageContent::Thinking { text, .. } => {
+                            message_content.push_part(mistral::MessagePart::Text {
+                                text: text.clone(),
+                                cache_control: None,
+                            });
+                        }
+                        MessageContent::RedactedThinking(_) => {}
+                        MessageContent::ToolUse(_) => {
+                            // Tool use is not supported in User messages for Mistral
+                        }
+                        MessageContent::ToolResult(tool_result) => {
+                            let mut tool_content = mistral::MessageContent::empty();
+                            tool_content.push_part(tool_result_part(
+                                &tool_result.content,
+                                model,
+                                settings,
+                            ));
+                            messages.push(mistral::RequestMessage::Tool {
+                                content: tool_content,
+                                tool_call_id: tool_result.tool_use_id.to_string(),
+                            });
+                        }
+                    }
+                }
+                if !matches!(message_content, mistral::MessageContent::Plain { ref content } if content.is_empty())
+                {
+                    messages.push(mistral::RequestMessage::User {
+                        content: message_content,
+                    });
+                }
+            }
+            Role::Assistant => {
+                for content in &message.content {
+                    match content {
+                        MessageContent::Thinking { .. }
+                            if !settings.include_reasoning_in_history => {}
+                        MessageContent::Text(text) | MessageContent::Thinking { text, .. } => {
+                            messages.push(mistral::RequestMessage::Assistant {
+                                content: Some(text.clone()),
+                                tool_calls: Vec::new(),
+                                prefix: false,
+                            });
+                        }
+                        MessageContent::RedactedThinking(_) => {}
+                        MessageContent::Image(_) => {}
+                        MessageContent::ToolUse(tool_use) => {
+                            let tool_call = mistral::ToolCall {
+                                id: tool_use.id.to_string(),
+                                content: mistral::ToolCallContent::Function {
+                                    function: mistral::FunctionContent {
+                                        name: tool_use.name.to_string(),
+                                        arguments: serde_json::to_string(&tool_use.input)
+                                            .unwrap_or_default(),
+                                    },
+                                },
+                            };
+
+                            if let Some(mistral::RequestMessage::Assistant { tool_calls, .. }) =
+                                messages.last_mut()
+                            {
+                                tool_calls.push(tool_call);
+                            } else {
+                                messages.push(mistral::RequestMessage::Assistant {
+                                    content: None,
+                                    tool_calls: vec![tool_call],
+                                    prefix: false,
+                                });
+                            }
+                        }
+                        MessageContent::ToolResult(_) => {
+                            // Tool results are not supported in Assistant messages
+                        }
+                    }
+                }
+            }
+            Role::System => {
+                for content in &message.content {
+                    match content {
+                        MessageContent::Text(text) | MessageContent::Thinking { text, .. } => {
+                            // Some models only honor a single system message, so merge
+                            // consecutive ones.
+                            if let Some(mistral::RequestMessage::System { content }) =
+                                messages.last_mut()
+                            {
+                                content.push('\n');
+                                content.push_str(text);
+                            } else {
+                                messages.push(mistral::RequestMessage::System {
+                                    content: text.clone(),
+                                });
+                            }
+                        }
+                        MessageContent::RedactedThinking(_) => {}
+                        MessageContent::Image(_)
+                        | MessageContent::ToolUse(_)
+                        | MessageContent::ToolResult(_) => {
+                            // Images and tools are not supported in System messages
+                        }
+                    }
+                }
+            }
+        }
+
+        // Only user and tool content can carry cache breakpoints.
+        if cache_prompt && message.cache && messages.len() > message_start {
+            if let Some(
+                mistral::RequestMessage::User { content }
+                | mistral::RequestMessage::Tool { content, .. },
+            ) = messages.last_mut()
+            {
+                content.set_cache_control(mistral::CacheControl::Ephemeral);
+            }
+        }
+    }
+
+    if !settings.file_ids.is_empty() {
+        let files = settings
+            .file_ids
+            .iter()
+            .map(|file_id| mistral::MessagePart::File {
+                file_id: file_id.clone(),
+            });
+        let last_user_content = messages.iter_mut().rev().find_map(|message| match message {
+            mistral::RequestMessage::User { content } => Some(content),
+            _ => None,
+        });
+        match last_user_content {
+            Some(content) => files.for_each(|file| content.push_part(file)),
+            None => messages.push(mistral::RequestMessage::User {
+                content: mistral::MessageContent::Multipart {
+                    content: files.collect(),
+                },
+            }),
+        }
+    }
+
+    let mut messages = fix_message_order(messages);
+    if request.continue_last_message {
+        join_trailing_assistant_messages(&mut messages);
+    }
+
+    // A trailing assistant message prefills the reply, which Mistral only accepts when the
+    // message is marked as a prefix.
+    if let Some(mistral::RequestMessage::Assistant {
+        content: Some(_),
+        tool_calls,
+        prefix,
+    }) = messages.last_mut()
+    {
+        *prefix = tool_calls.is_empty();
+    }
+
+    // The model's own stop sequences come first, so that they're kept if there are too many.
+    let mut stop = model.default_stop_sequences();
+    for sequence in request.stop {
+        if !stop.contains(&sequence) {
+            stop.push(sequence);
+        }
+    }
+    if stop.len() > mistral::MAX_STOP_SEQUENCES {
+        log::warn!(
+            "Mistral accepts at most {} stop sequences, ignoring {} of them",
+            mistral::MAX_STOP_SEQUENCES,
+            stop.len() - mistral::MAX_STOP_SEQUENCES
+        );
+        stop.truncate(mistral::MAX_STOP_SEQUENCES);
+    }
+
+    let response_format = request.response_schema.map(|response_schema| {
+        if model.supports_structured_output() {
+            mistral::ResponseFormat::JsonSchema {
+                json_schema: mistral::JsonSchemaFormat {
+                    name: response_schema.name,
+                    schema: response_schema.schema,
+                    strict: true,
+                },
+            }
+        } else {
+            mistral::ResponseFormat::JsonObject
+        }
+    });
+
+    let prompt_mode = (request.thinking_allowed && model.supports_thinking())
+        .then_some(mistral::PromptMode::Reasoning);
+
+    // Mistral rejects requests with tools for models that can't call them, so the request is
+    // sent without them rather than failing outright.
+    let supports_tools = model.supports_tools();
+    let tools = if supports_tools {
+        request.tools
+    } else {
+        if !request.tools.is_empty() {
+            log::warn!(
+                "Mistral model {} doesn't support tools, ignoring {} of them",
+                model.id(),
+                request.tools.len()
+            );
+        }
+        Vec::new()
+    };
+
+    // An explicit temperature takes precedence over the one configured for the intent.
+    let temperature = request.temperature.or_else(|| {
+        let intent = intent_name(request.intent?)?;
+        settings.temperature_by_intent.get(&intent).copied()
+    });
+    let temperature = match (temperature, model.max_temperature()) {
+        (Some(temperature), Some(max_temperature)) if temperature > max_temperature => {
+            log::debug!(
+                "clamping temperature {temperature} to {max_temperature} for Mistral model {}",
+                model.id()
+            );
+            Some(max_temperature)
+        }
+        (temperature, _) => temperature,
+    };
+
+    let mut mistral_request = mistral::Request {
+        model: model.id().to_string(),
+        messages,
+        stream,
+        max_tokens: max_output_tokens,
+        min_tokens: settings.min_tokens,
+        temperature,
+        top_p: request.top_p,
+        frequency_penalty: request.frequency_penalty,
+        presence_penalty: request.presence_penalty,
+        n: settings.n,
+        stop,
+        response_format,
+        // Forbidding tools is always honored, but choosing between tools only makes sense
+        // when there are some.
+        tool_choice: match request.tool_choice {
+            _ if !supports_tools => None,
+            Some(LanguageModelToolChoice::None) => Some(mistral::ToolChoice::None),
+            _ if tools.is_empty() => None,
+            Some(LanguageModelToolChoice::Any) => Some(mistral::ToolChoice::Any),
+            Some(LanguageModelToolChoice::Auto) | None => Some(mistral::ToolChoice::Auto),
+        },
+        parallel_tool_calls: if !tools.is_empty() {
+            Some(settings.parallel_tool_calls)
+        } else {
+            None
+        },
+        tools: tools.into_iter().map(tool_definition).collect(),
+        safe_prompt: settings.safe_prompt.then_some(true),
+        random_seed: settings.random_seed,
+        prompt_mode,
+        prediction: settings
+            .prediction
+            .clone()
+            .filter(|_| model.supports_prediction())
+            .map(|content| mistral::Prediction::Content { content }),
+        agent_id: settings.agent_id.clone(),
+        metadata,
+    };
+    if settings.openai_compat {
+        make_openai_compatible(&mut mistral_request);
+    }
+    mistral_request
+}
+
+/// Identifies what `request` is for, without any of its content: the thread and prompt it
+/// belongs to, its intent, and any tags in `settings`.
+fn request_metadata(
+    request: &LanguageModelRequest,
+    settings: &MistralRequestSettings,
+) -> BTreeMap<String, String> {
+    let mut metadata = settings.metadata.clone();
+    if let Some(thread_id) = &request.thread_id {
+        metadata.insert("thread_id".into(), thread_id.clone());
+    }
+    if let Some(prompt_id) = &request.prompt_id {
+        metadata.insert("prompt_id".into(), prompt_id.clone());
+    }
+    if let Some(intent) = request.intent.and_then(intent_name) {
+        metadata.insert("intent".into(), intent);
+    }
+    metadata
+}
+
+/// The name of `intent` as it's written in settings, such as `inline_assist`.
+fn intent_name(intent: CompletionIntent) -> Option<String> {
+    serde_json::to_value(intent)
+        .ok()?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Joins the assistant messages that the last request message was split into, such as one
+/// per text part, so that the model continues that message as a whole.
+///
+/// Any trailing assistant message is sent as a prefix that the reply starts with, but on a
+/// fresh turn only the last part is: the earlier ones stay separate messages that read as
+/// previous turns. Messages with tool calls end the joined message, since they can't be
+/// part of a prefix.
+fn join_trailing_assistant_messages(messages: &mut Vec<mistral::RequestMessage>) {
+    let mut parts = Vec::new();
+    while let Some(mistral::RequestMessage::Assistant {
+        content: Some(content),
+        tool_calls,
+        ..
+    }) = messages.last_mut()
+        && tool_calls.is_empty()
+    {
+        parts.push(std::mem::take(content));
+        messages.pop();
+    }
+    if parts.is_empty() {
+        log::warn!(
+            "Can't continue a {PROVIDER_NAME} request that doesn't end with an assistant message"
+        );
+        return;
+    }
+    parts.reverse();
+    messages.push(mistral::RequestMessage::Assistant {
+        content: Some(parts.concat()),
+        tool_calls: Vec::new(),
+        prefix: true,
+    });
+}
+
+/// Rewrites `request` using only what OpenAI-compatible APIs accept: string content, no
+/// prefilled replies, and OpenAI's tool choices. Images and files can't be sent as strings,
+/// so they are dropped.
+fn make_openai_compatible(request: &mut mistral::Request) {
+    let mut dropped_parts = 0;
+    for message in &mut request.messages {
+        match message {
+            mistral::RequestMessage::User { content }
+            | mistral::RequestMessage::Tool { content, .. } => {
+                dropped_parts += content.flatten();
+            }
+            mistral::RequestMessage::Assistant { prefix, .. } => *prefix = false,
+            mistral::RequestMessage::System { .. } => {}
+        }
+    }
+    if dropped_parts > 0 {
+        log::warn!(
+            "Dropped {dropped_parts} images and files from a {PROVIDER_NAME} request in \
+            OpenAI-compatible mode"
+        );
+    }
+    if let Some(tool_choice @ mistral::ToolChoice::Any) = &mut request.tool_choice {
+        *tool_choice = mistral::ToolChoice::Required;
+    }
+}
+
+fn tool_result_part(
+    content: &LanguageModelToolResultContent,
+    model: &mistral::Model,
+    settings: &MistralRequestSettings,
+) -> mistral::MessagePart {
+    match content {
+        LanguageModelToolResultContent::Text(text) => mistral::MessagePart::Text {
+            text: match settings.max_tool_result_tokens {
+                Some(max_tokens) => truncate_tool_result(text, max_tokens),
+                None => text.to_string(),
+            },
+            cache_control: None,
+        },
+        LanguageModelToolResultContent::Image(image) if model.supports_images() => {
+            mistral::MessagePart::ImageUrl {
+                image_url: image_url(image, settings),
+                cache_control: None,
+            }
+        }
+        LanguageModelToolResultContent::Image(_) => mistral::MessagePart::Text {
+            text: "[Tool responded with an image, but this Mistral model doesn't support images]"
+                .to_string(),
+            cache_control: None,
+        },
+    }
+}
+
+/// Encodes `image` as a data URL with the configured detail.
+fn image_url(image: &LanguageModelImage, settings: &MistralRequestSettings) -> mistral::ImageUrl {
+    mistral::ImageUrl {
+        url: image.to_base64_url(),
+        detail: settings.image_detail,
+    }
+}
+
+/// Scales down the images in `request` that are wider or taller than `max_dimension`. Images
+/// that can't be scaled are sent at full size. Scaling decodes and encodes each image, so
+/// it's done in the background before converting the request.
+fn scale_down_images(request: &mut LanguageModelRequest, max_dimension: u32) {
+    for content in request
+        .messages
+        .iter_mut()
+        .flat_map(|message| &mut message.content)
+    {
+        let image = match content {
+            MessageContent::Image(image)
+            | MessageContent::ToolResult(LanguageModelToolResult {
+                content: LanguageModelToolResultContent::Image(image),
+                ..
+            }) => image,
+            _ => continue,
+        };
+        match image.scale_down(max_dimension) {
+            Ok(scaled) => *image = scaled,
+            Err(error) => {
+                log::warn!("Failed to scale down image for {PROVIDER_NAME}: {error:#}")
+            }
+        }
+    }
+}
+
+/// Truncates `text` to roughly `max_tokens` tokens, at [`CHARS_PER_TOKEN`], and marks it as
+/// truncated. The cut is moved back to the end of a word where possible, so that the
+/// last token isn't split.
+fn truncate_tool_result(text: &str, max_tokens: u64) -> String {
+    let max_chars = usize::try_from(max_tokens)
+        .unwrap_or(usize::MAX)
+        .saturating_mul(CHARS_PER_TOKEN);
+    let Some((end, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+    let end = text[..end]
+        .rfind(char::is_whitespace)
+        .filter(|&word_end| word_end > 0)
+        .unwrap_or(end);
+    log::warn!(
+        "Truncated a {} byte tool result to {max_tokens} tokens for {PROVIDER_NAME}",
+        text.len()
+    );
+    format!("{}\n[truncated]", text[..end].trim_end())
+}
+
+/// Repairs the order of `messages` to satisfy Mistral's rules for roles, which reject
+/// conversations that other providers accept:
+///
+/// - An assistant message that calls tools has no content, so any content is split into a
+///   separate assistant message before it.
+/// - Each tool call is answered by a tool message directly after the assistant message that
+///   made it. Calls without a result are dropped, and results without a call are sent as
+///   user messages. A call with several results, such as text followed by an image, is
+///   answered by one tool message combining them in order.
+/// - A tool message is followed by an assistant message, so a placeholder is inserted
+///   between a tool message and a user message.
+fn fix_message_order(messages: Vec<mistral::RequestMessage>) -> Vec<mistral::RequestMessage> {
+    let tool_call_ids = messages
+        .iter()
+        .flat_map(|message| match message {
+            mistral::RequestMessage::Assistant { tool_calls, .. } => tool_calls.as_slice(),
+            _ => &[],
+        })
+        .map(|tool_call| tool_call.id.clone())
+        .collect::<HashSet<_>>();
+    let mut tool_results = HashMap::default();
+    let mut remaining_messages = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message {
+            mistral::RequestMessage::Tool {
+                content,
+                tool_call_id,
+            } => {
+                if !tool_call_ids.contains(&tool_call_id) {
+                    remaining_messages.push(mistral::RequestMessage::User { content });
+                } else if let Some(result) = tool_results.get_mut(&tool_call_id) {
+                    result.append(content);
+                } else {
+                    tool_results.insert(tool_call_id, content);
+                }
+            }
+            message => remaining_messages.push(message),
+        }
+    }
+
+    let mut fixed_messages = Vec::with_capacity(remaining_messages.len());
+    for message in remaining_messages {
+        let (content, mut tool_calls, prefix) = match message {
+            mistral::RequestMessage::Assistant {
+                content,
+                tool_calls,
+                prefix,
+            } => (content, tool_calls, prefix),
+            message => {
+                if matches!(message, mistral::RequestMessage::User { .. })
+                    && matches!(
+                        fixed_messages.last(),
+                        Some(mistral::RequestMessage::Tool { .. })
+                    )
+                {
+                    fixed_messages.push(mistral::RequestMessage::Assistant {
+                        content: Some(" ".to_string()),
+                        tool_calls: Vec::new(),
+                        prefix: false,
+                    });
+                }
+                fixed_messages.push(message);
+                continue;
+            }
+        };
+
+        tool_calls.retain(|tool_call| tool_results.contains_key(&tool_call.id));
+        let content = content.filter(|content| !content.is_empty());
+        if tool_calls.is_empty() {
+            if content.is_some() {
+                fixed_messages.push(mistral::RequestMessage::Assistant {
+                    content,
+                    tool_calls,
+                    prefix,
+                });
+            }
+            continue;
+        }
+
+        if content.is_some() {
+            fixed_messages.push(mistral::RequestMessage::Assistant {
+                content,
+                tool_calls: Vec::new(),
+                prefix: false,
+            });
+        }
+        let results = tool_calls
+            .iter()
+            .filter_map(|tool_call| {
+                let content = tool_results.remove(&tool_call.id)?;
+                Some(mistral::RequestMessage::Tool {
+                    content,
+                    tool_call_id: tool_call.id.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        fixed_messages.push(mistral::RequestMessage::Assistant {
+            content: None,
+            tool_calls,
+            prefix: false,
+        });
+        fixed_messages.extend(results);
+    }
+    fixed_messages
+}
+
+/// A complete assistant message, as yielded by [`MistralEventMapper::collect_stream`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MistralMessage {
+    pub message_id: Option<String>,
+    pub text: String,
+    pub thinking: String,
+    pub tool_uses: Vec<LanguageModelToolUse>,
+    pub usage: Option<TokenUsage>,
+    pub stop_reason: Option<StopReason>,
+}
+
+pub struct MistralEventMapper {
+    choices: BTreeMap<u32, ChoiceState>,
+    supports_thinking: bool,
+    lenient_json: bool,
+    raw_response_logger: Option<RawResponseLogger>,
+    /// The characters of output streamed so far, to estimate usage if the stream fails
+    /// before the final chunk reports it.
+    output_chars: usize,
+    usage_reported: bool,
+}
+
+/// Logs the raw chunks of a response for debugging streaming issues. Secrets such as the
+/// API key are redacted in case a chunk echoes them back.
+struct RawResponseLogger {
+    secrets: Vec<String>,
+}
+
+impl RawResponseLogger {
+    fn log(&self, event: &mistral::StreamResponse) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        match serde_json::to_string(event) {
+            Ok(json) => log::debug!("Mistral response chunk: {}", self.redact(json)),
+            Err(error) => log::debug!("Failed to serialize Mistral response chunk: {error}"),
+        }
+    }
+
+    fn redact(&self, mut text: String) -> String {
+        for secret in &self.secrets {
+            if !secret.is_empty() {
+                text = text.replace(secret.as_str(), "[REDACTED]");
+            }
+        }
+        text
+    }
+}
+
+/// The state of a single choice of a streamed completion. Requests with `n` set stream
+/// several choices at once, interleaved in the same chunks.
+#[derive(Default)]
+struct ChoiceState {
+    tool_calls_by_index: BTreeMap<usize, RawToolCall>,
+    message_started: bool,
+    in_thinking: bool,
+}
+
+impl MistralEventMapper {
+    pub fn new(model: &mistral::Model) -> Self {
+        Self {
+            choices: BTreeMap::default(),
+            supports_thinking: model.supports_thinking(),
+            lenient_json: false,
+            raw_response_logger: None,
+            output_chars: 0,
+            usage_reported: false,
+        }
+    }
+
+    /// Repairs trailing commas and unescaped control characters in the JSON of tool call
+    /// arguments before giving up on parsing them.
+    pub fn lenient_json(mut self, lenient_json: bool) -> Self {
+        self.lenient_json = lenient_json;
+        self
+    }
+
+    /// Logs every chunk passed to [`Self::map_stream`] at debug level, with `secrets` redacted.
+    pub fn log_raw_responses(mut self, secrets: Vec<String>) -> Self {
+        self.raw_response_logger = Some(RawResponseLogger { secrets });
+        self
+    }
+
+    pub fn map_stream(
+        self,
+        events: Pin<Box<dyn Send + Stream<Item = Result<StreamResponse>>>>,
+    ) -> impl Stream<Item = Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>
+    {
+        self.map_choice_stream(events).map(|(_, event)| event)
+    }
+
+    /// Like [`Self::map_stream`], but pairs each event with the index of its choice. See
+    /// [`Self::map_choices`].
+    pub fn map_choice_stream(
+        mut self,
+        events: Pin<Box<dyn Send + Stream<Item = Result<StreamResponse>>>>,
+    ) -> impl Stream<
+        Item = (
+            u32,
+            Result<LanguageModelCompletionEvent, LanguageModelCompletionError>,
+        ),
+    > {
+        events.flat_map(move |event| {
+            futures::stream::iter(match event {
+                Ok(event) => {
+                    if let Some(logger) = &self.raw_response_logger {
+                        logger.log(&event);
+                    }
+                    self.map_choices(event)
+                }
+                Err(error) => {
+                    let mut events = Vec::new();
+                    if let Some(usage) = self.partial_usage() {
+                        events.push((0, Ok(LanguageModelCompletionEvent::UsageUpdate(usage))));
+                    }
+                    events.push((0, Err(into_stream_error(error))));
+                    events
+                }
+            })
+        })
+    }
+
+    /// Like [`Self::map_stream`], but buffers the events of each assistant message and
+    /// yields the whole message once it stops, for callers that don't display responses
+    /// as they stream in.
+    pub fn collect_stream(
+        self,
+        events: Pin<Box<dyn Send + Stream<Item = Result<StreamResponse>>>>,
+    ) -> impl Stream<Item = Result<MistralMessage, LanguageModelCompletionError>> {
+        let mut message = MistralMessage::default();
+        self.map_stream(events).filter_map(move |event| {
+            futures::future::ready(match event {
+                Ok(LanguageModelCompletionEvent::StartMessage { message_id }) => {
+                    message.message_id = Some(message_id);
+                    None
+                }
+                Ok(LanguageModelCompletionEvent::Text(text)) => {
+                    message.text.push_str(&text);
+                    None
+                }
+                Ok(LanguageModelCompletionEvent::Thinking { text, .. }) => {
+                    message.thinking.push_str(&text);
+                    None
+                }
+                // Incomplete tool uses are only previews of arguments that are still streaming.
+                Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => {
+                    if tool_use.is_input_complete {
+                        message.tool_uses.push(tool_use);
+                    }
+                    None
+                }
+                Ok(LanguageModelCompletionEvent::ToolUseJsonParseError {
+                    tool_name,
+                    json_parse_error,
+                    ..
+                }) => Some(Err(LanguageModelCompletionError::from(anyhow!(
+                    "Failed to parse the arguments of a {tool_name} tool call: {json_parse_error}"
+                )))),
+                Ok(LanguageModelCompletionEvent::UsageUpdate(usage)) => {
+                    message.usage = Some(usage);
+                    None
+                }
+                Ok(LanguageModelCompletionEvent::Stop(stop_reason)) => {
+                    message.stop_reason = Some(stop_reason);
+                    Some(Ok(std::mem::take(&mut message)))
+                }
+                Ok(
+                    LanguageModelCompletionEvent::StatusUpdate(_)
+                    | LanguageModelCompletionEvent::RedactedThinking { .. }
+                    | LanguageModelCompletionEvent::ToolUseIncomplete { .. },
+                ) => None,
+                Err(error) => Some(Err(error)),
+            })
+        })
+    }
+
+    /// Maps the events of every choice in `event`. Use [`Self::map_choices`] to tell the
+    /// events of different choices apart when more than one completion was requested.
+    pub fn map_event(
+        &mut self,
+        event: mistral::StreamResponse,
+    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
+        self.map_choices(event)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    /// Maps the events of every choice in `event`, paired with the index of their choice.
+    /// Usage covers all choices, so it is reported with the first choice of the chunk.
+    pub fn map_choices(
+        &mut self,
+        event: mistral::StreamResponse,
+    ) -> Vec<(
+        u32,
+        Result<LanguageModelCompletionEvent, LanguageModelCompletionError>,
+    )> {
+        let Some(first_index) = event.choices.first().map(|choice| choice.index) else {
+            // The final chunk can report usage without any choices.
+            if let Some(usage) = &event.usage {
+                self.usage_reported = true;
+                return vec![(
+                    0,
+                    Ok(LanguageModelCompletionEvent::UsageUpdate(token_usage(
+                        usage,
+                    ))),
+                )];
+            }
+            return vec![(
+                0,
+                Err(LanguageModelCompletionError::from(anyhow!(
+                    "Response contained no choices"
+                ))),
+            )];
+        };
+
+        self.usage_reported |= event.usage.is_some();
+        let mut events = Vec::new();
+        for choice in &event.choices {
+            let text = [&choice.delta.content, &choice.delta.reasoning_content]
+                .into_iter()
+                .flatten();
+            let arguments = choice
+                .delta
+                .tool_calls
+                .iter()
+                .flatten()
+                .filter_map(|tool_call| tool_call.function.as_ref()?.arguments.as_ref());
+            self.output_chars += text
+                .chain(arguments)
+                .map(|text| text.chars().count())
+                .sum::<usize>();
+            let usage = event.usage.as_ref().filter(|_| choice.index == first_index);
+            let state = self.choices.entry(choice.index).or_default();
+            events.extend(
+                state
+                    .map_choice(
+                        &event.id,
+                        choice,
+                        usage,
+                        self.supports_thinking,
+                        self.lenient_json,
+                    )
+                    .into_iter()
+                    .map(|event| (choice.index, event)),
+            );
+        }
+        events
+    }
+
+    /// Estimates the usage of a response that ended before reporting it with
+    /// [`estimate_tokens`]. Input tokens aren't known, so only output tokens are counted.
+    fn partial_usage(&self) -> Option<TokenUsage> {
+        if self.usage_reported || self.output_chars == 0 {
+            return None;
+        }
+        Some(TokenUsage {
+            output_tokens: estimate_tokens(self.output_chars),
+            ..Default::default()
+        })
+    }
+}
+
+/// Copies `events`, such as those from [`MistralEventMapper::map_stream`], to `count` streams
+/// that each yield every event, so that a completion can be displayed and parsed at once.
+///
+/// The returned future forwards the events and has to be spawned alongside the streams. Each
+/// stream buffers up to `buffer` events, after which forwarding waits for it to catch up, so a
+/// slow consumer holds the others back by that many events at most instead of buffering
+/// without bound. Streams that are dropped stop receiving events, and forwarding stops once
+/// they all are. Errors can't be cloned, so every stream but the first receives them as
+/// messages.
+pub fn broadcast_stream(
+    events: impl Stream<Item = Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>
+    + Send
+    + 'static,
+    count: usize,
+    buffer: usize,
+) -> (
+    BoxFuture<'static, ()>,
+    Vec<BoxStream<'static, Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>>,
+) {
+    let (mut senders, receivers): (Vec<_>, Vec<_>) =
+        (0..count).map(|_| mpsc::channel(buffer)).unzip();
+    let forward = async move {
+        let mut events = pin!(events);
+        while let Some(event) = events.next().await {
+            let copies: Vec<_> = match event {
+                Ok(event) => senders.iter().map(|_| Ok(event.clone())).collect(),
+                Err(error) => {
+                    let message = format!("{error:#}");
+                    std::iter::once(Err(error))
+                        .chain((1..senders.len()).map(|_| {
+                            Err(LanguageModelCompletionError::from(anyhow!(message.clone())))
+                        }))
+                        .collect()
+                }
+            };
+            let mut open_senders = Vec::with_capacity(senders.len());
+            for (mut sender, copy) in senders.into_iter().zip(copies) {
+                if sender.send(copy).await.is_ok() {
+                    open_senders.push(sender);
+                }
+            }
+            senders = open_senders;
+            if senders.is_empty() {
+                break;
+            }
+        }
+    };
+    (
+        forward.boxed(),
+        receivers.into_iter().map(StreamExt::boxed).collect(),
+    )
+}
+
+impl ChoiceState {
+    fn map_choice(
+        &mut self,
+        message_id: &str,
+        choice: &mistral::StreamChoice,
+        usage: Option<&mistral::Usage>,
+        supports_thinking: bool,
+        lenient_json: bool,
+    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
+        let mut events = Vec::new();
+        if !self.message_started && !message_id.is_empty() {
+            self.message_started = true;
+            events.push(Ok(LanguageModelCompletionEvent::StartMessage {
+                message_id: message_id.to_string(),
+            }));
+        }
+
+        if supports_thinking {
+            if let Some(reasoning) = choice.delta.reasoning_content.clone() {
+                events.push(Ok(LanguageModelCompletionEvent::Thinking {
+                    text: reasoning,
+                    signature: None,
+                }));
+            }
+        }
+
+        // Chunks that only carry a role or a finish reason may have empty content. Whitespace
+        // is still meaningful, so only exactly empty content is skipped.
+        if let Some(content) = choice.delta.content.as_deref().filter(|c| !c.is_empty()) {
+            if supports_thinking {
+                events.extend(self.map_content(content).into_iter().map(Ok));
+            } else {
+                events.push(Ok(LanguageModelCompletionEvent::Text(content.to_string())));
+            }
+        }
+
+        if let Some(tool_calls) = choice.delta.tool_calls.as_ref() {
+            for tool_call in tool_calls {
+                // Chunks are stitched together by index, since the id may only arrive after
+                // some of the arguments. Later chunks may repeat the id and name, or send them
+                // empty, which must not erase them.
+                let entry = self.tool_calls_by_index.entry(tool_call.index).or_default();
+
+                if let Some(tool_id) = tool_call.id.clone().filter(|id| !id.is_empty()) {
+                    entry.id = tool_id;
+                }
+
+                if let Some(function) = tool_call.function.as_ref() {
+                    if let Some(name) = function.name.clone().filter(|name| !name.is_empty()) {
+                        entry.name = name;
+                    }
+
+                    if let Some(arguments) = function.arguments.as_deref() {
+                        entry.arguments.push_str(arguments);
+
+                        // Close any unclosed delimiters of the partial arguments so that the
+                        // UI can show them as they stream in.
+                        if !entry.id.is_empty() && !entry.name.is_empty() {
+                            if let Ok(input) = serde_json::Value::from_str(
+                                &partial_json_fixer::fix_json(&entry.arguments),
+                            ) {
+                                events.push(Ok(LanguageModelCompletionEvent::ToolUse(
+                                    LanguageModelToolUse {
+                                        id: entry.id.clone().into(),
+                                        name: entry.name.clone().into(),
+                                        is_input_complete: false,
+                                        input,
+                                        raw_input: entry.arguments.clone(),
+                                    },
+                                )));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(usage) = usage {
+            events.push(Ok(LanguageModelCompletionEvent::UsageUpdate(token_usage(
+                usage,
+            ))));
+        }
+
+        if let Some(finish_reason) = choice.finish_reason.as_deref() {
+            match finish_reason {
+                "stop" => {
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
+                }
+                "tool_calls" => {
+                    events.extend(self.process_tool_calls(lenient_json));
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)));
+                }
+                "length" | "model_length" => {
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(
+                        StopReason::MaxTokens,
+                    )));
+                }
+                "content_filter" => {
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::Refusal)));
+                }
+                unexpected => {
+                    log::error!("Unexpected Mistral stop_reason: {unexpected:?}");
+                    events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
+                }
+            }
+        }
+
+        events
+    }
+
+    /// Splits `content` on `<think>` delimiters, which reasoning models may use to
+    /// mark their reasoning inline instead of streaming it in `reasoning_content`.
+    fn map_content(&mut self, mut content: &str) -> Vec<LanguageModelCompletionEvent> {
+        let mut events = Vec::new();
+        while !content.is_empty() {
+            let delimiter = if self.in_thinking {
+                "</think>"
+            } else {
+                "<think>"
+            };
+            let (segment, rest) = match content.split_once(delimiter) {
+                Some((segment, rest)) => (segment, Some(rest)),
+                None => (content, None),
+            };
+
+            if !segment.is_empty() {
+                events.push(if self.in_thinking {
+                    LanguageModelCompletionEvent::Thinking {
+                        text: segment.to_string(),
+                        signature: None,
+                    }
+                } else {
+                    LanguageModelCompletionEvent::Text(segment.to_string())
+                });
+            }
+
+            let Some(rest) = rest else {
+                break;
+            };
+            self.in_thinking = !self.in_thinking;
+            content = rest;
+        }
+        events
+    }
+
+    fn process_tool_calls(
+        &mut self,
+        lenient_json: bool,
+    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
+        let mut results = Vec::new();
+
+        // Emit the tool calls in the order the model requested them.
+        for tool_call in std::mem::take(&mut self.tool_calls_by_index).into_values() {
+            // An incomplete call can't be run, but the other calls in the response can.
+            if tool_call.id.is_empty() || tool_call.name.is_empty() {
+                log::warn!("Received an incomplete {PROVIDER_NAME} tool call: missing id or name");
+                results.push(Ok(LanguageModelCompletionEvent::ToolUseIncomplete {
+                    id: (!tool_call.id.is_empty()).then(|| tool_call.id.into()),
+                    tool_name: (!tool_call.name.is_empty()).then(|| tool_call.name.into()),
+                    raw_input: tool_call.arguments.into(),
+                }));
+                continue;
+            }
+
+            let input = serde_json::Value::from_str(&tool_call.arguments).or_else(|error| {
+                if !lenient_json {
+                    return Err(error);
+                }
+                let input = serde_json::Value::from_str(&repair_json(&tool_call.arguments));
+                if input.is_ok() {
+                    log::debug!("Repaired malformed arguments of {PROVIDER_NAME} tool call");
+                }
+                // Report the error in the original arguments, which is the one the model made.
+                input.map_err(|_| error)
+            });
+            match input {
+                Ok(input) => results.push(Ok(LanguageModelCompletionEvent::ToolUse(
+                    LanguageModelToolUse {
+                        id: tool_call.id.into(),
+                        name: tool_call.name.into(),
+                        is_input_complete: true,
+                        input,
+                        raw_input: tool_call.arguments,
+                    },
+                ))),
+                Err(error) => {
+                    results.push(Ok(LanguageModelCompletionEvent::ToolUseJsonParseError {
+                        id: tool_call.id.into(),
+                        tool_name: tool_call.name.into(),
+                        raw_input: tool_call.arguments.into(),
+                        json_parse_error: error.to_string(),
+                    }))
+                }
+            }
+        }
+
+        results
+    }
+}
+
+/// Fixes mistakes that models commonly make when writing JSON: commas after the last
+/// element of an object or array, and newlines or tabs that aren't escaped in strings.
+fn repair_json(json: &str) -> String {
+    let mut repaired = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in json.chars() {
+        if in_string {
+            match c {
+                _ if escaped => {
+                    escaped = false;
+                    repaired.push(c);
+                }
+                '\\' => {
+                    escaped = true;
+                    repaired.push(c);
+                }
+                '"' => {
+                    in_string = false;
+                    repaired.push(c);
+                }
+                '\n' => repaired.push_str("\\n"),
+                '\r' => repaired.push_str("\\r"),
+                '\t' => repaired.push_str("\\t"),
+                _ => repaired.push(c),
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '}' | ']' => {
+                    let len = repaired.trim_end().len();
+                    if repaired[..len].ends_with(',') {
+                        repaired.truncate(len - 1);
+                    }
+                }
+                _ => {}
+            }
+            repaired.push(c);
+        }
+    }
+    repaired
+}
+
+/// Mistral counts cached tokens as part of the prompt, whereas [`TokenUsage`] keeps them
+/// separate from the uncached input tokens.
+fn token_usage(usage: &mistral::Usage) -> TokenUsage {
+    let cached_tokens = usage
+        .prompt_tokens_details
+        .as_ref()
+        .map_or(0, |details| details.cached_tokens);
+    TokenUsage {
+        input_tokens: usage.prompt_tokens.saturating_sub(cached_tokens),
+        output_tokens: usage.completion_tokens,
+        cache_creation_input_tokens: 0,
+        cache_read_input_tokens: cached_tokens,
+    }
+}
+
+/// Maps the chunks of a fill-in-the-middle completion, which only ever carry text.
+pub struct MistralFimEventMapper;
+
+impl MistralFimEventMapper {
+    pub fn map_stream(
+        events: Pin<Box<dyn Send + Stream<Item = Result<StreamResponse>>>>,
+    ) -> impl Stream<Item = Result<LanguageModelCompletionEvent, LanguageModelCompletionError>>
+    {
+        events.flat_map(|event| {
+            futures::stream::iter(match event {
+                Ok(event) => Self::map_event(event),
+                Err(error) => vec![Err(into_stream_error(error))],
+            })
+        })
+    }
+
+    pub fn map_event(
+        event: mistral::StreamResponse,
+    ) -> Vec<Result<LanguageModelCompletionEvent, LanguageModelCompletionError>> {
+        let mut events = Vec::new();
+        let choice = event.choices.first();
+
+        if let Some(content) = choice.and_then(|choice| choice.delta.content.clone()) {
+            events.push(Ok(LanguageModelCompletionEvent::Text(content)));
+        }
+
+        if let Some(usage) = event.usage.as_ref() {
+            events.push(Ok(LanguageModelCompletionEvent::UsageUpdate(token_usage(
+                usage,
+            ))));
+        }
+
+        match choice.and_then(|choice| choice.finish_reason.as_deref()) {
+            Some("length") => {
+                events.push(Ok(LanguageModelCompletionEvent::Stop(
+                    StopReason::MaxTokens,
+                )));
+            }
+            Some(_) => {
+                events.push(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
+            }
+            None => {}
+        }
+
+        events
+    }
+}
+
+#[derive(Default)]
+struct RawToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+struct ConfigurationView {
+    api_key_editor: Entity<Editor>,
+    api_url_editor: Entity<Editor>,
+    api_url_error: Option<SharedString>,
+    state: gpui::Entity<State>,
+    load_credentials_task: Option<Task<()>>,
+    validate_api_key_task: Option<Task<()>>,
+    validation_error: Option<HealthCheckError>,
+}
+
+/// Why Mistral couldn't be used with the configured API URL and key. Its message is meant
+/// to be shown to the user.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum HealthCheckError {
+    /// No API key has been set.
+    #[error("No Mistral API key is set.")]
+    NoApiKey,
+    /// Mistral rejected the key.
+    #[error("Mistral rejected this API key. Check that it was copied correctly.")]
+    Unauthorized,
+    /// Mistral responded with some other error, so the key may or may not be valid.
+    #[error("Mistral returned an error while validating the API key: {0}")]
+    Api(StatusCode),
+    /// Mistral couldn't be reached, so the key may or may not be valid.
+    #[error("Couldn't reach Mistral to validate the API key: {0}")]
+    Network(String),
+}
+
+impl HealthCheckError {
+    /// Whether Mistral rejected the key, rather than failing in a way that says nothing
+    /// about whether it's valid.
+    pub fn rejects_api_key(&self) -> bool {
+        matches!(self, Self::Unauthorized)
+    }
+}
+
+/// Parses a user-provided API base URL, which must use an http(s) scheme.
+fn parse_api_url(api_url: &str) -> Result<String> {
+    let api_url = api_url.trim().trim_end_matches('/');
+    let url = Url::parse(api_url).context("Invalid API URL")?;
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https"),
+        "API URL must start with http:// or https://"
+    );
+    Ok(api_url.to_string())
+}
+
+/// Issues a lightweight authenticated request to check that Mistral is reachable and
+/// accepts the API key of `connection`, e.g. so that a mistyped key is reported before it is
+/// saved.
+async fn check_health(
+    client: &dyn HttpClient,
+    connection: &mistral::Connection,
+) -> Result<(), HealthCheckError> {
+    let Err(error) = mistral::list_models(client, connection).await else {
+        return Ok(());
+    };
+    match error.downcast_ref::<mistral::ApiError>() {
+        Some(error)
+            if matches!(
+                error.status_code,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) =>
+        {
+            Err(HealthCheckError::Unauthorized)
+        }
+        Some(error) => Err(HealthCheckError::Api(error.status_code)),
+        None => Err(HealthCheckError::Network(format!("{error:#}"))),
+    }
+}
+
+impl ConfigurationView {
+    fn new(state: gpui::Entity<State>, window: &mut Window, cx: &mut Context<Self>) -> Self {
+        let api_key_editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text("0aBCDEFGhIjKLmNOpqrSTUVwxyzabCDE1f2", cx);
+            editor
+        });
+        let api_url_editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text(mistral::MISTRAL_API_URL, cx);
+            editor.set_text(
+                AllLanguageModelSettings::get_global(cx)
+                    .mistral
+                    .api_url
+                    .clone(),
+                window,
+                cx,
+            );
+            editor
+        });
+
+        cx.observe(&state, |_, _, cx| {
+            cx.notify();
+        })
+        .detach();
+
+        let load_credentials_task = Some(cx.spawn_in(window, {
+            let state = state.clone();
+            async move |this, cx| {
+                if let Some(task) = state
+                    .update(cx, |state, cx| state.authenticate(cx))
+                    .log_err()
+                {
+                    // We don't log an error, because "not signed in" is also an error.
+                    let _ = task.await;
+                }
+
+                this.update(cx, |this, cx| {
+                    this.load_credentials_task = None;
+                    cx.notify();
+                })
+                .log_err();
+            }
+        }));
+
+        Self {
+            api_key_editor,
+            api_url_editor,
+            api_url_error: None,
+            state,
+            load_credentials_task,
+            validate_api_key_task: None,
+            validation_error: None,
+        }
+    }
+
+    fn save_api_key(&mut self, _: &menu::Confirm, window: &mut Window, cx: &mut Context<Self>) {
+        let api_key = self.api_key_editor.read(cx).text(cx);
+        if api_key.is_empty() || self.validate_api_key_task.is_some() {
+            return;
+        }
+
+        let state = self.state.clone();
+        let http_client = state.read(cx).http_client.clone();
+        let connection = AllLanguageModelSettings::get_global(cx)
+            .mistral
+            .connection(&api_key);
+        self.validation_error = None;
+        self.validate_api_key_task = Some(cx.spawn_in(window, async move |this, cx| {
+            let validation = check_health(http_client.as_ref(), &connection).await;
+            // A key that couldn't be checked is saved anyway, with the error shown as a warning,
+            // so that an outage or a gateway without a models endpoint doesn't lock users out.
+            if !validation
+                .as_ref()
+                .is_err_and(HealthCheckError::rejects_api_key)
+            {
+                if let Some(task) = state
+                    .update(cx, |state, cx| state.set_api_key(api_key, cx))
+                    .log_err()
+                {
+                    task.await.log_err();
+                }
+            }
+
+            this.update(cx, |this, cx| {
+                this.validate_api_key_task = None;
+                this.validation_error = validation.err();
+                cx.notify();
+            })
+            .log_err();
+        }));
+
+        cx.notify();
+    }
+
+    fn save_api_url(&mut self, _: &menu::Confirm, _: &mut Window, cx: &mut Context<Self>) {
+        let api_url = self.api_url_editor.read(cx).text(cx);
+        let api_url = if api_url.trim().is_empty() {
+            mistral::MISTRAL_API_URL.to_string()
+        } else {
+            match parse_api_url(&api_url) {
+                Ok(api_url) => api_url,
+                Err(error) => {
+                    self.api_url_error = Some(error.to_string().into());
+                    cx.notify();
+                    return;
+                }
+            }
+        };
+
+        self.api_url_error = None;
+        self.state
+            .update(cx, |state, cx| state.set_api_url(api_url, cx))
+            .detach_and_log_err(cx);
+        cx.notify();
+    }
+
+    fn reload_credentials(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        let state = self.state.clone();
+        self.load_credentials_task = Some(cx.spawn_in(window, async move |this, cx| {
+            if let Some(task) = state
+                .update(cx, |state, cx| state.reload_credentials(cx))
+                .log_err()
+            {
+                // We don't log an error, because "not signed in" is also an error.
+                let _ = task.await;
+            }
+
+            this.update(cx, |this, cx| {
+                this.load_credentials_task = None;
+                cx.notify();
+            })
+            .log_err();
+        }));
+        cx.notify();
+    }
+
+    fn reset_api_key(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        self.api_key_editor
+            .update(cx, |editor, cx| editor.set_text("", window, cx));
+        self.validation_error = None;
+
+        let state = self.state.clone();
+        cx.spawn_in(window, async move |_, cx| {
+            state.update(cx, |state, cx| state.reset_api_key(cx))?.await
+        })
+        .detach_and_log_err(cx);
+
+        cx.notify();
+    }
+
+    fn render_editor(&self, editor: &Entity<Editor>, cx: &mut Context<Self>) -> impl IntoElement {
+        let settings = ThemeSettings::get_global(cx);
         let text_style = TextStyle {
             color: cx.theme().colors().text,
             font_family: settings.ui_font.family.clone(),
@@ -781,189 +3925,4512 @@ impl ConfigurationView {
             white_space: WhiteSpace::Normal,
             ..Default::default()
         };
-        EditorElement::new(
-            &self.api_key_editor,
-            EditorStyle {
-                background: cx.theme().colors().editor_background,
-                local_player: cx.theme().players().local(),
-                text: text_style,
-                ..Default::default()
-            },
-        )
+        EditorElement::new(
+            editor,
+            EditorStyle {
+                background: cx.theme().colors().editor_background,
+                local_player: cx.theme().players().local(),
+                text: text_style,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// An error if the key was rejected, or otherwise a warning, since the key was saved.
+    fn render_validation_error(error: &HealthCheckError) -> Label {
+        Label::new(error.to_string())
+            .size(LabelSize::Small)
+            .color(if error.rejects_api_key() {
+                Color::Error
+            } else {
+                Color::Warning
+            })
+    }
+
+    fn render_models(&self, cx: &mut Context<Self>) -> impl IntoElement {
+        let models = self.state.read(cx).models_with_status(cx);
+        v_flex()
+            .mt_2()
+            .gap_1()
+            .child(
+                Label::new("Available models")
+                    .size(LabelSize::Small)
+                    .color(Color::Muted),
+            )
+            .children(models.into_iter().enumerate().map(|(ix, (model, status))| {
+                let mut description = model_description(&model);
+                if status == ModelStatus::Unavailable {
+                    description.push_str("\nNot available with this API key");
+                }
+                h_flex()
+                    .gap_1()
+                    .child(
+                        div()
+                            .id(("mistral-model-name", ix))
+                            .child(
+                                Label::new(model.display_name().to_string())
+                                    .when(status == ModelStatus::Unavailable, |label| {
+                                        label.color(Color::Disabled)
+                                    }),
+                            )
+                            .tooltip(Tooltip::text(description)),
+                    )
+                    .when(model.supports_tools(), |this| {
+                        this.child(
+                            div()
+                                .id(("mistral-model-tools", ix))
+                                .child(
+                                    Icon::new(IconName::ToolHammer)
+                                        .size(IconSize::Small)
+                                        .color(Color::Muted),
+                                )
+                                .tooltip(Tooltip::text("Supports tools")),
+                        )
+                    })
+                    .when(model.supports_images(), |this| {
+                        this.child(
+                            div()
+                                .id(("mistral-model-images", ix))
+                                .child(
+                                    Icon::new(IconName::Image)
+                                        .size(IconSize::Small)
+                                        .color(Color::Muted),
+                                )
+                                .tooltip(Tooltip::text("Supports images")),
+                        )
+                    })
+            }))
+    }
+
+    fn should_render_editor(&self, cx: &mut Context<Self>) -> bool {
+        !self.state.read(cx).is_authenticated()
+    }
+}
+
+/// Summarizes `model` for the model list: what it's for, its token limits, and when its
+/// training data ends.
+fn model_description(model: &mistral::Model) -> String {
+    let mut description = model
+        .description()
+        .map(|description| format!("{description}\n"))
+        .unwrap_or_default();
+    description.push_str(&model_limits_description(model));
+    if let Some(knowledge_cutoff) = model.knowledge_cutoff() {
+        description.push_str(&format!("\nKnowledge cutoff: {knowledge_cutoff}"));
+    }
+    description
+}
+
+/// Describes the token limits of `model`, including those configured for custom models.
+fn model_limits_description(model: &mistral::Model) -> String {
+    let mut description = format!("Context window: {} tokens", model.max_token_count());
+    if let Some(max_output_tokens) = model.max_output_tokens() {
+        description.push_str(&format!("\nMax output: {max_output_tokens} tokens"));
+    }
+    if let Some(max_completion_tokens) = model
+        .max_completion_tokens()
+        .filter(|tokens| Some(*tokens) != model.max_output_tokens())
+    {
+        description.push_str(&format!(
+            "\nMax tokens per response: {max_completion_tokens}"
+        ));
+    }
+    description
+}
+
+impl Render for ConfigurationView {
+    fn render(&mut self, _: &mut Window, cx: &mut Context<Self>) -> impl IntoElement {
+        let env_var_set = self.state.read(cx).api_key_from_env;
+
+        if self.load_credentials_task.is_some() {
+            div().child(Label::new("Loading credentials...")).into_any()
+        } else if self.should_render_editor(cx) {
+            v_flex()
+                .size_full()
+                .on_action(cx.listener(Self::save_api_key))
+                .child(Label::new("To use Zed's assistant with Mistral, you need to add an API key. Follow these steps:"))
+                .child(
+                    List::new()
+                        .child(InstructionListItem::new(
+                            "Create one by visiting",
+                            Some("Mistral's console"),
+                            Some("https://console.mistral.ai/api-keys"),
+                        ))
+                        .child(InstructionListItem::text_only(
+                            "Ensure your Mistral account has credits",
+                        ))
+                        .child(InstructionListItem::text_only(
+                            "Paste your API key below and hit enter to start using the assistant",
+                        )),
+                )
+                .child(
+                    h_flex()
+                        .w_full()
+                        .my_2()
+                        .px_2()
+                        .py_1()
+                        .bg(cx.theme().colors().editor_background)
+                        .border_1()
+                        .border_color(cx.theme().colors().border)
+                        .rounded_sm()
+                        .child(self.render_editor(&self.api_key_editor, cx)),
+                )
+                .when(self.validate_api_key_task.is_some(), |this| {
+                    this.child(
+                        Label::new("Validating…")
+                            .size(LabelSize::Small)
+                            .color(Color::Muted),
+                    )
+                })
+                .when_some(self.validation_error.as_ref(), |this, error| {
+                    this.child(Self::render_validation_error(error))
+                })
+                .child(
+                    Label::new("API URL (press enter to save)")
+                        .size(LabelSize::Small)
+                        .color(Color::Muted),
+                )
+                .child(
+                    h_flex()
+                        .w_full()
+                        .my_2()
+                        .px_2()
+                        .py_1()
+                        .bg(cx.theme().colors().editor_background)
+                        .border_1()
+                        .border_color(cx.theme().colors().border)
+                        .rounded_sm()
+                        .on_action(cx.listener(Self::save_api_url))
+                        .child(self.render_editor(&self.api_url_editor, cx)),
+                )
+                .when_some(self.api_url_error.clone(), |this, error| {
+                    this.child(Label::new(error).size(LabelSize::Small).color(Color::Error))
+                })
+                .child(
+                    Label::new(
+                        format!("You can also assign the {MISTRAL_API_KEY_VAR} environment variable and restart Zed."),
+                    )
+                    .size(LabelSize::Small).color(Color::Muted),
+                )
+                .into_any()
+        } else {
+            v_flex()
+                .child(
+                    h_flex()
+                        .mt_1()
+                        .p_1()
+                        .justify_between()
+                        .rounded_md()
+                        .border_1()
+                        .border_color(cx.theme().colors().border)
+                        .bg(cx.theme().colors().background)
+                        .child(
+                            h_flex()
+                                .gap_1()
+                                .child(Icon::new(IconName::Check).color(Color::Success))
+                                .child(Label::new(if env_var_set {
+                                    format!("API key set in {MISTRAL_API_KEY_VAR} environment variable.")
+                                } else {
+                                    "API key configured.".to_string()
+                                })),
+                        )
+                        .child(
+                            h_flex()
+                                .gap_1()
+                                .child(
+                                    Button::new("reload-credentials", "Reload Credentials")
+                                        .label_size(LabelSize::Small)
+                                        .icon(Some(IconName::RotateCw))
+                                        .icon_size(IconSize::Small)
+                                        .icon_position(IconPosition::Start)
+                                        .tooltip(Tooltip::text(format!("Re-read the {MISTRAL_API_KEY_VAR} environment variable and stored credentials.")))
+                                        .on_click(cx.listener(|this, _, window, cx| this.reload_credentials(window, cx))),
+                                )
+                                .child(
+                                    Button::new("reset-key", "Reset Key")
+                                        .label_size(LabelSize::Small)
+                                        .icon(Some(IconName::Trash))
+                                        .icon_size(IconSize::Small)
+                                        .icon_position(IconPosition::Start)
+                                        .disabled(env_var_set)
+                                        .when(env_var_set, |this| {
+                                            this.tooltip(Tooltip::text(format!("To reset your API key, unset the {MISTRAL_API_KEY_VAR} environment variable.")))
+                                        })
+                                        .on_click(cx.listener(|this, _, window, cx| this.reset_api_key(window, cx))),
+                                ),
+                        ),
+                )
+                .child(
+                    Label::new(format!("API URL: {}", AllLanguageModelSettings::get_global(cx).mistral.api_url))
+                        .size(LabelSize::Small)
+                        .color(Color::Muted),
+                )
+                .when_some(self.validation_error.as_ref(), |this, error| {
+                    this.child(Self::render_validation_error(error))
+                })
+                .child(self.render_models(cx))
+                .into_any()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt as _;
+    use gpui::{DevicePixels, size};
+    use http_client::{AsyncBody, FakeHttpClient};
+    use language_model::{
+        LanguageModelRequestMessage, LanguageModelResponseSchema, MessageContent,
+    };
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+
+    #[test]
+    fn test_into_mistral_basic_conversion() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::System,
+                    content: vec![MessageContent::Text("System prompt".into())],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("Hello".into())],
+                    cache: false,
+                },
+            ],
+            temperature: Some(0.5),
+            tools: vec![],
+            tool_choice: None,
+            thread_id: None,
+            prompt_id: None,
+            intent: None,
+            mode: None,
+            stop: vec![],
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
+            thinking_allowed: true,
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &MistralRequestSettings {
+                stream: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(mistral_request.model, "mistral-small-latest");
+        assert_eq!(mistral_request.temperature, Some(0.5));
+        assert_eq!(mistral_request.messages.len(), 2);
+        assert!(mistral_request.stream);
+    }
+
+    #[test]
+    fn test_into_mistral_with_image() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![
+                    MessageContent::Text("What's in this image?".into()),
+                    MessageContent::Image(LanguageModelImage {
+                        source: "base64data".into(),
+                        size: Default::default(),
+                    }),
+                ],
+                cache: false,
+            }],
+            tools: vec![],
+            tool_choice: None,
+            temperature: None,
+            thread_id: None,
+            prompt_id: None,
+            intent: None,
+            mode: None,
+            stop: vec![],
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
+            thinking_allowed: true,
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::Pixtral12BLatest,
+            None,
+            &Default::default(),
+        );
+
+        assert_eq!(mistral_request.messages.len(), 1);
+        assert!(matches!(
+            &mistral_request.messages[0],
+            mistral::RequestMessage::User {
+                content: mistral::MessageContent::Multipart { .. }
+            }
+        ));
+
+        if let mistral::RequestMessage::User {
+            content: mistral::MessageContent::Multipart { content },
+        } = &mistral_request.messages[0]
+        {
+            assert_eq!(content.len(), 2);
+            assert!(matches!(
+                &content[0],
+                mistral::MessagePart::Text { text, .. } if text == "What's in this image?"
+            ));
+            assert!(matches!(
+                &content[1],
+                mistral::MessagePart::ImageUrl { image_url, .. } if image_url.url.starts_with("data:image/png;base64,")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_into_mistral_stop_sequences() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hello".into())],
+                cache: false,
+            }],
+            stop: vec!["END".into()],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.stop, vec!["END".to_string()]);
+
+        let request = LanguageModelRequest {
+            stop: (0..6).map(|ix| format!("STOP{ix}")).collect(),
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.stop.len(), mistral::MAX_STOP_SEQUENCES);
+        assert_eq!(mistral_request.stop[0], "STOP0");
+    }
+
+    #[test]
+    fn test_into_mistral_default_stop_sequences() {
+        let mut model = custom_model("self-hosted-reasoner");
+        if let mistral::Model::Custom {
+            default_stop_sequences,
+            ..
+        } = &mut model
+        {
+            *default_stop_sequences = Some(vec!["</answer>".into()]);
+        }
+        let request = LanguageModelRequest {
+            stop: vec!["END".into(), "</answer>".into()],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(request, &model, None, &Default::default());
+        assert_eq!(
+            mistral_request.stop,
+            vec!["</answer>".to_string(), "END".to_string()]
+        );
+        assert!(
+            mistral::Model::MagistralMediumLatest
+                .default_stop_sequences()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_tool_choice() {
+        let tool = LanguageModelRequestTool {
+            name: "search".into(),
+            description: "Searches the project".into(),
+            input_schema: serde_json::json!({ "type": "object" }),
+        };
+        let tool_choice = |tool_choice, tools: &[LanguageModelRequestTool]| {
+            let request = LanguageModelRequest {
+                tool_choice,
+                tools: tools.to_vec(),
+                ..Default::default()
+            };
+            let request = into_mistral(
+                request,
+                &mistral::Model::MistralSmallLatest,
+                None,
+                &Default::default(),
+            );
+            serde_json::to_value(&request).unwrap()["tool_choice"].clone()
+        };
+
+        for (choice, with_tools, without_tools) in [
+            (None, "auto", None),
+            (Some(LanguageModelToolChoice::Auto), "auto", None),
+            (Some(LanguageModelToolChoice::Any), "any", None),
+            (Some(LanguageModelToolChoice::None), "none", Some("none")),
+        ] {
+            assert_eq!(
+                tool_choice(choice.clone(), &[tool.clone()]),
+                serde_json::json!(with_tools),
+                "{choice:?} with tools"
+            );
+            assert_eq!(
+                tool_choice(choice.clone(), &[]),
+                serde_json::json!(without_tools),
+                "{choice:?} without tools"
+            );
+        }
+    }
+
+    #[test]
+    fn test_into_mistral_tools_unsupported() {
+        let mut model = custom_model("mistral-tiny");
+        if let mistral::Model::Custom { supports_tools, .. } = &mut model {
+            *supports_tools = Some(false);
+        }
+        let request = LanguageModelRequest {
+            tools: vec![LanguageModelRequestTool {
+                name: "search".into(),
+                description: "Searches the project".into(),
+                input_schema: serde_json::json!({ "type": "object" }),
+            }],
+            tool_choice: Some(LanguageModelToolChoice::Any),
+            ..Default::default()
+        };
+
+        let request = into_mistral(request, &model, None, &Default::default());
+        assert!(request.tools.is_empty());
+        assert!(request.tool_choice.is_none());
+        assert_eq!(request.parallel_tool_calls, None);
+        let request = serde_json::to_value(&request).unwrap();
+        assert!(request.get("tools").is_none(), "{request}");
+        assert!(request.get("tool_choice").is_none(), "{request}");
+    }
+
+    #[test]
+    fn test_into_mistral_reasoning_in_history() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("What is 2 + 2?".into())],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![
+                        MessageContent::Thinking {
+                            text: "The user wants the sum of 2 and 2.".into(),
+                            signature: None,
+                        },
+                        MessageContent::Text("4".into()),
+                    ],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+        let assistant_messages = |include_reasoning_in_history| {
+            let settings = MistralRequestSettings {
+                include_reasoning_in_history,
+                ..Default::default()
+            };
+            into_mistral(
+                request.clone(),
+                &mistral::Model::MagistralMediumLatest,
+                None,
+                &settings,
+            )
+            .messages
+            .into_iter()
+            .filter_map(|message| match message {
+                mistral::RequestMessage::Assistant { content, .. } => content,
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(assistant_messages(false), vec!["4".to_string()]);
+        assert_eq!(
+            assistant_messages(true),
+            vec![
+                "The user wants the sum of 2 and 2.".to_string(),
+                "4".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_agent() {
+        let model = mistral::Model::MistralSmallLatest;
+        let mut settings = MistralSettings::default();
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &model,
+            None,
+            &settings.request_settings(&model),
+        );
+        assert_eq!(request.agent_id, None);
+
+        settings.available_models.push(AvailableModel {
+            name: model.id().into(),
+            max_tokens: model.max_token_count(),
+            agent_id: Some("ag-123".into()),
+            ..Default::default()
+        });
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &model,
+            None,
+            &settings.request_settings(&model),
+        );
+        assert_eq!(request.agent_id.as_deref(), Some("ag-123"));
+    }
+
+    #[test]
+    fn test_into_mistral_prediction() {
+        let prediction = |model, prediction: Option<&str>| {
+            let settings = MistralRequestSettings {
+                prediction: prediction.map(Into::into),
+                ..Default::default()
+            };
+            let request = into_mistral(LanguageModelRequest::default(), model, None, &settings);
+            serde_json::to_value(&request)
+                .unwrap()
+                .get("prediction")
+                .cloned()
+        };
+
+        let codestral = mistral::Model::CodestralLatest;
+        assert_eq!(
+            prediction(&codestral, Some("fn main() {}")),
+            Some(serde_json::json!({ "type": "content", "content": "fn main() {}" }))
+        );
+        assert_eq!(prediction(&codestral, None), None);
+        assert_eq!(
+            prediction(&mistral::Model::MistralSmallLatest, Some("fn main() {}")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_clamps_temperature() {
+        let request = |temperature| LanguageModelRequest {
+            temperature,
+            ..Default::default()
+        };
+        let temperature = |temperature, model| {
+            into_mistral(request(temperature), model, None, &Default::default()).temperature
+        };
+
+        let magistral = mistral::Model::MagistralMediumLatest;
+        assert_eq!(temperature(Some(1.0), &magistral), Some(0.7));
+        assert_eq!(temperature(Some(0.2), &magistral), Some(0.2));
+        assert_eq!(temperature(None, &magistral), None);
+        assert_eq!(
+            temperature(Some(1.0), &mistral::Model::MistralSmallLatest),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_temperature_by_intent() {
+        let settings = MistralRequestSettings {
+            temperature_by_intent: BTreeMap::from_iter([
+                ("edit_file".to_string(), 0.1),
+                ("thread_summarization".to_string(), 0.9),
+            ]),
+            ..Default::default()
+        };
+        let temperature = |intent, temperature| {
+            let request = LanguageModelRequest {
+                intent,
+                temperature,
+                ..Default::default()
+            };
+            into_mistral(
+                request,
+                &mistral::Model::MistralSmallLatest,
+                None,
+                &settings,
+            )
+            .temperature
+        };
+
+        assert_eq!(
+            temperature(Some(CompletionIntent::EditFile), None),
+            Some(0.1)
+        );
+        assert_eq!(
+            temperature(Some(CompletionIntent::ThreadSummarization), None),
+            Some(0.9)
+        );
+        assert_eq!(
+            temperature(Some(CompletionIntent::EditFile), Some(0.5)),
+            Some(0.5)
+        );
+        assert_eq!(temperature(Some(CompletionIntent::UserPrompt), None), None);
+        assert_eq!(temperature(None, None), None);
+    }
+
+    #[test]
+    fn test_into_mistral_top_p() {
+        let request = LanguageModelRequest {
+            temperature: Some(0.3),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.temperature, Some(0.3));
+        assert_eq!(mistral_request.top_p, Some(0.9));
+
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(body["top_p"], serde_json::json!(0.9f32));
+
+        let mistral_request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert!(body.get("top_p").is_none());
+    }
+
+    #[test]
+    fn test_into_mistral_penalties() {
+        let mistral_request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert!(body.get("frequency_penalty").is_none());
+        assert!(body.get("presence_penalty").is_none());
+
+        let request = LanguageModelRequest {
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-0.25),
+            ..Default::default()
+        };
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(body["frequency_penalty"], serde_json::json!(0.5));
+        assert_eq!(body["presence_penalty"], serde_json::json!(-0.25));
+    }
+
+    #[test]
+    fn test_into_mistral_response_format() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "answer": { "type": "string" } },
+            "required": ["answer"],
+        });
+        let request = LanguageModelRequest {
+            response_schema: Some(LanguageModelResponseSchema {
+                name: "answer".into(),
+                schema: schema.clone(),
+            }),
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request.clone(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(
+            body["response_format"],
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": { "name": "answer", "schema": schema, "strict": true },
+            })
+        );
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::OpenCodestralMamba,
+            None,
+            &Default::default(),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(
+            body["response_format"],
+            serde_json::json!({ "type": "json_object" })
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_safe_prompt() {
+        let model = mistral::Model::MistralSmallLatest;
+        let mut settings = MistralSettings::default();
+
+        let mistral_request = into_mistral(
+            LanguageModelRequest::default(),
+            &model,
+            None,
+            &settings.request_settings(&model),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert!(body.get("safe_prompt").is_none());
+
+        settings.safe_prompt = true;
+        let mistral_request = into_mistral(
+            LanguageModelRequest::default(),
+            &model,
+            None,
+            &settings.request_settings(&model),
+        );
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(body["safe_prompt"], serde_json::json!(true));
+
+        settings.available_models.push(AvailableModel {
+            name: model.id().to_string(),
+            max_tokens: model.max_token_count(),
+            safe_prompt: Some(false),
+            ..Default::default()
+        });
+        assert!(!settings.request_settings(&model).safe_prompt);
+    }
+
+    fn stream_response(
+        content: Option<&str>,
+        finish_reason: Option<&str>,
+        usage: Option<mistral::Usage>,
+    ) -> mistral::StreamResponse {
+        mistral::StreamResponse {
+            id: "response-id".into(),
+            object: "chat.completion.chunk".into(),
+            created: 0,
+            model: "codestral-latest".into(),
+            choices: vec![mistral::StreamChoice {
+                index: 0,
+                delta: mistral::StreamDelta {
+                    role: None,
+                    content: content.map(|content| content.to_string()),
+                    tool_calls: None,
+                    reasoning_content: None,
+                },
+                finish_reason: finish_reason.map(|reason| reason.to_string()),
+            }],
+            usage,
+        }
+    }
+
+    /// A signed in [`State`] for Mistral's own API, for tests to override fields of.
+    fn test_state(http_client: Arc<dyn HttpClient>, api_key: Option<&str>) -> State {
+        State {
+            http_client,
+            api_key: api_key.map(ApiKey::from),
+            api_key_from_env: false,
+            api_url: mistral::MISTRAL_API_URL.into(),
+            api_keys_by_url: HashMap::default(),
+            discovered_models: Vec::new(),
+            listed_model_ids: None,
+            fetch_models_task: None,
+            usage_sink: None,
+            latency_sink: None,
+            api_key_rotation: Arc::default(),
+            _subscription: Subscription::new(|| {}),
+        }
+    }
+
+    fn test_model(
+        model: mistral::Model,
+        state: Entity<State>,
+        http_client: Arc<dyn HttpClient>,
+    ) -> MistralLanguageModel {
+        MistralLanguageModel {
+            id: LanguageModelId::from(model.id().to_string()),
+            model,
+            state,
+            http_client,
+            request_limiter: RateLimiter::new(1),
+            request_deduplicator: RequestDeduplicator::default(),
+            token_count_cache: Arc::new(Mutex::new(TokenCountCache::new(1))),
+        }
+    }
+
+    /// A custom model with a 32k token context window and every other field unset, for
+    /// tests to set the fields they need.
+    fn custom_model(name: &str) -> mistral::Model {
+        mistral::Model::Custom {
+            name: name.into(),
+            display_name: None,
+            max_tokens: 32000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            supports_tools: None,
+            supports_images: None,
+            supports_structured_output: None,
+            supports_thinking: None,
+            supports_prompt_cache: None,
+            max_temperature: None,
+            supports_prediction: None,
+            price_per_token: None,
+            knowledge_cutoff: None,
+            description: None,
+            default_stop_sequences: None,
+        }
+    }
+
+    #[test]
+    fn test_fim_event_mapper() {
+        assert!(mistral::Model::CodestralLatest.supports_fim());
+        assert!(!mistral::Model::MistralSmallLatest.supports_fim());
+
+        let events = MistralFimEventMapper::map_event(stream_response(Some("fn main"), None, None));
+        assert!(matches!(
+            events.as_slice(),
+            [Ok(LanguageModelCompletionEvent::Text(text))] if text == "fn main"
+        ));
+
+        let events = MistralFimEventMapper::map_event(stream_response(
+            Some("() {}"),
+            Some("length"),
+            Some(mistral::Usage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+                total_tokens: 15,
+                prompt_tokens_details: None,
+            }),
+        ));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Ok(LanguageModelCompletionEvent::Text(_)),
+                Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                    input_tokens: 10,
+                    output_tokens: 5,
+                    ..
+                })),
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::MaxTokens)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_event_mapper_length_finish_reason() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let events = mapper.map_event(stream_response(Some("truncated"), Some("length"), None));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Ok(LanguageModelCompletionEvent::StartMessage { .. }),
+                Ok(LanguageModelCompletionEvent::Text(_)),
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::MaxTokens)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_event_mapper_usage_only_chunk() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        mapper.map_event(stream_response(Some("Hello"), Some("stop"), None));
+
+        let mut usage_chunk = stream_response(
+            None,
+            None,
+            Some(mistral::Usage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+                total_tokens: 15,
+                prompt_tokens_details: None,
+            }),
+        );
+        usage_chunk.choices.clear();
+        let events = mapper.map_event(usage_chunk);
+        assert!(matches!(
+            events.as_slice(),
+            [Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                input_tokens: 10,
+                output_tokens: 5,
+                ..
+            }))]
+        ));
+
+        // A chunk without choices or usage is still an error.
+        let mut empty_chunk = stream_response(None, None, None);
+        empty_chunk.choices.clear();
+        assert!(matches!(mapper.map_event(empty_chunk).as_slice(), [Err(_)]));
+    }
+
+    #[test]
+    fn test_event_mapper_start_message() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let events = mapper.map_event(stream_response(Some("Hello"), None, None));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Ok(LanguageModelCompletionEvent::StartMessage { message_id }),
+                Ok(LanguageModelCompletionEvent::Text(_)),
+            ] if message_id == "response-id"
+        ));
+
+        let events = mapper.map_event(stream_response(Some(" world"), Some("stop"), None));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Ok(LanguageModelCompletionEvent::Text(_)),
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_stream_idle_timeout() {
+        let stream = with_idle_timeout(
+            futures::stream::pending::<Result<()>>().boxed(),
+            Duration::from_millis(10),
+        );
+        let events = futures::executor::block_on(stream.collect::<Vec<_>>());
+        assert!(matches!(
+            events.as_slice(),
+            [Err(error)] if error.downcast_ref::<io::Error>().map(io::Error::kind)
+                == Some(io::ErrorKind::TimedOut)
+        ));
+
+        let stream = with_idle_timeout(
+            futures::stream::iter([Ok(1), Ok(2)]).boxed(),
+            Duration::from_secs(60),
+        );
+        let events = futures::executor::block_on(stream.collect::<Vec<_>>());
+        assert!(matches!(events.as_slice(), [Ok(1), Ok(2)]));
+
+        // A stream that stalls after some output still reports its usage.
+        let chunk = stream_response(Some("Hello there"), None, None);
+        let stream = with_idle_timeout(
+            futures::stream::iter([Ok(chunk)])
+                .chain(futures::stream::pending())
+                .boxed(),
+            Duration::from_millis(10),
+        );
+        let events = futures::executor::block_on(
+            MistralEventMapper::new(&mistral::Model::CodestralLatest)
+                .map_stream(stream)
+                .collect::<Vec<_>>(),
+        );
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ..,
+                Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage { output_tokens: 3, .. })),
+                Err(LanguageModelCompletionError::ApiReadResponseError { error, .. }),
+            ] if error.kind() == io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let client = FakeHttpClient::create(|_| async move {
+            smol::Timer::after(Duration::from_secs(60)).await;
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::default())
+                .unwrap())
+        });
+        let request = mistral::Request {
+            model: "mistral-small-latest".into(),
+            stream: true,
+            ..Default::default()
+        };
+
+        let result = futures::executor::block_on(with_request_timeout(
+            mistral::stream_completion(
+                client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                request,
+            ),
+            Some(Duration::from_millis(10)),
+        ));
+        let error = result.err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::TimedOut)
+        );
+
+        let result =
+            futures::executor::block_on(with_request_timeout(async { Ok(1) }, None)).unwrap();
+        assert_eq!(result, 1);
+    }
+
+    #[test]
+    fn test_dropping_stream_cancels_request() {
+        struct ResponseBody {
+            chunk: Option<String>,
+            dropped: Arc<AtomicUsize>,
+        }
+
+        impl futures::AsyncRead for ResponseBody {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                buf: &mut [u8],
+            ) -> std::task::Poll<io::Result<usize>> {
+                // Send one chunk, then stall as if the model were still generating.
+                match self.chunk.take() {
+                    Some(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+                        std::task::Poll::Ready(Ok(chunk.len()))
+                    }
+                    None => std::task::Poll::Pending,
+                }
+            }
+        }
+
+        impl Drop for ResponseBody {
+            fn drop(&mut self) {
+                self.dropped.fetch_add(1, SeqCst);
+            }
+        }
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let dropped = dropped.clone();
+            move |_| {
+                let chunk =
+                    serde_json::to_string(&stream_response(Some("Hello"), None, None)).unwrap();
+                let body = ResponseBody {
+                    chunk: Some(format!("data: {chunk}\n\n")),
+                    dropped: dropped.clone(),
+                };
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from_reader(body))
+                        .unwrap())
+                }
+            }
+        });
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::CodestralLatest,
+            None,
+            &Default::default(),
+        );
+        let request_limiter = RateLimiter::new(1);
+
+        let mut events = futures::executor::block_on(request_limiter.stream(async {
+            let stream = mistral::stream_completion(
+                client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                request,
+            )
+            .await
+            .map_err(into_completion_error)?;
+            Ok(MistralEventMapper::new(&mistral::Model::CodestralLatest).map_stream(stream))
+        }))
+        .unwrap()
+        .boxed();
+        assert!(matches!(
+            futures::executor::block_on(events.next()),
+            Some(Ok(LanguageModelCompletionEvent::StartMessage { .. }))
+        ));
+        assert_eq!(dropped.load(SeqCst), 0);
+
+        drop(events);
+        assert_eq!(dropped.load(SeqCst), 1);
+        assert!(
+            request_limiter
+                .stream(async { Ok(futures::stream::empty::<()>()) })
+                .now_or_never()
+                .is_some(),
+            "the rate limiter's permit should be released"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_request_deduplicator(cx: &mut gpui::TestAppContext) {
+        let requests_sent = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let requests_sent = requests_sent.clone();
+            move |_| {
+                requests_sent.fetch_add(1, SeqCst);
+                async move {
+                    let body = [Some("Hello"), Some(", world!")]
+                        .into_iter()
+                        .map(|content| {
+                            let chunk = stream_response(content, None, None);
+                            format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap())
+                        })
+                        .collect::<String>();
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(body))
+                        .unwrap())
+                }
+            }
+        });
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::CodestralLatest,
+            None,
+            &Default::default(),
+        );
+        let key = RequestDeduplicator::key(&request);
+        let deduplicator = RequestDeduplicator::default();
+        let send = || {
+            let client = client.clone();
+            let request = request.clone();
+            || async move {
+                let stream = mistral::stream_completion(
+                    client.as_ref(),
+                    &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                    request,
+                )
+                .await
+                .map_err(into_completion_error)?;
+                Ok::<_, LanguageModelCompletionError>(stream)
+            }
+        };
+        let contents = |stream: BoxStream<'static, Result<mistral::StreamResponse>>| async move {
+            stream
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .map(|chunk| chunk.unwrap().choices[0].delta.content.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let executor = cx.executor();
+
+        let first = deduplicator.stream(key, &executor, send());
+        let second = deduplicator.stream(key, &executor, send());
+        let first = first.await.unwrap();
+        let second = second.await.unwrap();
+        assert_eq!(contents(first).await, ["Hello", ", world!"]);
+        assert_eq!(contents(second).await, ["Hello", ", world!"]);
+        assert_eq!(requests_sent.load(SeqCst), 1);
+
+        // Requests made after the response has finished are sent again.
+        let third = deduplicator.stream(key, &executor, send()).await.unwrap();
+        assert_eq!(contents(third).await, ["Hello", ", world!"]);
+        assert_eq!(requests_sent.load(SeqCst), 2);
+
+        // Requests that joined a response still receive all of it if the request that sent it
+        // is dropped.
+        let first = deduplicator.stream(key, &executor, send());
+        let second = deduplicator.stream(key, &executor, send());
+        drop(first);
+        assert_eq!(contents(second.await.unwrap()).await, ["Hello", ", world!"]);
+        assert_eq!(requests_sent.load(SeqCst), 3);
+
+        // The request is cancelled once every request sharing its response has been dropped.
+        let first = deduplicator.stream(key, &executor, send());
+        let second = deduplicator.stream(key, &executor, send());
+        drop((first, second));
+        cx.run_until_parked();
+        assert!(deduplicator.in_flight.lock().is_empty());
+        assert_eq!(requests_sent.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn test_into_mistral_n() {
+        let model = mistral::Model::MistralSmallLatest;
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Name a color".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        let body = serde_json::to_value(into_mistral(
+            request.clone(),
+            &model,
+            None,
+            &MistralRequestSettings::default(),
+        ))
+        .unwrap();
+        assert!(body.get("n").is_none());
+
+        let settings = MistralRequestSettings {
+            n: Some(2),
+            ..Default::default()
+        };
+        let body = serde_json::to_value(into_mistral(request, &model, None, &settings)).unwrap();
+        assert_eq!(body["n"], 2);
+    }
+
+    #[test]
+    fn test_event_mapper_multiple_choices() {
+        let choice = |index, content: &str, finish_reason: Option<&str>| mistral::StreamChoice {
+            index,
+            delta: mistral::StreamDelta {
+                role: None,
+                content: Some(content.into()),
+                tool_calls: None,
+                reasoning_content: None,
+            },
+            finish_reason: finish_reason.map(|reason| reason.to_string()),
+        };
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let mut texts = BTreeMap::<u32, String>::default();
+        let mut stops = Vec::new();
+        for choices in [
+            vec![choice(0, "Blue", None), choice(1, "Red", None)],
+            vec![choice(1, "dish", Some("stop")), choice(0, "ish", None)],
+            vec![choice(0, "", Some("stop"))],
+        ] {
+            let mut event = stream_response(None, None, None);
+            event.choices = choices;
+            for (index, event) in mapper.map_choices(event) {
+                match event.unwrap() {
+                    LanguageModelCompletionEvent::Text(text) => {
+                        texts.entry(index).or_default().push_str(&text)
+                    }
+                    LanguageModelCompletionEvent::Stop(_) => stops.push(index),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(texts[&0], "Blueish");
+        assert_eq!(texts[&1], "Reddish");
+        assert_eq!(stops, [1, 0]);
+    }
+
+    #[test]
+    fn test_raw_response_logger_redacts_secrets() {
+        let logger = RawResponseLogger {
+            secrets: vec!["sk-secret".into(), String::new()],
+        };
+        let mut event = stream_response(Some("Your key is sk-secret"), None, None);
+        event.id = "sk-secret".into();
+        let json = logger.redact(serde_json::to_string(&event).unwrap());
+        assert!(!json.contains("sk-secret"));
+        assert!(json.contains("Your key is [REDACTED]"));
+    }
+
+    #[test]
+    fn test_event_mapper_skips_empty_content() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let events = mapper.map_event(stream_response(Some(""), None, None));
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, Ok(LanguageModelCompletionEvent::Text(_))))
+        );
+
+        let events = mapper.map_event(stream_response(Some("\n"), None, None));
+        assert!(matches!(
+            events.as_slice(),
+            [Ok(LanguageModelCompletionEvent::Text(text))] if text == "\n"
+        ));
+    }
+
+    #[test]
+    fn test_event_mapper_tool_call_argument_previews() {
+        let tool_call_chunk = |id: Option<&str>, name: Option<&str>, arguments: &str| {
+            let mut event = stream_response(None, None, None);
+            event.choices[0].delta.tool_calls = Some(vec![mistral::ToolCallChunk {
+                index: 0,
+                id: id.map(|id| id.to_string()),
+                function: Some(mistral::FunctionChunk {
+                    name: name.map(|name| name.to_string()),
+                    arguments: Some(arguments.into()),
+                }),
+            }]);
+            event
+        };
+        let tool_uses = |events: Vec<Result<LanguageModelCompletionEvent, _>>| {
+            events
+                .into_iter()
+                .filter_map(|event| match event {
+                    Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => Some(tool_use),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let previews = tool_uses(mapper.map_event(tool_call_chunk(
+            Some("call_1"),
+            Some("read_file"),
+            "{\"path\": \"src/ma",
+        )));
+        assert_eq!(previews.len(), 1);
+        assert!(!previews[0].is_input_complete);
+        assert_eq!(previews[0].input, serde_json::json!({ "path": "src/ma" }));
+
+        let previews = tool_uses(mapper.map_event(tool_call_chunk(None, None, "in.rs\"")));
+        assert_eq!(previews.len(), 1);
+        assert!(!previews[0].is_input_complete);
+        assert_eq!(
+            previews[0].input,
+            serde_json::json!({ "path": "src/main.rs" })
+        );
+
+        let previews = tool_uses(mapper.map_event(tool_call_chunk(None, None, "}")));
+        assert_eq!(previews.len(), 1);
+        assert!(!previews[0].is_input_complete);
+
+        let tool_uses =
+            tool_uses(mapper.map_event(stream_response(None, Some("tool_calls"), None)));
+        assert_eq!(tool_uses.len(), 1);
+        assert!(tool_uses[0].is_input_complete);
+        assert_eq!(tool_uses[0].id.to_string(), "call_1");
+        assert_eq!(
+            tool_uses[0].input,
+            serde_json::json!({ "path": "src/main.rs" })
+        );
+    }
+
+    #[test]
+    fn test_repair_json() {
+        assert_eq!(
+            repair_json("{\"paths\": [\"a\", \"b\",], \"recursive\": true,\n}"),
+            "{\"paths\": [\"a\", \"b\"], \"recursive\": true}"
+        );
+        assert_eq!(
+            repair_json("{\"text\": \"line 1\nline 2\"}"),
+            "{\"text\": \"line 1\\nline 2\"}"
+        );
+        // Commas and escapes inside strings are left alone.
+        assert_eq!(
+            repair_json("{\"text\": \"a,}\\\" b,]\"}"),
+            "{\"text\": \"a,}\\\" b,]\"}"
+        );
+    }
+
+    #[test]
+    fn test_event_mapper_lenient_json() {
+        let tool_call = |arguments: &str| {
+            let mut event = stream_response(None, Some("tool_calls"), None);
+            event.choices[0].delta.tool_calls = Some(vec![mistral::ToolCallChunk {
+                index: 0,
+                id: Some("call_1".into()),
+                function: Some(mistral::FunctionChunk {
+                    name: Some("edit_file".into()),
+                    arguments: Some(arguments.into()),
+                }),
+            }]);
+            event
+        };
+        let recoverable = "{\"path\": \"a.rs\", \"text\": \"fn main() {\n}\",}";
+        let unrecoverable = "{\"path\": \"a.rs\" \"text\": }";
+
+        let expected_input = serde_json::json!({ "path": "a.rs", "text": "fn main() {\n}" });
+        let events = MistralEventMapper::new(&mistral::Model::MistralSmallLatest)
+            .lenient_json(true)
+            .map_event(tool_call(recoverable));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Ok(LanguageModelCompletionEvent::ToolUse(tool_use))
+                if tool_use.is_input_complete && tool_use.input == expected_input
+        )));
+
+        for (lenient_json, arguments) in [(false, recoverable), (true, unrecoverable)] {
+            let events = MistralEventMapper::new(&mistral::Model::MistralSmallLatest)
+                .lenient_json(lenient_json)
+                .map_event(tool_call(arguments));
+            assert!(
+                events.iter().any(|event| matches!(
+                    event,
+                    Ok(LanguageModelCompletionEvent::ToolUseJsonParseError { raw_input, .. })
+                        if raw_input.as_ref() == arguments
+                )),
+                "expected {arguments:?} to fail to parse with lenient_json: {lenient_json}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_mapper_tool_call_order() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let mut event = stream_response(None, None, None);
+        event.choices[0].delta.tool_calls = Some(
+            [2, 0, 1]
+                .into_iter()
+                .map(|index| mistral::ToolCallChunk {
+                    index,
+                    id: Some(format!("call_{index}")),
+                    function: Some(mistral::FunctionChunk {
+                        name: Some(format!("tool_{index}")),
+                        arguments: Some("{}".into()),
+                    }),
+                })
+                .collect(),
+        );
+        mapper.map_event(event);
+
+        let events = mapper.map_event(stream_response(None, Some("tool_calls"), None));
+        let ids = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => {
+                    Some(tool_use.id.to_string())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["call_0", "call_1", "call_2"]);
+    }
+
+    #[test]
+    fn test_event_mapper_tool_call_id_after_arguments() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let chunk = |id: Option<&str>, name: Option<&str>, arguments: &str| {
+            let mut event = stream_response(None, None, None);
+            event.choices[0].delta.tool_calls = Some(vec![mistral::ToolCallChunk {
+                index: 0,
+                id: id.map(str::to_string),
+                function: Some(mistral::FunctionChunk {
+                    name: name.map(str::to_string),
+                    arguments: Some(arguments.to_string()),
+                }),
+            }]);
+            event
+        };
+
+        // No preview can be shown before the id is known.
+        let events = mapper.map_event(chunk(None, Some("read_file"), r#"{"path":"#));
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, Ok(LanguageModelCompletionEvent::ToolUse(_))))
+        );
+        mapper.map_event(chunk(Some("call_0"), None, r#""src/"#));
+        mapper.map_event(chunk(Some(""), Some(""), r#"main.rs"}"#));
+
+        let events = mapper.map_event(stream_response(None, Some("tool_calls"), None));
+        let tool_uses = events
+            .into_iter()
+            .filter_map(|event| match event {
+                Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => Some(tool_use),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tool_uses.len(), 1);
+        assert_eq!(tool_uses[0].id.to_string(), "call_0");
+        assert_eq!(tool_uses[0].name.as_ref(), "read_file");
+        assert_eq!(
+            tool_uses[0].input,
+            serde_json::json!({ "path": "src/main.rs" })
+        );
+    }
+
+    #[test]
+    fn test_event_mapper_incomplete_tool_call() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let mut event = stream_response(None, None, None);
+        event.choices[0].delta.tool_calls = Some(vec![
+            mistral::ToolCallChunk {
+                index: 0,
+                id: None,
+                function: Some(mistral::FunctionChunk {
+                    name: Some("broken_tool".into()),
+                    arguments: Some("{}".into()),
+                }),
+            },
+            mistral::ToolCallChunk {
+                index: 1,
+                id: Some("call_1".into()),
+                function: Some(mistral::FunctionChunk {
+                    name: Some("valid_tool".into()),
+                    arguments: Some(r#"{"path":"a.rs"}"#.into()),
+                }),
+            },
+        ]);
+        mapper.map_event(event);
+
+        let events = mapper.map_event(stream_response(None, Some("tool_calls"), None));
+        assert!(events.iter().all(Result::is_ok));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Ok(LanguageModelCompletionEvent::ToolUseIncomplete {
+                id: None,
+                tool_name: Some(tool_name),
+                ..
+            }) if tool_name.as_ref() == "broken_tool"
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Ok(LanguageModelCompletionEvent::ToolUse(tool_use))
+                if tool_use.id.to_string() == "call_1" && tool_use.is_input_complete
+        )));
+        assert!(matches!(
+            events.last(),
+            Some(Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)))
+        ));
+    }
+
+    #[test]
+    fn test_event_mapper_content_filter_finish_reason() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let events = mapper.map_event(stream_response(None, Some("content_filter"), None));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Ok(LanguageModelCompletionEvent::StartMessage { .. }),
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::Refusal)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_event_mapper_thinking() {
+        assert!(mistral::Model::MagistralSmallLatest.supports_thinking());
+        assert!(!mistral::Model::MistralSmallLatest.supports_thinking());
+
+        let reasoning = |text: &str| {
+            let mut response = stream_response(None, None, None);
+            response.choices[0].delta.reasoning_content = Some(text.into());
+            response
+        };
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MagistralSmallLatest);
+        let events = [
+            reasoning("Let me think."),
+            stream_response(Some("The answer"), None, None),
+            reasoning("Double-checking."),
+            stream_response(Some(" is 42."), Some("stop"), None),
+        ]
+        .into_iter()
+        .flat_map(|event| mapper.map_event(event))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                LanguageModelCompletionEvent::StartMessage { .. },
+                LanguageModelCompletionEvent::Thinking { text: first, .. },
+                LanguageModelCompletionEvent::Text(answer),
+                LanguageModelCompletionEvent::Thinking { text: second, .. },
+                LanguageModelCompletionEvent::Text(_),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ] if first == "Let me think." && answer == "The answer" && second == "Double-checking."
+        ));
+
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MagistralSmallLatest);
+        let events = [
+            stream_response(Some("<think>Hmm"), None, None),
+            stream_response(Some(", easy.</think>Done"), None, None),
+        ]
+        .into_iter()
+        .flat_map(|event| mapper.map_event(event))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                LanguageModelCompletionEvent::StartMessage { .. },
+                LanguageModelCompletionEvent::Thinking { text: first, .. },
+                LanguageModelCompletionEvent::Thinking { text: second, .. },
+                LanguageModelCompletionEvent::Text(answer),
+            ] if first == "Hmm" && second == ", easy." && answer == "Done"
+        ));
+
+        // Models without thinking support keep reasoning delimiters in the text.
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let events = mapper.map_event(reasoning("ignored"));
+        assert!(matches!(
+            events.as_slice(),
+            [Ok(LanguageModelCompletionEvent::StartMessage { .. })]
+        ));
+        let events = mapper.map_event(stream_response(Some("<think>"), None, None));
+        assert!(matches!(
+            events.as_slice(),
+            [Ok(LanguageModelCompletionEvent::Text(text))] if text == "<think>"
+        ));
+    }
+
+    #[test]
+    fn test_into_mistral_thinking() {
+        let reasoning_model = |thinking| {
+            let mut model = custom_model("magistral-custom");
+            if let mistral::Model::Custom {
+                supports_thinking, ..
+            } = &mut model
+            {
+                *supports_thinking = thinking;
+            }
+            model
+        };
+        let request = |thinking_allowed| LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hello".into())],
+                cache: false,
+            }],
+            thinking_allowed,
+            ..Default::default()
+        };
+
+        let model = reasoning_model(Some(true));
+        assert!(model.supports_thinking());
+        let mistral_request = into_mistral(request(true), &model, None, &Default::default());
+        assert_eq!(
+            mistral_request.prompt_mode,
+            Some(mistral::PromptMode::Reasoning)
+        );
+        let mistral_request = into_mistral(request(false), &model, None, &Default::default());
+        assert_eq!(mistral_request.prompt_mode, None);
+
+        let model = reasoning_model(None);
+        assert!(!model.supports_thinking());
+        let mistral_request = into_mistral(request(true), &model, None, &Default::default());
+        assert_eq!(mistral_request.prompt_mode, None);
+        assert!(
+            serde_json::to_value(&mistral_request)
+                .unwrap()
+                .get("prompt_mode")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            base_delay: Duration::from_millis(500),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(4), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10), Duration::from_secs(30));
+        assert_eq!(policy.backoff(40), Duration::from_secs(30));
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::MAX,
+        };
+        assert_eq!(policy.backoff(2), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let attempts = attempts.clone();
+            move |_| {
+                let attempt = attempts.fetch_add(1, SeqCst);
+                async move {
+                    let response = if attempt < 2 {
+                        http_client::Response::builder()
+                            .status(503)
+                            .body(AsyncBody::from("Service unavailable"))
+                    } else {
+                        http_client::Response::builder()
+                            .status(200)
+                            .body(AsyncBody::from(
+                                "data: {\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":0,\"model\":\"mistral-small-latest\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n",
+                            ))
+                    };
+                    Ok(response.unwrap())
+                }
+            }
+        });
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &MistralRequestSettings::default(),
+        );
+        let send = |max_attempts| {
+            let policy = RetryPolicy {
+                max_attempts,
+                base_delay: Duration::from_millis(1),
+            };
+            futures::executor::block_on(policy.retry(|| {
+                mistral::stream_completion(
+                    client.as_ref(),
+                    &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                    request.clone(),
+                )
+            }))
+        };
+
+        let error = send(2).err().unwrap();
+        assert_eq!(
+            error.downcast::<mistral::ApiError>().unwrap().status_code,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(attempts.load(SeqCst), 2);
+
+        attempts.store(0, SeqCst);
+        let events = futures::executor::block_on(send(3).unwrap().collect::<Vec<_>>());
+        assert_eq!(attempts.load(SeqCst), 3);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].as_ref().unwrap().choices[0]
+                .delta
+                .content
+                .as_deref(),
+            Some("Hi")
+        );
+    }
+
+    #[test]
+    fn test_upload_file() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/files");
+            let mut body = Vec::new();
+            request.into_body().read_to_end(&mut body).await?;
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains("name=\"purpose\"\r\n\r\nocr\r\n"));
+            assert!(body.contains("filename=\"report.pdf\""));
+            assert!(body.contains("%PDF-1.7"));
+
+            if body.contains("huge") {
+                return Ok(http_client::Response::builder()
+                    .status(413)
+                    .body(AsyncBody::from(r#"{"message":"File too large"}"#))
+                    .unwrap());
+            }
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(
+                    r#"{"id":"file-1","object":"file","bytes":12,"filename":"report.pdf","purpose":"ocr"}"#,
+                ))
+                .unwrap())
+        });
+        let upload = |contents: &str| {
+            futures::executor::block_on(upload_file(
+                client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                "report.pdf",
+                contents.as_bytes().to_vec(),
+                mistral::FilePurpose::Ocr,
+            ))
+        };
+
+        assert_eq!(upload("%PDF-1.7 ...").unwrap(), "file-1");
+        assert_eq!(
+            upload("%PDF-1.7 huge").unwrap_err().to_string(),
+            "report.pdf is larger than Mistral accepts for uploaded files"
+        );
+
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Summarize this report".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let settings = MistralRequestSettings {
+            file_ids: vec!["file-1".into()],
+            ..Default::default()
+        };
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &settings,
+        );
+        assert_eq!(
+            mistral_request.messages,
+            [mistral::RequestMessage::User {
+                content: mistral::MessageContent::Multipart {
+                    content: vec![
+                        mistral::MessagePart::Text {
+                            text: "Summarize this report".into(),
+                            cache_control: None,
+                        },
+                        mistral::MessagePart::File {
+                            file_id: "file-1".into(),
+                        },
+                    ],
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_poll_batch() {
+        let client = FakeHttpClient::create(|request| async move {
+            let body = match request.uri().path() {
+                "/v1/batch/jobs/job-running" => serde_json::json!({
+                    "id": "job-running",
+                    "status": "RUNNING",
+                    "model": "mistral-small-latest",
+                    "total_requests": 2,
+                })
+                .to_string(),
+                "/v1/batch/jobs/job-done" => serde_json::json!({
+                    "id": "job-done",
+                    "status": "SUCCESS",
+                    "model": "mistral-small-latest",
+                    "output_file": "file-out",
+                    "total_requests": 1,
+                    "succeeded_requests": 1,
+                })
+                .to_string(),
+                "/v1/files/file-out/content" => serde_json::json!({
+                    "custom_id": "0",
+                    "response": {
+                        "status_code": 200,
+                        "body": {
+                            "id": "cmpl-1",
+                            "object": "chat.completion",
+                            "created": 0,
+                            "model": "mistral-small-latest",
+                            "choices": [{
+                                "index": 0,
+                                "message": { "role": "assistant", "content": "Summary" },
+                                "finish_reason": "stop"
+                            }],
+                            "usage": { "prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12 }
+                        }
+                    }
+                })
+                .to_string(),
+                path => panic!("unexpected request to {path}"),
+            };
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(body))
+                .unwrap())
+        });
+
+        let batch = futures::executor::block_on(poll_batch(
+            client.as_ref(),
+            &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+            "job-running",
+        ))
+        .unwrap();
+        assert_eq!(batch.job.status, mistral::BatchJobStatus::Running);
+        assert!(batch.results.is_none());
+
+        let batch = futures::executor::block_on(poll_batch(
+            client.as_ref(),
+            &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+            "job-done",
+        ))
+        .unwrap();
+        let results = batch.results.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].custom_id, "0");
+        let response = results[0].response.as_ref().unwrap();
+        assert_eq!(
+            response.body.choices[0].message.text().as_deref(),
+            Some("Summary")
+        );
+    }
+
+    #[gpui::test]
+    async fn test_token_count_cache(cx: &mut gpui::TestAppContext) {
+        let http_client = FakeHttpClient::with_404_response();
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key")));
+        let model = MistralLanguageModel {
+            token_count_cache: Arc::new(Mutex::new(TokenCountCache::new(2))),
+            ..test_model(mistral::Model::MistralSmallLatest, state, http_client)
+        };
+        let request = |text: &str| LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text(text.into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let count = |text: &str, cx: &mut gpui::TestAppContext| {
+            cx.update(|cx| model.count_tokens(request(text), cx))
+        };
+        // Replaces the cached count of `text` with one that tokenizing can't produce, so that
+        // counting it again shows whether it was read from the cache.
+        let mark = |text: &str| {
+            model
+                .token_count_cache
+                .lock()
+                .insert(TokenCountCache::key(&request(text)), 1000);
+        };
+
+        let hello = count("Hello, world!", cx).await.unwrap();
+        assert_ne!(hello, 1000);
+        mark("Hello, world!");
+        assert_eq!(count("Hello, world!", cx).await.unwrap(), 1000);
+
+        count("Goodbye", cx).await.unwrap();
+        mark("Goodbye");
+        assert_eq!(count("Hello, world!", cx).await.unwrap(), 1000);
+
+        // "Goodbye" is now the least recently used count, so it is evicted first.
+        count("See you later", cx).await.unwrap();
+        assert_ne!(count("Goodbye", cx).await.unwrap(), 1000);
+
+        // A count recorded twice, such as by two concurrent counts, is only kept once.
+        mark("Goodbye");
+        mark("Goodbye");
+        assert_eq!(model.token_count_cache.lock().entries.len(), 2);
+    }
+
+    #[test]
+    fn test_model_limits_description() {
+        assert_eq!(
+            model_limits_description(&mistral::Model::MistralSmallLatest),
+            format!(
+                "Context window: {} tokens",
+                mistral::Model::MistralSmallLatest.max_token_count()
+            )
+        );
+
+        let mut model = custom_model("custom");
+        if let mistral::Model::Custom {
+            max_output_tokens,
+            max_completion_tokens,
+            ..
+        } = &mut model
+        {
+            *max_output_tokens = Some(8192);
+            *max_completion_tokens = Some(1024);
+        }
+        assert_eq!(
+            model_limits_description(&model),
+            "Context window: 32000 tokens\nMax output: 8192 tokens\nMax tokens per response: 1024"
+        );
+    }
+
+    #[test]
+    fn test_mismatched_max_tokens() {
+        let model = |name: &str, max_tokens| AvailableModel {
+            name: name.into(),
+            max_tokens,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            mismatched_max_tokens(&model("mistral-medium-latest", 32000)),
+            Some(128000)
+        );
+        assert_eq!(
+            mismatched_max_tokens(&model("mistral-medium-latest", 1280000)),
+            Some(128000)
+        );
+        assert_eq!(
+            mismatched_max_tokens(&model("mistral-medium-latest", 128000)),
+            None
+        );
+        assert_eq!(
+            mismatched_max_tokens(&model("mistral-medium-latest", 100000)),
+            None
+        );
+        // The limits of custom models aren't known.
+        assert_eq!(mismatched_max_tokens(&model("my-fine-tune", 32000)), None);
+    }
+
+    #[test]
+    fn test_resolve_model() {
+        let settings = MistralSettings {
+            available_models: vec![AvailableModel {
+                name: "mistral-gateway".into(),
+                max_tokens: 32000,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let models = merge_models(&[], &settings);
+        let resolve = |id| {
+            resolve_model(&models, id, mistral::Model::default())
+                .id()
+                .to_string()
+        };
+
+        assert_eq!(
+            resolve(Some("mistral-large-latest")),
+            "mistral-large-latest"
+        );
+        assert_eq!(resolve(Some("mistral-gateway")), "mistral-gateway");
+        assert_eq!(
+            resolve(Some("mistral-huge")),
+            mistral::Model::default().id()
+        );
+        assert_eq!(resolve(None), mistral::Model::default().id());
+    }
+
+    #[test]
+    fn test_merge_models_disabled_models() {
+        let ids = |settings: &MistralSettings| {
+            merge_models(&[], settings)
+                .iter()
+                .map(|model| model.id().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut settings = MistralSettings::default();
+        assert!(ids(&settings).contains(&"mistral-large-latest".to_string()));
+
+        settings.disabled_models = vec!["mistral-large-latest".into()];
+        let models = ids(&settings);
+        assert!(!models.contains(&"mistral-large-latest".to_string()));
+        assert!(models.contains(&"mistral-small-latest".to_string()));
+
+        settings.available_models.push(AvailableModel {
+            name: "mistral-large-latest".into(),
+            max_tokens: 128000,
+            ..Default::default()
+        });
+        assert!(ids(&settings).contains(&"mistral-large-latest".to_string()));
+    }
+
+    #[test]
+    fn test_usage_cost() {
+        let usage = TokenUsage {
+            input_tokens: 2_000_000,
+            output_tokens: 500_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            usage_cost(&mistral::Model::MistralLargeLatest, &usage),
+            Some(7.0)
+        );
+
+        let model = |price| {
+            let mut model = custom_model("self-hosted");
+            if let mistral::Model::Custom {
+                price_per_token, ..
+            } = &mut model
+            {
+                *price_per_token = price;
+            }
+            model
+        };
+        assert_eq!(usage_cost(&model(None), &usage), None);
+        assert_eq!(
+            usage_cost(
+                &model(Some(mistral::TokenPrice {
+                    input: 0.5,
+                    output: 1.0,
+                })),
+                &usage
+            ),
+            Some(1.5)
+        );
+    }
+
+    #[test]
+    fn test_count_mistral_tokens() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(10);
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text(text.trim_end().into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        // Mistral's tokenizer encodes this as 100 tokens of text, plus `<s>[INST]` and `[/INST]`.
+        let expected_tokens = 103.0;
+        let tokens =
+            count_mistral_tokens(request, &mistral::Model::MistralSmallLatest).unwrap() as f64;
+        assert!(
+            (tokens - expected_tokens).abs() / expected_tokens < 0.1,
+            "counted {tokens} tokens, expected about {expected_tokens}"
+        );
+    }
+
+    #[test]
+    fn test_count_mistral_tokens_with_images() {
+        let text = LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text("What is in this image?".into())],
+            cache: false,
+        };
+        let mut with_image = text.clone();
+        with_image
+            .content
+            .push(MessageContent::Image(LanguageModelImage {
+                source: "base64data".into(),
+                size: size(DevicePixels(2048), DevicePixels(1024)),
+            }));
+
+        let model = mistral::Model::Pixtral12BLatest;
+        let text_tokens = count_mistral_tokens(
+            LanguageModelRequest {
+                messages: vec![text],
+                ..Default::default()
+            },
+            &model,
+        )
+        .unwrap();
+        let total_tokens = count_mistral_tokens(
+            LanguageModelRequest {
+                messages: vec![with_image],
+                ..Default::default()
+            },
+            &model,
+        )
+        .unwrap();
+
+        // Downscaled to 1024x512, which is 64x32 patches plus 32 row breaks.
+        assert_eq!(total_tokens - text_tokens, 64 * 32 + 32);
+    }
+
+    #[test]
+    fn test_count_tokens_fallback() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hello, world!".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        // An unknown tokenizer fails, so the count is estimated from the text instead.
+        assert_eq!(count_tokens(&request, "unknown-tokenizer"), 4);
+        assert!(count_tokens(&request, "gpt-4o") > 0);
+    }
+
+    #[test]
+    fn test_count_mistral_tokens_with_tools() {
+        let mut request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("What's the weather in Paris?".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let model = mistral::Model::MistralSmallLatest;
+        let without_tools = count_mistral_tokens(request.clone(), &model).unwrap();
+
+        request.tools.push(LanguageModelRequestTool {
+            name: "get_weather".into(),
+            description: "Returns the current weather for a city".into(),
+            input_schema: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "city": { "type": "string", "description": "The city name" }
+                },
+                "required": ["city"]
+            }),
+        });
+        let with_tools = count_mistral_tokens(request, &model).unwrap();
+        assert!(
+            with_tools > without_tools + 20,
+            "{with_tools} tokens with tools, {without_tools} without"
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_assistant_prefix() {
+        let message = |role, text: &str| LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "Hi"),
+                message(Role::Assistant, "Hello!"),
+                message(Role::User, "Reply with JSON"),
+                message(Role::Assistant, "{\"answer\":"),
+            ],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        let prefixes = mistral_request
+            .messages
+            .iter()
+            .map(|message| {
+                matches!(
+                    message,
+                    mistral::RequestMessage::Assistant { prefix: true, .. }
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(prefixes, [false, false, false, true]);
+
+        let body = serde_json::to_value(&mistral_request).unwrap();
+        assert_eq!(body["messages"][3]["prefix"], serde_json::json!(true));
+        assert!(body["messages"][1].get("prefix").is_none());
+    }
+
+    #[test]
+    fn test_into_mistral_continue_last_message() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("Write a hello world program".into())],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![
+                        MessageContent::Text("fn main() {".into()),
+                        MessageContent::Text("\n    println!(".into()),
+                    ],
+                    cache: false,
+                },
+            ],
+            continue_last_message: true,
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request.clone(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.messages.len(), 2);
+        assert!(matches!(
+            mistral_request.messages.last(),
+            Some(mistral::RequestMessage::Assistant {
+                content: Some(content),
+                prefix: true,
+                ..
+            }) if content == "fn main() {\n    println!("
+        ));
+
+        // A fresh turn only prefills the reply with the last part.
+        let mistral_request = into_mistral(
+            LanguageModelRequest {
+                continue_last_message: false,
+                ..request
+            },
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.messages.len(), 3);
+    }
+
+    #[test]
+    fn test_join_trailing_assistant_messages() {
+        let assistant = |content: &str, tool_calls| mistral::RequestMessage::Assistant {
+            content: Some(content.into()),
+            tool_calls,
+            prefix: false,
+        };
+        let tool_call = mistral::ToolCall {
+            id: "call-1".into(),
+            content: mistral::ToolCallContent::Function {
+                function: mistral::FunctionContent {
+                    name: "search".into(),
+                    arguments: "{}".into(),
+                },
+            },
+        };
+        let mut messages = vec![
+            assistant("Let me search for that.", vec![tool_call]),
+            assistant("fn main() {", Vec::new()),
+            assistant("\n    println!(", Vec::new()),
+        ];
+
+        join_trailing_assistant_messages(&mut messages);
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                mistral::RequestMessage::Assistant { tool_calls, .. },
+                mistral::RequestMessage::Assistant {
+                    content: Some(content),
+                    prefix: true,
+                    ..
+                },
+            ] if tool_calls.len() == 1 && content == "fn main() {\n    println!("
+        ));
+    }
+
+    #[test]
+    fn test_into_mistral_merges_system_messages() {
+        let message = |role, text: &str| LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::System, "Answer concisely."),
+                message(Role::User, "Hello"),
+                message(Role::System, "The user is in Paris."),
+            ],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.messages.len(), 3);
+        match &mistral_request.messages[0] {
+            mistral::RequestMessage::System { content } => {
+                assert_eq!(content, "You are a helpful assistant.\nAnswer concisely.");
+            }
+            _ => panic!("Expected System message"),
+        }
+        assert!(matches!(
+            &mistral_request.messages[2],
+            mistral::RequestMessage::System { content } if content == "The user is in Paris."
+        ));
+    }
+
+    #[test]
+    fn test_into_mistral_default_system_prompt() {
+        let message = |role, text: &str| LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        };
+        let settings = MistralRequestSettings {
+            default_system_prompt: Some("Follow the team's coding standards.".into()),
+            ..Default::default()
+        };
+
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::User, "Hello"),
+            ],
+            ..Default::default()
+        };
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &settings,
+        );
+        assert_eq!(mistral_request.messages.len(), 2);
+        assert!(matches!(
+            &mistral_request.messages[0],
+            mistral::RequestMessage::System { content }
+                if content == "Follow the team's coding standards.\nYou are a helpful assistant."
+        ));
+
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "Hello")],
+            ..Default::default()
+        };
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &settings,
+        );
+        assert_eq!(mistral_request.messages.len(), 2);
+        assert!(matches!(
+            &mistral_request.messages[0],
+            mistral::RequestMessage::System { content }
+                if content == "Follow the team's coding standards."
+        ));
+    }
+
+    #[test]
+    fn test_into_mistral_prompt_cache() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("Here is a large file".into())],
+                    cache: true,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("Summarize it".into())],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+        let model = |prompt_cache| {
+            let mut model = custom_model("mistral-cache");
+            if let mistral::Model::Custom {
+                supports_prompt_cache,
+                ..
+            } = &mut model
+            {
+                *supports_prompt_cache = prompt_cache;
+            }
+            model
+        };
+
+        let mistral_request = into_mistral(
+            request.clone(),
+            &model(Some(true)),
+            None,
+            &Default::default(),
+        );
+        assert_eq!(
+            mistral_request.messages[0],
+            mistral::RequestMessage::User {
+                content: mistral::MessageContent::Multipart {
+                    content: vec![mistral::MessagePart::Text {
+                        text: "Here is a large file".into(),
+                        cache_control: Some(mistral::CacheControl::Ephemeral),
+                    }],
+                },
+            }
+        );
+        assert_eq!(
+            mistral_request.messages[1],
+            mistral::RequestMessage::User {
+                content: mistral::MessageContent::Plain {
+                    content: "Summarize it".into(),
+                },
+            }
+        );
+
+        let mistral_request = into_mistral(request, &model(None), None, &Default::default());
+        assert!(
+            !serde_json::to_string(&mistral_request)
+                .unwrap()
+                .contains("cache_control")
+        );
+    }
+
+    #[test]
+    fn test_event_mapper_collect_stream() {
+        let usage = || mistral::Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+            prompt_tokens_details: None,
+        };
+        let chunks = vec![
+            Ok(stream_response(Some("Hello"), None, None)),
+            Ok(stream_response(Some(", "), None, None)),
+            Ok(stream_response(Some("world!"), None, None)),
+            Ok(stream_response(None, Some("stop"), Some(usage()))),
+        ];
+        let messages = futures::executor::block_on(
+            MistralEventMapper::new(&mistral::Model::CodestralLatest)
+                .collect_stream(Box::pin(futures::stream::iter(chunks)))
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].as_ref().unwrap(),
+            &MistralMessage {
+                message_id: Some("response-id".into()),
+                text: "Hello, world!".into(),
+                thinking: String::new(),
+                tool_uses: Vec::new(),
+                usage: Some(token_usage(&usage())),
+                stop_reason: Some(StopReason::EndTurn),
+            }
+        );
+    }
+
+    #[test]
+    fn test_broadcast_stream() {
+        let events = || {
+            let chunks = (0..20)
+                .map(|ix| Ok(stream_response(Some(&format!("{ix} ")), None, None)))
+                .chain([Err(anyhow!("connection closed"))])
+                .collect::<Vec<_>>();
+            MistralEventMapper::new(&mistral::Model::CodestralLatest)
+                .map_stream(Box::pin(futures::stream::iter(chunks)))
+        };
+        let summarize = |events: Vec<Result<_, LanguageModelCompletionError>>| {
+            events
+                .into_iter()
+                .map(|event| event.map_err(|error| error.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let expected = summarize(futures::executor::block_on(events().collect::<Vec<_>>()));
+        assert!(expected.last().unwrap().is_err());
+
+        let (forward, mut streams) = broadcast_stream(events(), 2, 1);
+        let second = streams.pop().unwrap();
+        let first = streams.pop().unwrap();
+        let ((), first, second) = futures::executor::block_on(async {
+            futures::join!(
+                forward,
+                first.collect::<Vec<_>>(),
+                second.collect::<Vec<_>>()
+            )
+        });
+        assert_eq!(summarize(first), expected);
+        assert_eq!(summarize(second), expected);
+
+        // A consumer that goes away doesn't hold back the others.
+        let (forward, mut streams) = broadcast_stream(events(), 2, 1);
+        drop(streams.pop());
+        let first = streams.pop().unwrap();
+        let ((), first) = futures::executor::block_on(async {
+            futures::join!(forward, first.collect::<Vec<_>>())
+        });
+        assert_eq!(summarize(first), expected);
+    }
+
+    #[test]
+    fn test_event_mapper_partial_usage_on_error() {
+        let map = |chunks: Vec<Result<mistral::StreamResponse>>| {
+            let events = MistralEventMapper::new(&mistral::Model::CodestralLatest)
+                .map_stream(Box::pin(futures::stream::iter(chunks)));
+            futures::executor::block_on(events.collect::<Vec<_>>())
+        };
+
+        let events = map(vec![
+            Ok(stream_response(Some("Hello, "), None, None)),
+            Ok(stream_response(Some("world!"), None, None)),
+            Err(anyhow!("connection closed")),
+        ]);
+        let usage = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(LanguageModelCompletionEvent::UsageUpdate(usage)) => Some(*usage),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usage,
+            [TokenUsage {
+                output_tokens: 4,
+                ..Default::default()
+            }]
+        );
+        assert!(events.last().unwrap().is_err());
+
+        // Usage reported by the response takes precedence over the estimate.
+        let events = map(vec![
+            Ok(stream_response(
+                Some("Hello"),
+                None,
+                Some(mistral::Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 1,
+                    total_tokens: 11,
+                    prompt_tokens_details: None,
+                }),
+            )),
+            Err(anyhow!("connection closed")),
+        ]);
+        let usage_updates = events
+            .iter()
+            .filter(|event| matches!(event, Ok(LanguageModelCompletionEvent::UsageUpdate(_))))
+            .count();
+        assert_eq!(usage_updates, 1);
+    }
+
+    #[test]
+    fn test_event_mapper_cached_tokens() {
+        let mut mapper = MistralEventMapper::new(&mistral::Model::MistralSmallLatest);
+        let usage: mistral::Usage = serde_json::from_value(serde_json::json!({
+            "prompt_tokens": 1000,
+            "completion_tokens": 50,
+            "total_tokens": 1050,
+            "prompt_tokens_details": { "cached_tokens": 800 }
+        }))
+        .unwrap();
+        let events = mapper.map_event(stream_response(None, None, Some(usage)));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Ok(LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                input_tokens: 200,
+                output_tokens: 50,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 800,
+            }))
+        )));
+    }
+
+    #[test]
+    fn test_into_mistral_tool_result_image() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                        id: "tool_1".into(),
+                        name: "screenshot".into(),
+                        raw_input: "{}".into(),
+                        input: serde_json::json!({}),
+                        is_input_complete: true,
+                    })],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                        tool_use_id: "tool_1".into(),
+                        tool_name: "screenshot".into(),
+                        is_error: false,
+                        content: LanguageModelToolResultContent::Image(LanguageModelImage {
+                            source: "base64data".into(),
+                            size: size(DevicePixels(64), DevicePixels(64)),
+                        }),
+                        output: None,
+                    })],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request.clone(),
+            &mistral::Model::Pixtral12BLatest,
+            None,
+            &Default::default(),
+        );
+        match &mistral_request.messages[1] {
+            mistral::RequestMessage::Tool {
+                content: mistral::MessageContent::Multipart { content },
+                tool_call_id,
+            } => {
+                assert_eq!(tool_call_id, "tool_1");
+                assert_eq!(
+                    content,
+                    &[mistral::MessagePart::ImageUrl {
+                        image_url: "data:image/png;base64,base64data".into(),
+                        cache_control: None,
+                    }]
+                );
+            }
+            _ => panic!("Expected multipart Tool message"),
+        }
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::CodestralLatest,
+            None,
+            &Default::default(),
+        );
+        assert!(matches!(
+            &mistral_request.messages[1],
+            mistral::RequestMessage::Tool {
+                content: mistral::MessageContent::Plain { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_into_mistral_truncates_tool_results() {
+        let output = "word ".repeat(100);
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                        id: "tool_1".into(),
+                        name: "read_file".into(),
+                        raw_input: "{}".into(),
+                        input: serde_json::json!({}),
+                        is_input_complete: true,
+                    })],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                        tool_use_id: "tool_1".into(),
+                        tool_name: "read_file".into(),
+                        is_error: false,
+                        content: LanguageModelToolResultContent::Text(output.clone().into()),
+                        output: None,
+                    })],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+        let tool_result = |max_tool_result_tokens| {
+            let settings = MistralRequestSettings {
+                max_tool_result_tokens,
+                ..Default::default()
+            };
+            let mistral_request = into_mistral(
+                request.clone(),
+                &mistral::Model::MistralSmallLatest,
+                None,
+                &settings,
+            );
+            match &mistral_request.messages[1] {
+                mistral::RequestMessage::Tool {
+                    content: mistral::MessageContent::Plain { content },
+                    ..
+                } => content.clone(),
+                message => panic!("Expected a plain Tool message, got {message:?}"),
+            }
+        };
+
+        assert_eq!(tool_result(None), output);
+        assert_eq!(tool_result(Some(1000)), output);
+
+        // 9 tokens is about 36 characters, which ends mid-word.
+        let truncated = tool_result(Some(9));
+        assert_eq!(
+            truncated,
+            format!("{}\n[truncated]", "word ".repeat(7).trim_end())
+        );
+    }
+
+    #[test]
+    fn test_scale_down_images() {
+        // A 4x2 PNG.
+        let image = LanguageModelImage {
+            source: "iVBORw0KGgoAAAANSUhEUgAAAAQAAAACCAYAAAB/qH1jAAAAEklEQVR4nGP4z8DwHxkzoAsAAA8hD/EEN8afAAAAAElFTkSuQmCC".into(),
+            size: size(DevicePixels(4), DevicePixels(2)),
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![
+                    MessageContent::Image(image.clone()),
+                    MessageContent::ToolResult(LanguageModelToolResult {
+                        tool_use_id: "call-1".into(),
+                        tool_name: "screenshot".into(),
+                        is_error: false,
+                        content: LanguageModelToolResultContent::Image(image.clone()),
+                        output: None,
+                    }),
+                ],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let image_sizes = |max_dimension| {
+            let mut request = request.clone();
+            scale_down_images(&mut request, max_dimension);
+            request.messages[0]
+                .content
+                .iter()
+                .map(|content| match content {
+                    MessageContent::Image(image)
+                    | MessageContent::ToolResult(LanguageModelToolResult {
+                        content: LanguageModelToolResultContent::Image(image),
+                        ..
+                    }) => image.size,
+                    content => panic!("Expected an image, got {content:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(image_sizes(4), [size(DevicePixels(4), DevicePixels(2)); 2]);
+        assert_eq!(image_sizes(2), [size(DevicePixels(2), DevicePixels(1)); 2]);
+    }
+
+    #[test]
+    fn test_into_mistral_image_detail() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Image(LanguageModelImage {
+                    source: "base64data".into(),
+                    size: size(DevicePixels(64), DevicePixels(64)),
+                })],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let image_url = |image_detail| {
+            let settings = MistralRequestSettings {
+                image_detail,
+                ..Default::default()
+            };
+            let mistral_request = into_mistral(
+                request.clone(),
+                &mistral::Model::Pixtral12BLatest,
+                None,
+                &settings,
+            );
+            serde_json::to_value(&mistral_request).unwrap()["messages"][0]["content"][0]
+                ["image_url"]
+                .clone()
+        };
+
+        assert_eq!(
+            image_url(None),
+            serde_json::json!({ "url": "data:image/png;base64,base64data" })
+        );
+        assert_eq!(
+            image_url(Some(mistral::ImageDetail::Low)),
+            serde_json::json!({ "url": "data:image/png;base64,base64data", "detail": "low" })
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_metadata() {
+        let request = LanguageModelRequest {
+            thread_id: Some("thread-1".into()),
+            prompt_id: Some("prompt-1".into()),
+            intent: Some(CompletionIntent::InlineAssist),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Secret plans".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let metadata = |send_metadata| {
+            let settings = MistralRequestSettings {
+                send_metadata,
+                metadata: BTreeMap::from_iter([("feature".into(), "inline_assist".into())]),
+                ..Default::default()
+            };
+            into_mistral(
+                request.clone(),
+                &mistral::Model::MistralSmallLatest,
+                None,
+                &settings,
+            )
+            .metadata
+        };
+
+        assert!(metadata(false).is_empty());
+        let metadata = metadata(true);
+        assert_eq!(
+            metadata,
+            BTreeMap::from_iter([
+                ("feature".to_string(), "inline_assist".to_string()),
+                ("intent".to_string(), "inline_assist".to_string()),
+                ("prompt_id".to_string(), "prompt-1".to_string()),
+                ("thread_id".to_string(), "thread-1".to_string()),
+            ])
+        );
+
+        let http_client = FakeHttpClient::create(|mut request| async move {
+            let mut body = String::new();
+            request.body_mut().read_to_string(&mut body).await?;
+            let header = request
+                .headers()
+                .get(mistral::METADATA_HEADER)
+                .map(|value| value.to_str().unwrap().to_string());
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(AsyncBody::from(format!(
+                    "{};{body}",
+                    header.unwrap_or_default()
+                )))
+                .unwrap())
+        });
+        let mut mistral_request = into_mistral(
+            request,
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &MistralRequestSettings::default(),
+        );
+        mistral_request.metadata = metadata;
+        let Err(error) = futures::executor::block_on(mistral::stream_completion(
+            http_client.as_ref(),
+            &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+            mistral_request,
+        )) else {
+            panic!("expected the request to fail");
+        };
+        let message = error.downcast::<mistral::ApiError>().unwrap().message;
+        let (header, body) = message.split_once(';').unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(header).unwrap(),
+            serde_json::json!({
+                "feature": "inline_assist",
+                "intent": "inline_assist",
+                "prompt_id": "prompt-1",
+                "thread_id": "thread-1",
+            })
+        );
+        assert!(!body.contains("thread-1"));
+    }
+
+    #[test]
+    fn test_into_mistral_openai_compat() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![
+                        MessageContent::Text("What's in this image?".into()),
+                        MessageContent::Image(LanguageModelImage {
+                            source: "base64data".into(),
+                            size: size(DevicePixels(64), DevicePixels(64)),
+                        }),
+                        MessageContent::Text("Describe it briefly.".into()),
+                    ],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::Text("It shows".into())],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+        let messages = |openai_compat| {
+            let settings = MistralRequestSettings {
+                openai_compat,
+                ..Default::default()
+            };
+            let mistral_request = into_mistral(
+                request.clone(),
+                &mistral::Model::Pixtral12BLatest,
+                None,
+                &settings,
+            );
+            serde_json::to_value(&mistral_request).unwrap()["messages"].clone()
+        };
+
+        let mistral_messages = messages(false);
+        assert!(mistral_messages[0]["content"].is_array());
+        assert_eq!(mistral_messages[1]["prefix"], true);
+
+        assert_eq!(
+            messages(true),
+            serde_json::json!([
+                { "role": "user", "content": "What's in this image?\nDescribe it briefly." },
+                { "role": "assistant", "content": "It shows" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_tool_result_text_and_image() {
+        let tool_result = |content| {
+            MessageContent::ToolResult(LanguageModelToolResult {
+                tool_use_id: "tool_1".into(),
+                tool_name: "screenshot".into(),
+                is_error: false,
+                content,
+                output: None,
+            })
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                        id: "tool_1".into(),
+                        name: "screenshot".into(),
+                        raw_input: "{}".into(),
+                        input: serde_json::json!({}),
+                        is_input_complete: true,
+                    })],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![
+                        tool_result(LanguageModelToolResultContent::Text(
+                            "Captured the window:".into(),
+                        )),
+                        tool_result(LanguageModelToolResultContent::Image(LanguageModelImage {
+                            source: "base64data".into(),
+                            size: size(DevicePixels(64), DevicePixels(64)),
+                        })),
+                    ],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mistral_request = into_mistral(
+            request,
+            &mistral::Model::Pixtral12BLatest,
+            None,
+            &Default::default(),
+        );
+        assert_eq!(mistral_request.messages.len(), 2);
+        assert_eq!(
+            mistral_request.messages[1],
+            mistral::RequestMessage::Tool {
+                content: mistral::MessageContent::Multipart {
+                    content: vec![
+                        mistral::MessagePart::Text {
+                            text: "Captured the window:".into(),
+                            cache_control: None,
+                        },
+                        mistral::MessagePart::ImageUrl {
+                            image_url: "data:image/png;base64,base64data".into(),
+                            cache_control: None,
+                        },
+                    ],
+                },
+                tool_call_id: "tool_1".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_fix_message_order() {
+        let tool_call = |id: &str| mistral::ToolCall {
+            id: id.into(),
+            content: mistral::ToolCallContent::Function {
+                function: mistral::FunctionContent {
+                    name: "search".into(),
+                    arguments: "{}".into(),
+                },
+            },
+        };
+        let assistant = |content: Option<&str>, tool_calls: Vec<mistral::ToolCall>| {
+            mistral::RequestMessage::Assistant {
+                content: content.map(Into::into),
+                tool_calls,
+                prefix: false,
+            }
+        };
+        let user = |text: &str| mistral::RequestMessage::User {
+            content: mistral::MessageContent::Plain {
+                content: text.into(),
+            },
+        };
+        let tool = |id: &str| mistral::RequestMessage::Tool {
+            content: mistral::MessageContent::Plain {
+                content: format!("result of {id}"),
+            },
+            tool_call_id: id.into(),
+        };
+
+        // Content alongside tool calls is split into its own assistant message.
+        assert_eq!(
+            fix_message_order(vec![
+                user("find it"),
+                assistant(Some("Searching"), vec![tool_call("a")]),
+                tool("a"),
+            ]),
+            vec![
+                user("find it"),
+                assistant(Some("Searching"), vec![]),
+                assistant(None, vec![tool_call("a")]),
+                tool("a"),
+            ]
+        );
+
+        // Tool calls without a result are dropped, along with the then-empty message.
+        assert_eq!(
+            fix_message_order(vec![
+                user("find it"),
+                assistant(None, vec![tool_call("a"), tool_call("b")]),
+                tool("b"),
+                assistant(None, vec![tool_call("c")]),
+                user("never mind"),
+            ]),
+            vec![
+                user("find it"),
+                assistant(None, vec![tool_call("b")]),
+                tool("b"),
+                assistant(Some(" "), vec![]),
+                user("never mind"),
+            ]
+        );
+
+        // Results without a matching call are sent as user messages.
+        let mut orphan = fix_message_order(vec![user("find it"), tool("z")]);
+        assert_eq!(orphan.len(), 2);
+        assert!(matches!(
+            orphan.pop(),
+            Some(mistral::RequestMessage::User {
+                content: mistral::MessageContent::Plain { content },
+            }) if content == "result of z"
+        ));
+
+        // Results are moved directly after their call, in call order.
+        assert_eq!(
+            fix_message_order(vec![
+                assistant(None, vec![tool_call("a"), tool_call("b")]),
+                user("hurry up"),
+                tool("b"),
+                tool("a"),
+            ]),
+            vec![
+                assistant(None, vec![tool_call("a"), tool_call("b")]),
+                tool("a"),
+                tool("b"),
+                assistant(Some(" "), vec![]),
+                user("hurry up"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discovered_model() {
+        let card = |id: &str, aliases: &[&str], chat: bool| mistral::ModelCard {
+            id: id.into(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            max_context_length: Some(128000),
+            capabilities: mistral::ModelCardCapabilities {
+                completion_chat: chat,
+                function_calling: true,
+                ..Default::default()
+            },
+        };
+
+        let mut expected = custom_model("mistral-new-2509");
+        if let mistral::Model::Custom {
+            max_tokens,
+            supports_tools,
+            supports_images,
+            ..
+        } = &mut expected
+        {
+            *max_tokens = 128000;
+            *supports_tools = Some(true);
+            *supports_images = Some(false);
+        }
+        assert_eq!(
+            discovered_model(&card("mistral-new-2509", &[], true)),
+            Some(expected)
+        );
+        assert_eq!(
+            discovered_model(&card("mistral-small-2503", &["mistral-small-latest"], true)),
+            None
+        );
+        assert_eq!(discovered_model(&card("mistral-embed", &[], false)), None);
+    }
+
+    #[gpui::test]
+    async fn test_models_with_status(cx: &mut gpui::TestAppContext) {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(
+                    serde_json::json!({
+                        "object": "list",
+                        "data": [
+                            {
+                                "id": "codestral-2508",
+                                "capabilities": { "completion_chat": true },
+                                "aliases": ["codestral-latest"],
+                            },
+                            {
+                                "id": "mistral-small-latest",
+                                "capabilities": { "completion_chat": true },
+                            },
+                        ],
+                    })
+                    .to_string(),
+                ))
+                .unwrap())
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+        });
+        let state = cx.new(|_| test_state(http_client, Some("key")));
+        let status = |model: mistral::Model, cx: &mut gpui::TestAppContext| {
+            state.read_with(cx, |state, cx| {
+                state
+                    .models_with_status(cx)
+                    .into_iter()
+                    .find(|(listed_model, _)| *listed_model == model)
+                    .map(|(_, status)| status)
+            })
+        };
+
+        assert_eq!(
+            status(mistral::Model::CodestralLatest, cx),
+            Some(ModelStatus::Unknown)
+        );
+
+        state
+            .update(cx, |state, cx| state.fetch_models(cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            status(mistral::Model::CodestralLatest, cx),
+            Some(ModelStatus::Available)
+        );
+        assert_eq!(
+            status(mistral::Model::MistralSmallLatest, cx),
+            Some(ModelStatus::Available)
+        );
+        assert_eq!(
+            status(mistral::Model::MistralLargeLatest, cx),
+            Some(ModelStatus::Unavailable)
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_random_seed() {
+        let model = mistral::Model::MistralSmallLatest;
+        let settings = MistralSettings {
+            random_seed: Some(42),
+            ..Default::default()
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hello".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        let first = into_mistral(
+            request.clone(),
+            &model,
+            None,
+            &settings.request_settings(&model),
+        );
+        let second = into_mistral(request, &model, None, &settings.request_settings(&model));
+        assert_eq!(first.random_seed, Some(42));
+        assert_eq!(
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_into_mistral_parallel_tool_calls() {
+        let model = mistral::Model::MistralSmallLatest;
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("What's the weather?".into())],
+                cache: false,
+            }],
+            tools: vec![LanguageModelRequestTool {
+                name: "get_weather".into(),
+                description: "Returns the current weather for a city".into(),
+                input_schema: serde_json::json!({ "type": "object" }),
+            }],
+            ..Default::default()
+        };
+        let body = |settings: &MistralSettings| {
+            serde_json::to_value(into_mistral(
+                request.clone(),
+                &model,
+                None,
+                &settings.request_settings(&model),
+            ))
+            .unwrap()
+        };
+
+        let mut settings = MistralSettings::default();
+        assert_eq!(body(&settings)["parallel_tool_calls"], false);
+
+        settings.parallel_tool_calls = Some(true);
+        assert_eq!(body(&settings)["parallel_tool_calls"], true);
+
+        settings.available_models.push(AvailableModel {
+            name: model.id().to_string(),
+            max_tokens: model.max_token_count(),
+            supports_tools: Some(true),
+            parallel_tool_calls: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(body(&settings)["parallel_tool_calls"], false);
+    }
+
+    #[test]
+    fn test_parse_api_url() {
+        assert_eq!(
+            parse_api_url(" https://mistral.example.com/v1/ ").unwrap(),
+            "https://mistral.example.com/v1"
+        );
+        assert_eq!(
+            parse_api_url("http://localhost:8080").unwrap(),
+            "http://localhost:8080"
+        );
+        assert!(parse_api_url("mistral.example.com").is_err());
+        assert!(parse_api_url("ftp://mistral.example.com").is_err());
+    }
+
+    #[test]
+    fn test_api_key_is_redacted() {
+        let api_key = ApiKey::from("sk-secret-1234");
+        assert!(!format!("{api_key:?}").contains("secret"));
+        assert!(!format!("{api_key}").contains("secret"));
+        assert!(!format!("{:?}", Some(api_key.clone())).contains("secret"));
+        assert_eq!(api_key.as_str(), "sk-secret-1234");
+        assert_eq!(ApiKey::from("sk-first, sk-second").first(), "sk-first");
+    }
+
+    #[test]
+    fn test_switch_api_url() {
+        let gateway_url = "https://mistral.example.com/v1";
+        let mut state = State {
+            api_keys_by_url: HashMap::from_iter([
+                (mistral::MISTRAL_API_URL.to_string(), "public-key".into()),
+                (gateway_url.to_string(), "gateway-key".into()),
+            ]),
+            ..test_state(FakeHttpClient::with_404_response(), Some("public-key"))
+        };
+
+        assert!(!state.switch_api_url(gateway_url.into()));
+        assert_eq!(
+            state.api_key.as_ref().map(ApiKey::as_str),
+            Some("gateway-key")
+        );
+        assert!(!state.switch_api_url(mistral::MISTRAL_API_URL.into()));
+        assert_eq!(
+            state.api_key.as_ref().map(ApiKey::as_str),
+            Some("public-key")
+        );
+
+        // Keys that haven't been loaded yet have to be read from the keychain.
+        assert!(state.switch_api_url("https://other.example.com/v1".into()));
+        assert_eq!(state.api_key, None);
+
+        // A key from the environment applies to every API URL.
+        state.api_key = Some("env-key".into());
+        state.api_key_from_env = true;
+        assert!(!state.switch_api_url(gateway_url.into()));
+        assert_eq!(state.api_key.as_ref().map(ApiKey::as_str), Some("env-key"));
+    }
+
+    #[test]
+    fn test_api_key_encryption() {
+        let api_key = ApiKey::from("sk-secret-key");
+        let credentials = api_key.encrypt("correct horse").unwrap();
+        assert!(!credentials.contains("sk-secret-key"));
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(&credentials)
+            .unwrap();
+        assert!(
+            !decoded
+                .windows(api_key.as_str().len())
+                .any(|window| window == api_key.as_str().as_bytes())
+        );
+
+        assert_eq!(
+            ApiKey::decrypt(&credentials, "correct horse").unwrap(),
+            api_key
+        );
+        assert!(ApiKey::decrypt(&credentials, "wrong horse").is_err());
+        assert!(ApiKey::decrypt("not credentials", "correct horse").is_err());
+        assert!(api_key.encrypt("").is_err());
+
+        // Each export uses a fresh salt and nonce.
+        assert_ne!(api_key.encrypt("correct horse").unwrap(), credentials);
+    }
+
+    #[derive(Default)]
+    struct FakeCredentialsProvider(Mutex<HashMap<String, (String, Vec<u8>)>>);
+
+    impl CredentialsProvider for FakeCredentialsProvider {
+        fn read_credentials<'a>(
+            &'a self,
+            url: &'a str,
+            _: &'a AsyncApp,
+        ) -> Pin<Box<dyn Future<Output = Result<Option<(String, Vec<u8>)>>> + 'a>> {
+            let credentials = self.0.lock().get(url).cloned();
+            Box::pin(async move { Ok(credentials) })
+        }
+
+        fn write_credentials<'a>(
+            &'a self,
+            url: &'a str,
+            username: &'a str,
+            password: &'a [u8],
+            _: &'a AsyncApp,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>> {
+            self.0
+                .lock()
+                .insert(url.to_string(), (username.to_string(), password.to_vec()));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn delete_credentials<'a>(
+            &'a self,
+            url: &'a str,
+            _: &'a AsyncApp,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>> {
+            self.0.lock().remove(url);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[gpui::test]
+    async fn test_credentials_round_trip(cx: &mut gpui::TestAppContext) {
+        let credentials_provider = Arc::new(FakeCredentialsProvider::default());
+        let api_url = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            <dyn CredentialsProvider>::set_global(credentials_provider.clone(), cx);
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .api_url
+                .clone()
+        });
+        let new_state = |api_key: Option<ApiKey>, cx: &mut gpui::TestAppContext| {
+            cx.new(|_| State {
+                api_key,
+                api_url: api_url.clone(),
+                ..test_state(FakeHttpClient::with_404_response(), None)
+            })
+        };
+
+        let exporting = new_state(Some("sk-secret-key".into()), cx);
+        let credentials = exporting
+            .read_with(cx, |state, cx| {
+                state.export_credentials("correct horse".into(), cx)
+            })
+            .await
+            .unwrap();
+        assert!(
+            new_state(None, cx)
+                .read_with(cx, |state, cx| state
+                    .export_credentials("correct horse".into(), cx))
+                .await
+                .is_err()
+        );
+
+        let importing = new_state(None, cx);
+        let import = |passphrase: &str, cx: &mut gpui::TestAppContext| {
+            importing.update(cx, |state, cx| {
+                state.import_credentials(credentials.clone(), passphrase.into(), cx)
+            })
+        };
+        assert!(import("wrong horse", cx).await.is_err());
+        assert!(credentials_provider.0.lock().is_empty());
+
+        import("correct horse", cx).await.unwrap();
+        importing.read_with(cx, |state, _| {
+            assert_eq!(
+                state.api_key.as_ref().map(ApiKey::as_str),
+                Some("sk-secret-key")
+            );
+        });
+        assert_eq!(
+            credentials_provider.0.lock().get(&api_url),
+            Some(&(
+                mistral::DEFAULT_AUTH_SCHEME.to_string(),
+                b"sk-secret-key".to_vec()
+            ))
+        );
+    }
+
+    #[gpui::test]
+    async fn test_reload_credentials_without_saved_key(cx: &mut gpui::TestAppContext) {
+        let api_url = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            <dyn CredentialsProvider>::set_global(Arc::new(FakeCredentialsProvider::default()), cx);
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .api_url
+                .clone()
+        });
+        let http_client = FakeHttpClient::with_404_response();
+        let state = cx.new(|_| State {
+            api_url,
+            discovered_models: vec![custom_model("mistral-new-2509")],
+            ..test_state(http_client.clone(), Some("key"))
+        });
+        let provider = MistralLanguageModelProvider {
+            http_client,
+            state: state.clone(),
+            request_deduplicator: RequestDeduplicator::default(),
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let _subscription = cx.update({
+            let events = events.clone();
+            |cx| provider.subscribe(move |event, _| events.lock().push(event), cx)
+        });
+
+        // The key was removed from the keychain, so reloading it signs out.
+        let result = state
+            .update(cx, |state, cx| state.reload_credentials(cx))
+            .await;
+        assert!(matches!(
+            result,
+            Err(AuthenticateError::CredentialsNotFound)
+        ));
+        cx.run_until_parked();
+
+        assert_eq!(*events.lock(), [MistralEvent::Deauthenticated]);
+        state.read_with(cx, |state, _| {
+            assert!(!state.is_authenticated());
+            assert!(state.discovered_models.is_empty());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_api_key_events(cx: &mut gpui::TestAppContext) {
+        let api_url = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            <dyn CredentialsProvider>::set_global(Arc::new(FakeCredentialsProvider::default()), cx);
+            AllLanguageModelSettings::get_global(cx)
+                .mistral
+                .api_url
+                .clone()
+        });
+        let http_client = FakeHttpClient::with_404_response();
+        let state = cx.new(|_| State {
+            api_url,
+            ..test_state(http_client.clone(), None)
+        });
+        let provider = MistralLanguageModelProvider {
+            http_client,
+            state: state.clone(),
+            request_deduplicator: RequestDeduplicator::default(),
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let _subscription = cx.update({
+            let events = events.clone();
+            |cx| provider.subscribe(move |event, _| events.lock().push(event), cx)
+        });
+
+        state
+            .update(cx, |state, cx| state.set_api_key("key".into(), cx))
+            .await
+            .unwrap();
+        state
+            .update(cx, |state, cx| state.reset_api_key(cx))
+            .await
+            .unwrap();
+        // Removing a key that isn't set is not reported.
+        state
+            .update(cx, |state, cx| state.reset_api_key(cx))
+            .await
+            .unwrap();
+        // Nor is switching to an API URL without a key while no key is set.
+        cx.update(|cx| {
+            AllLanguageModelSettings::override_global(
+                AllLanguageModelSettings {
+                    mistral: MistralSettings {
+                        api_url: "https://gateway.example.com/v1".into(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                cx,
+            );
+        });
+        state.update(cx, |state, cx| state.api_url_changed(cx));
+        cx.run_until_parked();
+
+        assert_eq!(
+            *events.lock(),
+            [MistralEvent::Authenticated, MistralEvent::Deauthenticated]
+        );
+    }
+
+    #[gpui::test]
+    fn test_capabilities(cx: &mut gpui::TestAppContext) {
+        let http_client = FakeHttpClient::with_404_response();
+        let state = cx.new(|_| test_state(http_client.clone(), None));
+
+        let capabilities = |model: mistral::Model| {
+            test_model(model, state.clone(), http_client.clone()).capabilities()
+        };
+
+        assert_eq!(
+            capabilities(mistral::Model::CodestralLatest),
+            LanguageModelCapabilities {
+                tools: true,
+                images: false,
+                thinking: false,
+                structured_output: true,
+                fim: true,
+            }
+        );
+        assert_eq!(
+            capabilities(mistral::Model::MagistralMediumLatest),
+            LanguageModelCapabilities {
+                tools: true,
+                images: false,
+                thinking: true,
+                structured_output: true,
+                fim: false,
+            }
+        );
+        assert_eq!(
+            capabilities(mistral::Model::Pixtral12BLatest),
+            LanguageModelCapabilities {
+                tools: true,
+                images: true,
+                thinking: false,
+                structured_output: true,
+                fim: false,
+            }
+        );
+    }
+
+    #[gpui::test]
+    async fn test_fallback_models(cx: &mut gpui::TestAppContext) {
+        let requested_models = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let requested_models = requested_models.clone();
+            move |mut request| {
+                let requested_models = requested_models.clone();
+                async move {
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await?;
+                    let model = serde_json::from_str::<serde_json::Value>(&body)?["model"]
+                        .as_str()
+                        .unwrap()
+                        .to_string();
+                    requested_models.lock().push(model.clone());
+                    if model == "pixtral-12b-latest" {
+                        return Ok(http_client::Response::builder()
+                            .status(503)
+                            .body(AsyncBody::from(r#"{"message":"Service unavailable"}"#))
+                            .unwrap());
+                    }
+                    let chunk = stream_response(Some(&model), Some("stop"), None);
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            serde_json::to_string(&chunk).unwrap()
+                        )))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            AllLanguageModelSettings::override_global(
+                AllLanguageModelSettings {
+                    mistral: MistralSettings {
+                        fallback_models: vec![
+                            "codestral-latest".into(),
+                            "mistral-small-latest".into(),
+                        ],
+                        max_retry_attempts: Some(1),
+                        stream: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                cx,
+            );
+        });
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key")));
+        let model = test_model(mistral::Model::Pixtral12BLatest, state, http_client);
+        let complete = |content: Vec<MessageContent>| {
+            let request = LanguageModelRequest {
+                messages: vec![LanguageModelRequestMessage {
+                    role: Role::User,
+                    content,
+                    cache: false,
+                }],
+                ..Default::default()
+            };
+            let stream = LanguageModel::stream_completion(&model, request, &cx.to_async());
+            async move {
+                let mut text = String::new();
+                let mut events = stream.await.unwrap();
+                while let Some(event) = events.next().await {
+                    if let LanguageModelCompletionEvent::Text(chunk) = event.unwrap() {
+                        text.push_str(&chunk);
+                    }
+                }
+                text
+            }
+        };
+
+        assert_eq!(
+            complete(vec![MessageContent::Text("Hello".into())]).await,
+            "codestral-latest"
+        );
+        assert_eq!(
+            *requested_models.lock(),
+            ["pixtral-12b-latest", "codestral-latest"]
+        );
+
+        // Codestral can't see images, so it's skipped.
+        requested_models.lock().clear();
+        let image = MessageContent::Image(LanguageModelImage {
+            source: "base64data".into(),
+            size: size(DevicePixels(1), DevicePixels(1)),
+        });
+        assert_eq!(complete(vec![image]).await, "mistral-small-latest");
+        assert_eq!(
+            *requested_models.lock(),
+            ["pixtral-12b-latest", "mistral-small-latest"]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_fallback_models_with_rate_limited_keys(cx: &mut gpui::TestAppContext) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |mut request| {
+                let requests = requests.clone();
+                async move {
+                    let key = request.headers()["Authorization"]
+                        .to_str()?
+                        .trim_start_matches("Bearer ")
+                        .to_string();
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await?;
+                    let model = serde_json::from_str::<serde_json::Value>(&body)?["model"]
+                        .as_str()
+                        .unwrap()
+                        .to_string();
+                    requests.lock().push((model.clone(), key));
+                    if model == "pixtral-12b-latest" {
+                        return Ok(http_client::Response::builder()
+                            .status(429)
+                            .body(AsyncBody::from(r#"{"message":"Rate limit exceeded"}"#))
+                            .unwrap());
+                    }
+                    let chunk = stream_response(Some("Hello"), Some("stop"), None);
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            serde_json::to_string(&chunk).unwrap()
+                        )))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            AllLanguageModelSettings::override_global(
+                AllLanguageModelSettings {
+                    mistral: MistralSettings {
+                        fallback_models: vec!["mistral-small-latest".into()],
+                        max_retry_attempts: Some(1),
+                        stream: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                cx,
+            );
+        });
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key-a, key-b")));
+        let model = test_model(mistral::Model::Pixtral12BLatest, state, http_client);
+
+        let events = LanguageModel::stream_completion(
+            &model,
+            LanguageModelRequest::default(),
+            &cx.to_async(),
+        )
+        .await
+        .unwrap()
+        .collect::<Vec<_>>()
+        .await;
+        assert!(events.iter().all(Result::is_ok));
+        // Every key is tried before falling back to another model.
+        assert_eq!(
+            *requests.lock(),
+            [
+                ("pixtral-12b-latest".to_string(), "key-a".to_string()),
+                ("pixtral-12b-latest".to_string(), "key-b".to_string()),
+                ("mistral-small-latest".to_string(), "key-a".to_string()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_choices(cx: &mut gpui::TestAppContext) {
+        let sent_body = Arc::new(Mutex::new(serde_json::Value::Null));
+        let http_client = FakeHttpClient::create({
+            let sent_body = sent_body.clone();
+            move |mut request| {
+                let sent_body = sent_body.clone();
+                async move {
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await?;
+                    *sent_body.lock() = serde_json::from_str(&body)?;
+                    let mut chunk = stream_response(Some("First"), Some("stop"), None);
+                    let mut second_choice = chunk.choices[0].clone();
+                    second_choice.index = 1;
+                    second_choice.delta.content = Some("Second".into());
+                    chunk.choices.push(second_choice);
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            serde_json::to_string(&chunk).unwrap()
+                        )))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+        });
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key")));
+        let model = test_model(mistral::Model::MistralSmallLatest, state, http_client);
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Summarize this report".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        let events = model
+            .stream_choices(
+                request,
+                |settings| {
+                    settings.n = Some(2);
+                    settings.file_ids = vec!["file-1".into()];
+                },
+                &cx.to_async(),
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let sent_body = sent_body.lock().clone();
+        assert_eq!(sent_body["n"], 2);
+        assert_eq!(
+            sent_body["messages"][0]["content"][1],
+            serde_json::json!({ "type": "file", "file_id": "file-1" })
+        );
+        let texts = events
+            .iter()
+            .filter_map(|(index, event)| match event {
+                Ok(LanguageModelCompletionEvent::Text(text)) => Some((*index, text.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(texts, [(0, "First"), (1, "Second")]);
     }
 
-    fn should_render_editor(&self, cx: &mut Context<Self>) -> bool {
-        !self.state.read(cx).is_authenticated()
+    #[gpui::test]
+    async fn test_fake_provider(cx: &mut gpui::TestAppContext) {
+        let provider = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            MistralLanguageModelProvider::fake(
+                vec![vec![
+                    stream_response(Some("Hello"), None, None),
+                    stream_response(Some(", world!"), Some("stop"), None),
+                ]],
+                cx,
+            )
+        });
+        let model = cx.update(|cx| provider.default_model(cx)).unwrap();
+
+        let events = model
+            .stream_completion(LanguageModelRequest::default(), &cx.to_async())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let text = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(LanguageModelCompletionEvent::Text(text)) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<String>();
+        assert_eq!(text, "Hello, world!");
+        assert!(matches!(
+            events.last(),
+            Some(Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)))
+        ));
+
+        // The script is used up, so further requests fail.
+        assert!(
+            model
+                .stream_completion(LanguageModelRequest::default(), &cx.to_async())
+                .await
+                .is_err()
+        );
     }
-}
 
-impl Render for ConfigurationView {
-    fn render(&mut self, _: &mut Window, cx: &mut Context<Self>) -> impl IntoElement {
-        let env_var_set = self.state.read(cx).api_key_from_env;
+    #[gpui::test]
+    async fn test_usage_sink(cx: &mut gpui::TestAppContext) {
+        let usage = mistral::Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            total_tokens: 120,
+            prompt_tokens_details: Some(mistral::PromptTokensDetails { cached_tokens: 60 }),
+        };
+        let provider = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            MistralLanguageModelProvider::fake(
+                vec![vec![
+                    stream_response(Some("Hello"), None, None),
+                    stream_response(None, Some("stop"), Some(usage)),
+                ]],
+                cx,
+            )
+        });
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        cx.update(|cx| {
+            let reports = reports.clone();
+            provider.set_usage_sink(
+                Some(Arc::new(move |report: &UsageReport| {
+                    reports.lock().push(report.clone())
+                })),
+                cx,
+            );
+        });
+        let model = cx.update(|cx| provider.default_model(cx)).unwrap();
 
-        if self.load_credentials_task.is_some() {
-            div().child(Label::new("Loading credentials...")).into_any()
-        } else if self.should_render_editor(cx) {
-            v_flex()
-                .size_full()
-                .on_action(cx.listener(Self::save_api_key))
-                .child(Label::new("To use Zed's assistant with Mistral, you need to add an API key. Follow these steps:"))
-                .child(
-                    List::new()
-                        .child(InstructionListItem::new(
-                            "Create one by visiting",
-                            Some("Mistral's console"),
-                            Some("https://console.mistral.ai/api-keys"),
-                        ))
-                        .child(InstructionListItem::text_only(
-                            "Ensure your Mistral account has credits",
-                        ))
-                        .child(InstructionListItem::text_only(
-                            "Paste your API key below and hit enter to start using the assistant",
-                        )),
-                )
-                .child(
-                    h_flex()
-                        .w_full()
-                        .my_2()
-                        .px_2()
-                        .py_1()
-                        .bg(cx.theme().colors().editor_background)
-                        .border_1()
-                        .border_color(cx.theme().colors().border)
-                        .rounded_sm()
-                        .child(self.render_api_key_editor(cx)),
-                )
-                .child(
-                    Label::new(
-                        format!("You can also assign the {MISTRAL_API_KEY_VAR} environment variable and restart Zed."),
-                    )
-                    .size(LabelSize::Small).color(Color::Muted),
-                )
-                .into_any()
-        } else {
-            h_flex()
-                .mt_1()
-                .p_1()
-                .justify_between()
-                .rounded_md()
-                .border_1()
-                .border_color(cx.theme().colors().border)
-                .bg(cx.theme().colors().background)
-                .child(
-                    h_flex()
-                        .gap_1()
-                        .child(Icon::new(IconName::Check).color(Color::Success))
-                        .child(Label::new(if env_var_set {
-                            format!("API key set in {MISTRAL_API_KEY_VAR} environment variable.")
-                        } else {
-                            "API key configured.".to_string()
-                        })),
-                )
-                .child(
-                    Button::new("reset-key", "Reset Key")
-                        .label_size(LabelSize::Small)
-                        .icon(Some(IconName::Trash))
-                        .icon_size(IconSize::Small)
-                        .icon_position(IconPosition::Start)
-                        .disabled(env_var_set)
-                        .when(env_var_set, |this| {
-                            this.tooltip(Tooltip::text(format!("To reset your API key, unset the {MISTRAL_API_KEY_VAR} environment variable.")))
-                        })
-                        .on_click(cx.listener(|this, _, window, cx| this.reset_api_key(window, cx))),
+        let start = SystemTime::now();
+        let events = model
+            .stream_completion(LanguageModelRequest::default(), &cx.to_async())
+            .await
+            .unwrap();
+        assert!(reports.lock().is_empty());
+        events.collect::<Vec<_>>().await;
+
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].model_id, "codestral-latest");
+        assert_eq!(
+            reports[0].usage,
+            TokenUsage {
+                input_tokens: 40,
+                output_tokens: 20,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 60,
+            }
+        );
+        assert!(reports[0].timestamp >= start);
+    }
+
+    #[test]
+    fn test_non_streaming_completion() {
+        let usage = mistral::Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+            prompt_tokens_details: None,
+        };
+        let client = FakeHttpClient::create(move |mut request| {
+            let usage = usage.clone();
+            async move {
+                let mut body = String::new();
+                request.body_mut().read_to_string(&mut body).await?;
+                let body = if serde_json::from_str::<serde_json::Value>(&body)?["stream"] == true {
+                    let mut tool_call = stream_response(None, None, None);
+                    tool_call.choices[0].delta.tool_calls = Some(vec![mistral::ToolCallChunk {
+                        index: 0,
+                        id: Some("call_0".into()),
+                        function: Some(mistral::FunctionChunk {
+                            name: Some("read_file".into()),
+                            arguments: Some(r#"{"path":"a.rs"}"#.into()),
+                        }),
+                    }]);
+                    [
+                        stream_response(Some("Let me check."), None, None),
+                        tool_call,
+                        stream_response(None, Some("tool_calls"), Some(usage)),
+                    ]
+                    .iter()
+                    .map(|chunk| format!("data: {}\n\n", serde_json::to_string(chunk).unwrap()))
+                    .chain(["data: [DONE]\n\n".to_string()])
+                    .collect::<String>()
+                } else {
+                    serde_json::json!({
+                        "id": "response-id",
+                        "object": "chat.completion",
+                        "created": 0,
+                        "model": "codestral-latest",
+                        "choices": [{
+                            "index": 0,
+                            "message": {
+                                "role": "assistant",
+                                "content": "Let me check.",
+                                "tool_calls": [{
+                                    "id": "call_0",
+                                    "type": "function",
+                                    "function": {
+                                        "name": "read_file",
+                                        "arguments": r#"{"path":"a.rs"}"#,
+                                    },
+                                }],
+                            },
+                            "finish_reason": "tool_calls",
+                        }],
+                        "usage": usage,
+                    })
+                    .to_string()
+                };
+                Ok(http_client::Response::builder()
+                    .status(200)
+                    .body(AsyncBody::from(body))
+                    .unwrap())
+            }
+        });
+        let events = |stream| {
+            let settings = MistralRequestSettings {
+                stream,
+                ..Default::default()
+            };
+            let request = into_mistral(
+                LanguageModelRequest::default(),
+                &mistral::Model::CodestralLatest,
+                None,
+                &settings,
+            );
+            futures::executor::block_on(async {
+                let stream = mistral::stream_completion(
+                    client.as_ref(),
+                    &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+                    request,
                 )
-                .into_any()
+                .await
+                .unwrap();
+                MistralEventMapper::new(&mistral::Model::CodestralLatest)
+                    .map_stream(stream)
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+                    .await
+            })
+        };
+
+        let streamed_events = events(true);
+        assert!(matches!(
+            streamed_events.last(),
+            Some(LanguageModelCompletionEvent::Stop(StopReason::ToolUse))
+        ));
+        assert_eq!(events(false), streamed_events);
+    }
+
+    #[gpui::test]
+    async fn test_api_key_rotation(cx: &mut gpui::TestAppContext) {
+        let used_keys = Arc::new(Mutex::new(Vec::new()));
+        let key_a_rate_limited = Arc::new(AtomicBool::new(false));
+        let http_client = FakeHttpClient::create({
+            let used_keys = used_keys.clone();
+            let key_a_rate_limited = key_a_rate_limited.clone();
+            move |request| {
+                let key = request.headers()["Authorization"]
+                    .to_str()
+                    .unwrap()
+                    .trim_start_matches("Bearer ")
+                    .to_string();
+                if request.uri().path().ends_with("/chat/completions") {
+                    used_keys.lock().push(key.clone());
+                }
+                let rate_limited = key == "key-a" && key_a_rate_limited.load(SeqCst);
+                async move {
+                    if rate_limited {
+                        return Ok(http_client::Response::builder()
+                            .status(429)
+                            .body(AsyncBody::from(r#"{"message":"Rate limit exceeded"}"#))
+                            .unwrap());
+                    }
+                    let chunk = stream_response(Some("Hello"), Some("stop"), None);
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            serde_json::to_string(&chunk).unwrap()
+                        )))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+        });
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key-a, key-b")));
+        let model = test_model(mistral::Model::MistralSmallLatest, state, http_client);
+        let complete = || {
+            let stream = LanguageModel::stream_completion(
+                &model,
+                LanguageModelRequest::default(),
+                &cx.to_async(),
+            );
+            async move { stream.await.unwrap().collect::<Vec<_>>().await }
+        };
+
+        for _ in 0..4 {
+            complete().await;
         }
+        assert_eq!(*used_keys.lock(), ["key-a", "key-b", "key-a", "key-b"]);
+
+        // A rate limited key is retried with the other one, and then avoided.
+        used_keys.lock().clear();
+        key_a_rate_limited.store(true, SeqCst);
+        for _ in 0..2 {
+            let events = complete().await;
+            assert!(events.iter().all(Result::is_ok));
+        }
+        assert_eq!(*used_keys.lock(), ["key-a", "key-b", "key-b"]);
+
+        // Setting the keys again forgets which were used and rate limited.
+        used_keys.lock().clear();
+        key_a_rate_limited.store(false, SeqCst);
+        cx.update(|cx| {
+            model.state.update(cx, |state, cx| {
+                state.update_api_key(Some("key-a, key-b".into()), false, cx)
+            })
+        });
+        complete().await;
+        assert_eq!(*used_keys.lock(), ["key-a"]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use language_model::{LanguageModelImage, LanguageModelRequestMessage, MessageContent};
+    #[gpui::test]
+    async fn test_api_key_rotation_with_zero_retry_after(cx: &mut gpui::TestAppContext) {
+        let used_keys = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let used_keys = used_keys.clone();
+            move |request| {
+                let key = request.headers()["Authorization"]
+                    .to_str()
+                    .unwrap()
+                    .trim_start_matches("Bearer ")
+                    .to_string();
+                used_keys.lock().push(key);
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(429)
+                        .header("Retry-After", "0")
+                        .body(AsyncBody::from(r#"{"message":"Rate limit exceeded"}"#))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+        });
+        let complete = |api_key: &str, cx: &mut gpui::TestAppContext| {
+            let state = cx.new(|_| test_state(http_client.clone(), Some(api_key)));
+            let model = test_model(
+                mistral::Model::MistralSmallLatest,
+                state,
+                http_client.clone(),
+            );
+            let completion = cx.spawn(async move |cx| {
+                LanguageModel::stream_completion(&model, LanguageModelRequest::default(), &cx)
+                    .await
+                    .err()
+            });
+            cx.run_until_parked();
+            completion
+        };
+
+        // A single key that's always rate limited is only tried once, however soon the API
+        // says it can be retried.
+        let error = complete("key-a", cx).await;
+        assert!(matches!(
+            error,
+            Some(LanguageModelCompletionError::RateLimitExceeded { .. })
+        ));
+        assert_eq!(*used_keys.lock(), ["key-a"]);
+
+        used_keys.lock().clear();
+        let error = complete("key-a, key-b", cx).await;
+        assert!(error.is_some());
+        assert_eq!(*used_keys.lock(), ["key-a", "key-b"]);
+    }
 
     #[test]
-    fn test_into_mistral_basic_conversion() {
-        let request = LanguageModelRequest {
-            messages: vec![
-                LanguageModelRequestMessage {
-                    role: Role::System,
-                    content: vec![MessageContent::Text("System prompt".into())],
-                    cache: false,
-                },
-                LanguageModelRequestMessage {
-                    role: Role::User,
-                    content: vec![MessageContent::Text("Hello".into())],
-                    cache: false,
-                },
-            ],
-            temperature: Some(0.5),
-            tools: vec![],
-            tool_choice: None,
-            thread_id: None,
-            prompt_id: None,
-            intent: None,
-            mode: None,
-            stop: vec![],
-            thinking_allowed: true,
+    fn test_api_key_rotation_cooldown() {
+        let api_key = ApiKey::from("key-a, key-b");
+        let rate_limited = |retry_after| {
+            anyhow::Error::from(mistral::ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                String::new(),
+                retry_after,
+            ))
         };
+        let start = Instant::now();
+        let mut rotation = ApiKeyRotation::default();
 
-        let mistral_request = into_mistral(request, "mistral-small-latest".into(), None);
+        rotation.report_error("key-a", &rate_limited(Some(Duration::ZERO)), start);
+        assert_eq!(rotation.next_key(&api_key, start), "key-b");
+        assert_eq!(rotation.next_key(&api_key, start), "key-b");
+        let later = start + MIN_RATE_LIMITED_KEY_COOLDOWN;
+        assert_eq!(rotation.next_key(&api_key, later), "key-a");
 
-        assert_eq!(mistral_request.model, "mistral-small-latest");
-        assert_eq!(mistral_request.temperature, Some(0.5));
-        assert_eq!(mistral_request.messages.len(), 2);
-        assert!(mistral_request.stream);
+        rotation.report_error("key-a", &rate_limited(None), later);
+        rotation.report_error("key-b", &rate_limited(None), later);
+        assert!(!rotation.has_available_key(&api_key, later));
+        assert!(rotation.has_available_key(&api_key, later + RATE_LIMITED_KEY_COOLDOWN));
+    }
+
+    #[gpui::test]
+    async fn test_latency_sink(cx: &mut gpui::TestAppContext) {
+        let delay = Duration::from_millis(50);
+        let executor = cx.executor();
+        let http_client = FakeHttpClient::create(move |_| {
+            // The model takes a while to produce its first token.
+            let first_token = executor.timer(delay);
+            let body = [
+                stream_response(Some(""), None, None),
+                stream_response(Some("Hello"), None, None),
+                stream_response(Some(" world"), Some("stop"), None),
+            ]
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", serde_json::to_string(chunk).unwrap()))
+            .collect::<String>();
+            async move {
+                first_token.await;
+                Ok(http_client::Response::builder()
+                    .status(200)
+                    .body(AsyncBody::from(body))
+                    .unwrap())
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+        });
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let state = cx.new(|_| State {
+            latency_sink: Some(Arc::new({
+                let reports = reports.clone();
+                move |report: &LatencyReport| reports.lock().push(report.clone())
+            })),
+            ..test_state(http_client.clone(), Some("key"))
+        });
+        let model = test_model(mistral::Model::CodestralLatest, state, http_client);
+
+        let completion = cx.spawn(async move |cx| {
+            LanguageModel::stream_completion(&model, LanguageModelRequest::default(), &cx)
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+        });
+        cx.run_until_parked();
+        cx.executor().advance_clock(delay);
+        completion.await;
+
+        let reports = reports.lock();
+        assert_eq!(reports.len(), 1, "only the first token is timed");
+        assert_eq!(reports[0].model_id, "codestral-latest");
+        assert_eq!(reports[0].first_token_latency, delay);
+    }
+
+    #[gpui::test]
+    async fn test_custom_auth_scheme(cx: &mut gpui::TestAppContext) {
+        let authorization = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let authorization = authorization.clone();
+            move |request| {
+                authorization.lock().push(
+                    request
+                        .headers()
+                        .get("Authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(ToString::to_string),
+                );
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from("data: [DONE]\n\n"))
+                        .unwrap())
+                }
+            }
+        });
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            crate::settings::init_settings(cx);
+            AllLanguageModelSettings::override_global(
+                AllLanguageModelSettings {
+                    mistral: MistralSettings {
+                        api_url: mistral::MISTRAL_API_URL.into(),
+                        auth_scheme: Some("Token".into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                cx,
+            );
+        });
+        let state = cx.new(|_| test_state(http_client.clone(), Some("key")));
+        let model = test_model(mistral::Model::CodestralLatest, state, http_client);
+
+        LanguageModel::stream_completion(&model, LanguageModelRequest::default(), &cx.to_async())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        model
+            .stream_fim_completion("fn main() {".into(), None, Vec::new(), &cx.to_async())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            *authorization.lock(),
+            vec![Some("Token key".to_string()), Some("Token key".to_string())]
+        );
     }
 
     #[test]
-    fn test_into_mistral_with_image() {
-        let request = LanguageModelRequest {
-            messages: vec![LanguageModelRequestMessage {
-                role: Role::User,
-                content: vec![
-                    MessageContent::Text("What's in this image?".into()),
-                    MessageContent::Image(LanguageModelImage {
-                        source: "base64data".into(),
-                        size: Default::default(),
-                    }),
-                ],
-                cache: false,
-            }],
-            tools: vec![],
-            tool_choice: None,
-            temperature: None,
-            thread_id: None,
-            prompt_id: None,
-            intent: None,
-            mode: None,
-            stop: vec![],
-            thinking_allowed: true,
+    fn test_has_output() {
+        assert!(!has_output(&stream_response(None, None, None)));
+        assert!(!has_output(&stream_response(Some(""), Some("stop"), None)));
+        assert!(has_output(&stream_response(Some("Hi"), None, None)));
+
+        let mut tool_call = stream_response(None, None, None);
+        tool_call.choices[0].delta.tool_calls = Some(vec![mistral::ToolCallChunk {
+            index: 0,
+            id: Some("call_0".into()),
+            function: None,
+        }]);
+        assert!(has_output(&tool_call));
+    }
+
+    #[test]
+    fn test_check_health() {
+        let http_client = FakeHttpClient::create(|request| async move {
+            let authorized = request
+                .headers()
+                .get("Authorization")
+                .is_some_and(|value| value == "Bearer valid");
+            Ok(http_client::Response::builder()
+                .status(if authorized { 200 } else { 401 })
+                .body(AsyncBody::from(if authorized {
+                    r#"{"object":"list","data":[]}"#
+                } else {
+                    r#"{"message":"Unauthorized"}"#
+                }))
+                .unwrap())
+        });
+        let validate = |api_key: &str| {
+            futures::executor::block_on(check_health(
+                http_client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, api_key),
+            ))
         };
+        assert_eq!(validate("valid"), Ok(()));
+        assert_eq!(validate("typo"), Err(HealthCheckError::Unauthorized));
 
-        let mistral_request = into_mistral(request, "pixtral-12b-latest".into(), None);
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(503)
+                .body(AsyncBody::from(r#"{"message":"Service unavailable"}"#))
+                .unwrap())
+        });
+        assert_eq!(
+            futures::executor::block_on(check_health(
+                http_client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, "valid"),
+            )),
+            Err(HealthCheckError::Api(StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert_eq!(
+            HealthCheckError::Api(StatusCode::SERVICE_UNAVAILABLE).to_string(),
+            "Mistral returned an error while validating the API key: 503 Service Unavailable"
+        );
 
-        assert_eq!(mistral_request.messages.len(), 1);
+        let http_client =
+            FakeHttpClient::create(|_| async move { Err(anyhow!("connection refused")) });
         assert!(matches!(
-            &mistral_request.messages[0],
-            mistral::RequestMessage::User {
-                content: mistral::MessageContent::Multipart { .. }
+            futures::executor::block_on(check_health(
+                http_client.as_ref(),
+                &mistral::Connection::new(mistral::MISTRAL_API_URL, "valid"),
+            )),
+            Err(HealthCheckError::Network(_))
+        ));
+
+        // Only a rejected key is refused; other errors leave its validity unknown.
+        assert!(HealthCheckError::Unauthorized.rejects_api_key());
+        assert!(!HealthCheckError::Api(StatusCode::SERVICE_UNAVAILABLE).rejects_api_key());
+        assert!(!HealthCheckError::Network("connection refused".into()).rejects_api_key());
+    }
+
+    #[test]
+    fn test_api_error_to_completion_error() {
+        let error = |status: u16, body: &str| {
+            api_error_to_completion_error(mistral::ApiError::new(
+                StatusCode::from_u16(status).unwrap(),
+                body.to_string(),
+                None,
+            ))
+        };
+
+        assert!(matches!(
+            error(401, r#"{"message":"Unauthorized","request_id":"abc123"}"#),
+            LanguageModelCompletionError::AuthenticationError { message, .. }
+                if message == "Unauthorized"
+        ));
+        assert!(matches!(
+            error(429, r#"{"message":"Requests rate limit exceeded"}"#),
+            LanguageModelCompletionError::RateLimitExceeded { .. }
+        ));
+        assert!(matches!(
+            error(
+                400,
+                r#"{"object":"error","message":"Invalid model: mistral-huge","type":"invalid_model","param":null,"code":"1500"}"#
+            ),
+            LanguageModelCompletionError::BadRequestFormat { message, .. }
+                if message == "Invalid model: mistral-huge"
+        ));
+        assert!(matches!(
+            error(
+                422,
+                r#"{"object":"error","message":{"detail":[{"type":"missing","loc":["body","messages"],"msg":"Field required"}]},"type":"invalid_request_message_error","param":null,"code":null}"#
+            ),
+            LanguageModelCompletionError::BadRequestFormat { message, .. }
+                if message.contains("Field required")
+        ));
+        assert!(matches!(
+            error(
+                400,
+                r#"{"object":"error","message":"Prompt contains 40000 tokens and 0 draft tokens, too large for model with 32768 maximum context length","type":"invalid_request_error","param":null,"code":null}"#
+            ),
+            LanguageModelCompletionError::PromptTooLarge {
+                tokens: Some(40000),
+                max_tokens: Some(32768),
             }
         ));
+        assert!(matches!(
+            error(
+                500,
+                r#"{"object":"error","message":"Internal server error","type":"internal_server_error","param":null,"code":"1000"}"#
+            ),
+            LanguageModelCompletionError::ApiInternalServerError { message, .. }
+                if message == "Internal server error"
+        ));
+        assert!(matches!(
+            error(503, "upstream connect error"),
+            LanguageModelCompletionError::ServerOverloaded { .. }
+        ));
+    }
 
-        if let mistral::RequestMessage::User {
-            content: mistral::MessageContent::Multipart { content },
-        } = &mistral_request.messages[0]
-        {
-            assert_eq!(content.len(), 2);
-            assert!(matches!(
-                &content[0],
-                mistral::MessagePart::Text { text } if text == "What's in this image?"
-            ));
-            assert!(matches!(
-                &content[1],
-                mistral::MessagePart::ImageUrl { image_url } if image_url.starts_with("data:image/png;base64,")
-            ));
+    #[test]
+    fn test_rate_limit_retry_after() {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(429)
+                .header("Retry-After", "30")
+                .body(AsyncBody::from(
+                    r#"{"object":"error","message":"Requests rate limit exceeded"}"#,
+                ))
+                .unwrap())
+        });
+        let request = into_mistral(
+            LanguageModelRequest::default(),
+            &mistral::Model::MistralSmallLatest,
+            None,
+            &Default::default(),
+        );
+
+        let Err(error) = futures::executor::block_on(mistral::stream_completion(
+            http_client.as_ref(),
+            &mistral::Connection::new(mistral::MISTRAL_API_URL, "key"),
+            request,
+        )) else {
+            panic!("expected a 429 response to fail the request");
+        };
+        match into_completion_error(error) {
+            LanguageModelCompletionError::RateLimitExceeded { retry_after, .. } => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(30)));
+            }
+            error => panic!("unexpected error: {error:?}"),
         }
     }
 }
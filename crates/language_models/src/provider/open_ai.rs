@@ -907,6 +907,11 @@ mod tests {
             tool_choice: None,
             stop: vec![],
             temperature: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_schema: None,
+            continue_last_message: false,
             thinking_allowed: true,
         };
 
@@ -1,7 +1,8 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use collections::HashMap;
+use collections::{BTreeMap, HashMap};
 use gpui::App;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -101,6 +102,107 @@ pub struct DeepseekSettingsContent {
 pub struct MistralSettingsContent {
     pub api_url: Option<String>,
     pub available_models: Option<Vec<provider::mistral::AvailableModel>>,
+    pub safe_prompt: Option<bool>,
+    pub random_seed: Option<u64>,
+    pub max_concurrent_requests: Option<usize>,
+    pub organization_id: Option<String>,
+    /// Headers added to every request, such as those required by a proxy. They're sent after
+    /// the standard headers, so they can't be overwritten by them.
+    pub extra_headers: Option<BTreeMap<String, String>>,
+    /// The IDs of models to send a completion request to, in order, when the model it was
+    /// made for is overloaded or rate limited. Models that can't handle the request, such as
+    /// those without vision for a request with images, are skipped.
+    pub fallback_models: Option<Vec<String>>,
+    pub parallel_tool_calls: Option<bool>,
+    pub min_tokens: Option<u64>,
+    /// How long to wait for the next chunk of a streamed completion, in seconds.
+    ///
+    /// Default: 60
+    pub stream_idle_timeout: Option<u64>,
+    /// How long to wait for a completion request to be answered, in seconds. When unset,
+    /// requests wait as long as the HTTP client allows. Requests that aren't streamed aren't
+    /// answered until the whole completion is generated, so they always wait.
+    pub request_timeout_secs: Option<u64>,
+    /// The most times to send a completion request that fails with a server error.
+    ///
+    /// Default: 3
+    pub max_retry_attempts: Option<u32>,
+    /// The delay before retrying a failed completion request, in milliseconds. The
+    /// delay doubles with each further retry.
+    ///
+    /// Default: 500
+    pub retry_base_delay: Option<u64>,
+    /// A system prompt sent ahead of the assistant's own in every request.
+    pub default_system_prompt: Option<String>,
+    /// Whether to log the raw chunks of streamed responses at debug level, for debugging.
+    ///
+    /// Default: false
+    pub log_raw_responses: Option<bool>,
+    /// Whether a completion request identical to one sent in the last two seconds, such as
+    /// from submitting a prompt twice, shares that request's response instead of being sent
+    /// and billed again.
+    ///
+    /// Default: false
+    pub deduplicate_requests: Option<bool>,
+    /// Whether to send the thinking of earlier assistant messages back to reasoning models,
+    /// which costs tokens but can help them stay consistent.
+    ///
+    /// Default: false
+    pub include_reasoning_in_history: Option<bool>,
+    /// The most tokens of a tool's text result to send, such as the contents of a large
+    /// file. Longer results are truncated and marked as such. When unset, tool results are
+    /// sent in full.
+    pub max_tool_result_tokens: Option<u64>,
+    /// Whether to repair common mistakes in the JSON of tool call arguments, such as
+    /// trailing commas and unescaped newlines, instead of failing the tool call.
+    ///
+    /// Default: false
+    pub lenient_json: Option<bool>,
+    /// The largest width or height, in pixels, of images to send, such as screenshots for
+    /// Pixtral. Larger images are scaled down, preserving their aspect ratio. When unset,
+    /// images are sent at full size.
+    pub max_image_dimension: Option<u32>,
+    /// How closely the model looks at images: `low` uses fewer tokens per image, while
+    /// `high` picks out finer details. When unset, Mistral decides.
+    pub image_detail: Option<mistral::ImageDetail>,
+    /// Whether to send requests in the format that OpenAI-compatible APIs accept, for
+    /// gateways that are strict about it: message content is sent as plain strings, without
+    /// images, replies aren't prefilled, and `any` tool choices are sent as `required`.
+    ///
+    /// Default: false
+    pub openai_compat: Option<bool>,
+    /// Whether to tag requests with the IDs of the thread and prompt they belong to and
+    /// their intent, such as `user_prompt` or `inline_assist`, in an `X-Request-Metadata`
+    /// header. No content of the conversation is included.
+    ///
+    /// Default: false
+    pub send_request_metadata: Option<bool>,
+    /// The temperature to use for requests that don't set one, by their intent, such as
+    /// `edit_file`, `inline_assist`, or `thread_summarization`. Intents that aren't listed
+    /// use the model's default. A temperature set by the request always takes precedence.
+    pub temperature_by_intent: Option<BTreeMap<String, f32>>,
+    /// Whether to stream completions as they're generated. Setting this to false requests
+    /// each completion as a single response, for proxies that don't support server-sent
+    /// events, though nothing is shown until the whole completion is ready.
+    ///
+    /// Default: true
+    pub stream: Option<bool>,
+    /// IDs of built-in models to hide from the model picker.
+    pub disabled_models: Option<Vec<String>>,
+    /// The ID of the model to use by default, such as `mistral-large-latest`.
+    ///
+    /// Default: codestral-latest
+    pub default_model: Option<String>,
+    /// The ID of the model to use for quick tasks, such as summarizing threads.
+    ///
+    /// Default: mistral-small-latest
+    pub default_fast_model: Option<String>,
+    /// The scheme of the `Authorization` header the API key is sent with, for gateways that
+    /// expect something other than a bearer token. The key is sent on its own when this is
+    /// empty.
+    ///
+    /// Default: Bearer
+    pub auth_scheme: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -303,6 +405,119 @@ impl settings::Settings for AllLanguageModelSettings {
                 &mut settings.mistral.available_models,
                 mistral.as_ref().and_then(|s| s.available_models.clone()),
             );
+            merge(
+                &mut settings.mistral.safe_prompt,
+                mistral.as_ref().and_then(|s| s.safe_prompt),
+            );
+            if let Some(random_seed) = mistral.as_ref().and_then(|s| s.random_seed) {
+                settings.mistral.random_seed = Some(random_seed);
+            }
+            if let Some(max_concurrent_requests) =
+                mistral.as_ref().and_then(|s| s.max_concurrent_requests)
+            {
+                settings.mistral.max_concurrent_requests = Some(max_concurrent_requests);
+            }
+            if let Some(organization_id) = mistral.as_ref().and_then(|s| s.organization_id.clone())
+            {
+                settings.mistral.organization_id = Some(organization_id);
+            }
+            if let Some(extra_headers) = mistral.as_ref().and_then(|s| s.extra_headers.clone()) {
+                settings.mistral.extra_headers = extra_headers;
+            }
+            if let Some(fallback_models) = mistral.as_ref().and_then(|s| s.fallback_models.clone())
+            {
+                settings.mistral.fallback_models = fallback_models;
+            }
+            if let Some(parallel_tool_calls) = mistral.as_ref().and_then(|s| s.parallel_tool_calls)
+            {
+                settings.mistral.parallel_tool_calls = Some(parallel_tool_calls);
+            }
+            if let Some(min_tokens) = mistral.as_ref().and_then(|s| s.min_tokens) {
+                settings.mistral.min_tokens = Some(min_tokens);
+            }
+            if let Some(stream_idle_timeout) = mistral.as_ref().and_then(|s| s.stream_idle_timeout)
+            {
+                settings.mistral.stream_idle_timeout =
+                    Some(Duration::from_secs(stream_idle_timeout));
+            }
+            if let Some(request_timeout) = mistral.as_ref().and_then(|s| s.request_timeout_secs) {
+                settings.mistral.request_timeout = Some(Duration::from_secs(request_timeout));
+            }
+            if let Some(max_retry_attempts) = mistral.as_ref().and_then(|s| s.max_retry_attempts) {
+                settings.mistral.max_retry_attempts = Some(max_retry_attempts);
+            }
+            if let Some(retry_base_delay) = mistral.as_ref().and_then(|s| s.retry_base_delay) {
+                settings.mistral.retry_base_delay = Some(Duration::from_millis(retry_base_delay));
+            }
+            if let Some(default_system_prompt) = mistral
+                .as_ref()
+                .and_then(|s| s.default_system_prompt.clone())
+            {
+                settings.mistral.default_system_prompt = Some(default_system_prompt);
+            }
+            merge(
+                &mut settings.mistral.log_raw_responses,
+                mistral.as_ref().and_then(|s| s.log_raw_responses),
+            );
+            merge(
+                &mut settings.mistral.deduplicate_requests,
+                mistral.as_ref().and_then(|s| s.deduplicate_requests),
+            );
+            merge(
+                &mut settings.mistral.include_reasoning_in_history,
+                mistral
+                    .as_ref()
+                    .and_then(|s| s.include_reasoning_in_history),
+            );
+            if let Some(max_tool_result_tokens) =
+                mistral.as_ref().and_then(|s| s.max_tool_result_tokens)
+            {
+                settings.mistral.max_tool_result_tokens = Some(max_tool_result_tokens);
+            }
+            merge(
+                &mut settings.mistral.lenient_json,
+                mistral.as_ref().and_then(|s| s.lenient_json),
+            );
+            if let Some(max_image_dimension) = mistral.as_ref().and_then(|s| s.max_image_dimension)
+            {
+                settings.mistral.max_image_dimension = Some(max_image_dimension);
+            }
+            if let Some(image_detail) = mistral.as_ref().and_then(|s| s.image_detail) {
+                settings.mistral.image_detail = Some(image_detail);
+            }
+            merge(
+                &mut settings.mistral.openai_compat,
+                mistral.as_ref().and_then(|s| s.openai_compat),
+            );
+            merge(
+                &mut settings.mistral.send_request_metadata,
+                mistral.as_ref().and_then(|s| s.send_request_metadata),
+            );
+            if let Some(temperature_by_intent) = mistral
+                .as_ref()
+                .and_then(|s| s.temperature_by_intent.clone())
+            {
+                settings.mistral.temperature_by_intent = temperature_by_intent;
+            }
+            merge(
+                &mut settings.mistral.stream,
+                mistral.as_ref().and_then(|s| s.stream),
+            );
+            if let Some(default_model) = mistral.as_ref().and_then(|s| s.default_model.clone()) {
+                settings.mistral.default_model = Some(default_model);
+            }
+            if let Some(default_fast_model) =
+                mistral.as_ref().and_then(|s| s.default_fast_model.clone())
+            {
+                settings.mistral.default_fast_model = Some(default_fast_model);
+            }
+            if let Some(auth_scheme) = mistral.as_ref().and_then(|s| s.auth_scheme.clone()) {
+                settings.mistral.auth_scheme = Some(auth_scheme);
+            }
+            merge(
+                &mut settings.mistral.disabled_models,
+                mistral.as_ref().and_then(|s| s.disabled_models.clone()),
+            );
 
             // OpenRouter
             let open_router = value.open_router.clone();
@@ -1,13 +1,80 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Context as _, Result, anyhow};
 use futures::{AsyncBufReadExt, AsyncReadExt, StreamExt, io::BufReader, stream::BoxStream};
-use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use http_client::{
+    AsyncBody, HttpClient, Method, Request as HttpRequest, StatusCode,
+    http::{HeaderMap, HeaderName, HeaderValue, request::Builder as RequestBuilder},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::convert::TryFrom;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    hash::{BuildHasher as _, RandomState},
+    time::Duration,
+};
 use strum::EnumIter;
 
 pub const MISTRAL_API_URL: &str = "https://api.mistral.ai/v1";
 
+/// Scopes a request to a workspace within an organization's account.
+pub const ORGANIZATION_HEADER: &str = "Mistral-Organization";
+/// The scheme of the `Authorization` header the API key is sent with by default.
+pub const DEFAULT_AUTH_SCHEME: &str = "Bearer";
+
+/// Carries a request's [`metadata`](Request::metadata) as a JSON object, since the chat
+/// completions endpoint has no field for it.
+pub const METADATA_HEADER: &str = "X-Request-Metadata";
+
+/// Where and how to send requests: the settings shared by every endpoint.
+#[derive(Clone)]
+pub struct Connection {
+    pub api_url: String,
+    pub api_key: String,
+    /// The scheme of the `Authorization` header, such as [`DEFAULT_AUTH_SCHEME`]. An empty
+    /// scheme sends the key alone.
+    pub auth_scheme: String,
+    /// Sent in the [`ORGANIZATION_HEADER`].
+    pub organization_id: Option<String>,
+    /// Headers sent with every request, replacing any standard header of the same name.
+    pub extra_headers: BTreeMap<String, String>,
+}
+
+impl Connection {
+    /// A connection that sends `api_key` with the default scheme and no other headers.
+    pub fn new(api_url: impl Into<String>, api_key: impl Into<String>) -> Self {
+        Self {
+            api_url: api_url.into(),
+            api_key: api_key.into(),
+            auth_scheme: DEFAULT_AUTH_SCHEME.to_string(),
+            organization_id: None,
+            extra_headers: BTreeMap::default(),
+        }
+    }
+}
+
+/// The largest file accepted by the files API, in bytes.
+pub const MAX_FILE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// The maximum number of stop sequences accepted by the chat completions endpoint.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+pub const EMBEDDING_MODEL: &str = "mistral-embed";
+
+/// The maximum number of inputs sent in a single embeddings request.
+pub const EMBEDDING_BATCH_SIZE: usize = 128;
+
+pub const MODERATION_MODEL: &str = "mistral-moderation-latest";
+
+pub const OCR_MODEL: &str = "mistral-ocr-latest";
+
+/// Whether a model's name marks it as one of the families that accept images: Pixtral, and
+/// models with `vision` in their name.
+fn is_vision_model_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("pixtral") || name.contains("vision")
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
@@ -82,13 +149,46 @@ pub enum Model {
         /// The name displayed in the UI, such as in the assistant panel model dropdown menu.
         display_name: Option<String>,
         max_tokens: u64,
+        /// The most tokens the model can generate in a response.
         max_output_tokens: Option<u64>,
+        /// The most tokens to let a single completion generate, which may be lower than
+        /// `max_output_tokens` to keep responses short or cheap.
         max_completion_tokens: Option<u64>,
         supports_tools: Option<bool>,
+        /// Whether the model accepts images. When unset, it's inferred from the name.
         supports_images: Option<bool>,
+        supports_structured_output: Option<bool>,
+        supports_thinking: Option<bool>,
+        supports_prompt_cache: Option<bool>,
+        /// The highest temperature to sample with. Higher requested temperatures are clamped.
+        max_temperature: Option<f32>,
+        supports_prediction: Option<bool>,
+        price_per_token: Option<TokenPrice>,
+        /// When the model's training data ends, such as `2024-10`.
+        knowledge_cutoff: Option<String>,
+        /// A short summary of what the model is good at, shown in the UI.
+        description: Option<String>,
+        /// Sequences that end the model's output, such as the end of an answer block for a
+        /// self-hosted reasoning model.
+        default_stop_sequences: Option<Vec<String>>,
     },
 }
 
+/// The price of a model's tokens, in US dollars per million tokens.
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TokenPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl TokenPrice {
+    /// The cost in US dollars of processing `input_tokens` and generating `output_tokens`.
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
+}
+
 impl Model {
     pub fn default_fast() -> Self {
         Model::MistralSmallLatest
@@ -150,6 +250,58 @@ impl Model {
         }
     }
 
+    /// When the model's training data ends, as a year and month such as `2024-10`.
+    pub fn knowledge_cutoff(&self) -> Option<&str> {
+        match self {
+            Self::CodestralLatest => Some("2024-10"),
+            Self::MistralLargeLatest => Some("2024-09"),
+            Self::MistralMediumLatest => Some("2025-03"),
+            Self::MistralSmallLatest => Some("2025-03"),
+            Self::MagistralMediumLatest => Some("2025-03"),
+            Self::MagistralSmallLatest => Some("2025-03"),
+            Self::OpenMistralNemo => Some("2024-04"),
+            Self::OpenCodestralMamba => Some("2024-04"),
+            Self::DevstralMediumLatest => Some("2025-04"),
+            Self::DevstralSmallLatest => Some("2025-04"),
+            Self::Pixtral12BLatest => Some("2024-06"),
+            Self::PixtralLargeLatest => Some("2024-09"),
+            Self::Custom {
+                knowledge_cutoff, ..
+            } => knowledge_cutoff.as_deref(),
+        }
+    }
+
+    /// A short summary of what the model is good at.
+    pub fn description(&self) -> Option<&str> {
+        match self {
+            Self::CodestralLatest => Some("Code generation and fill-in-the-middle completion"),
+            Self::MistralLargeLatest => Some("Mistral's largest model, for complex tasks"),
+            Self::MistralMediumLatest => Some("Balanced performance and cost, with vision"),
+            Self::MistralSmallLatest => Some("Fast and inexpensive, with vision"),
+            Self::MagistralMediumLatest => Some("Reasoning model for multi-step problems"),
+            Self::MagistralSmallLatest => Some("Small reasoning model"),
+            Self::OpenMistralNemo => Some("Open-weight multilingual model"),
+            Self::OpenCodestralMamba => Some("Open-weight code model with a Mamba architecture"),
+            Self::DevstralMediumLatest => Some("Agentic coding across large codebases"),
+            Self::DevstralSmallLatest => Some("Open-weight model for agentic coding"),
+            Self::Pixtral12BLatest => Some("Small multimodal model for image understanding"),
+            Self::PixtralLargeLatest => Some("Large multimodal model for image understanding"),
+            Self::Custom { description, .. } => description.as_deref(),
+        }
+    }
+
+    /// Stop sequences that every request to the model should include, in addition to the
+    /// caller's. Mistral's API ends the built-in models' turns itself, so they need none.
+    pub fn default_stop_sequences(&self) -> Vec<String> {
+        match self {
+            Self::Custom {
+                default_stop_sequences,
+                ..
+            } => default_stop_sequences.clone().unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
     pub fn max_token_count(&self) -> u64 {
         match self {
             Self::CodestralLatest => 256000,
@@ -177,6 +329,18 @@ impl Model {
         }
     }
 
+    /// The `max_tokens` to send with completion requests: `max_completion_tokens` when it's
+    /// set, and `max_output_tokens` otherwise.
+    pub fn max_completion_tokens(&self) -> Option<u64> {
+        match self {
+            Self::Custom {
+                max_completion_tokens,
+                ..
+            } => max_completion_tokens.or(self.max_output_tokens()),
+            _ => self.max_output_tokens(),
+        }
+    }
+
     pub fn supports_tools(&self) -> bool {
         match self {
             Self::CodestralLatest
@@ -210,22 +374,189 @@ impl Model {
             | Self::DevstralMediumLatest
             | Self::DevstralSmallLatest => false,
             Self::Custom {
-                supports_images, ..
-            } => supports_images.unwrap_or(false),
+                name,
+                supports_images,
+                ..
+            } => supports_images.unwrap_or_else(|| is_vision_model_name(name)),
+        }
+    }
+
+    /// Whether the model streams its reasoning separately from the answer, either in
+    /// `reasoning_content` or inline between `<think>` delimiters.
+    pub fn supports_thinking(&self) -> bool {
+        match self {
+            Self::MagistralMediumLatest | Self::MagistralSmallLatest => true,
+            Self::CodestralLatest
+            | Self::MistralLargeLatest
+            | Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::OpenCodestralMamba
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest => false,
+            Self::Custom {
+                supports_thinking, ..
+            } => supports_thinking.unwrap_or(false),
+        }
+    }
+
+    /// The highest temperature the model is recommended to sample with. Magistral models are
+    /// tuned for a temperature of 0.7 and become erratic above it.
+    pub fn max_temperature(&self) -> Option<f32> {
+        match self {
+            Self::MagistralMediumLatest | Self::MagistralSmallLatest => Some(0.7),
+            Self::CodestralLatest
+            | Self::MistralLargeLatest
+            | Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::OpenCodestralMamba
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest => None,
+            Self::Custom {
+                max_temperature, ..
+            } => *max_temperature,
+        }
+    }
+
+    /// Whether the model accepts a [`Prediction`] of its output.
+    pub fn supports_prediction(&self) -> bool {
+        match self {
+            Self::CodestralLatest | Self::MistralLargeLatest => true,
+            Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::MagistralMediumLatest
+            | Self::MagistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::OpenCodestralMamba
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest => false,
+            Self::Custom {
+                supports_prediction,
+                ..
+            } => supports_prediction.unwrap_or(false),
+        }
+    }
+
+    /// The list price of the model on La Plateforme. Custom models only have a price
+    /// when one is configured, since they may be self-hosted.
+    pub fn price_per_token(&self) -> Option<TokenPrice> {
+        let (input, output) = match self {
+            Self::CodestralLatest => (0.3, 0.9),
+            Self::MistralLargeLatest => (2.0, 6.0),
+            Self::MistralMediumLatest => (0.4, 2.0),
+            Self::MistralSmallLatest => (0.1, 0.3),
+            Self::MagistralMediumLatest => (2.0, 5.0),
+            Self::MagistralSmallLatest => (0.5, 1.5),
+            Self::OpenMistralNemo => (0.15, 0.15),
+            Self::OpenCodestralMamba => (0.25, 0.25),
+            Self::DevstralMediumLatest => (0.4, 2.0),
+            Self::DevstralSmallLatest => (0.1, 0.3),
+            Self::Pixtral12BLatest => (0.15, 0.15),
+            Self::PixtralLargeLatest => (2.0, 6.0),
+            Self::Custom {
+                price_per_token, ..
+            } => return *price_per_token,
+        };
+        Some(TokenPrice { input, output })
+    }
+
+    /// Whether the model can reuse a cached prefix of the prompt, marked with
+    /// [`CacheControl`] breakpoints.
+    pub fn supports_prompt_cache(&self) -> bool {
+        match self {
+            Self::CodestralLatest
+            | Self::MistralLargeLatest
+            | Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::MagistralMediumLatest
+            | Self::MagistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::OpenCodestralMamba
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest => false,
+            Self::Custom {
+                supports_prompt_cache,
+                ..
+            } => supports_prompt_cache.unwrap_or(false),
+        }
+    }
+
+    /// Whether the model can be used with the fill-in-the-middle completions endpoint.
+    pub fn supports_fim(&self) -> bool {
+        match self {
+            Self::CodestralLatest => true,
+            Self::MistralLargeLatest
+            | Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::MagistralMediumLatest
+            | Self::MagistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::OpenCodestralMamba
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest
+            | Self::Custom { .. } => false,
+        }
+    }
+
+    /// Whether the model can constrain its output to a caller-provided JSON schema.
+    /// Models without this capability can still be asked for plain JSON output.
+    pub fn supports_structured_output(&self) -> bool {
+        match self {
+            Self::CodestralLatest
+            | Self::MistralLargeLatest
+            | Self::MistralMediumLatest
+            | Self::MistralSmallLatest
+            | Self::MagistralMediumLatest
+            | Self::MagistralSmallLatest
+            | Self::OpenMistralNemo
+            | Self::DevstralMediumLatest
+            | Self::DevstralSmallLatest
+            | Self::Pixtral12BLatest
+            | Self::PixtralLargeLatest => true,
+            Self::OpenCodestralMamba => false,
+            Self::Custom {
+                supports_structured_output,
+                ..
+            } => supports_structured_output.unwrap_or(false),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Request {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub model: String,
     pub messages: Vec<RequestMessage>,
     pub stream: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u64>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    /// The number of completions to generate, streamed as separate choices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub response_format: Option<ResponseFormat>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
@@ -233,29 +564,87 @@ pub struct Request {
     pub parallel_tool_calls: Option<bool>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tools: Vec<ToolDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safe_prompt: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub random_seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_mode: Option<PromptMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prediction: Option<Prediction>,
+    /// A server-side agent to run instead of `model`. Such requests are sent to the agents
+    /// endpoint, which uses the agent's own model, instructions, and tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_id: Option<String>,
+    /// Tags for correlating the request with the feature that made it, such as a thread ID.
+    /// Sent in the [`METADATA_HEADER`] rather than the body.
+    #[serde(skip)]
+    pub metadata: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Request {
+    /// Checks for parameter combinations that the API would reject.
+    pub fn validate(&self) -> Result<()> {
+        if let Some((min_tokens, max_tokens)) = self.min_tokens.zip(self.max_tokens) {
+            anyhow::ensure!(
+                min_tokens <= max_tokens,
+                "min_tokens ({min_tokens}) exceeds max_tokens ({max_tokens})"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Selects a system prompt provided by Mistral. Reasoning models need the `reasoning`
+/// prompt to separate their reasoning from the final answer.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
+pub enum PromptMode {
+    Reasoning,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum ResponseFormat {
     Text,
-    #[serde(rename = "json_object")]
     JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: Value,
+    #[serde(default)]
+    pub strict: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ToolDefinition {
     Function { function: FunctionDefinition },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FunctionDefinition {
     pub name: String,
     pub description: Option<String>,
     pub parameters: Option<Value>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FimRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    pub stream: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CompletionRequest {
     pub model: String,
@@ -268,29 +657,53 @@ pub struct CompletionRequest {
     pub rewrite_speculation: Option<bool>,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Prediction {
     Content { content: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ToolChoice {
     Auto,
     Required,
     None,
     Any,
-    Function(ToolDefinition),
+    /// Forces the model to call a specific function.
+    #[serde(untagged)]
+    Function(FunctionChoice),
+}
+
+impl ToolChoice {
+    pub fn function(name: impl Into<String>) -> Self {
+        Self::Function(FunctionChoice {
+            function: FunctionName { name: name.into() },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename = "function")]
+pub struct FunctionChoice {
+    pub function: FunctionName,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FunctionName {
+    pub name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum RequestMessage {
     Assistant {
         content: Option<String>,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         tool_calls: Vec<ToolCall>,
+        /// Whether the model should continue this message rather than start a new one.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        prefix: bool,
     },
     User {
         #[serde(flatten)]
@@ -300,12 +713,13 @@ pub enum RequestMessage {
         content: String,
     },
     Tool {
-        content: String,
+        #[serde(flatten)]
+        content: MessageContent,
         tool_call_id: String,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum MessageContent {
     #[serde(rename = "content")]
@@ -324,7 +738,10 @@ impl MessageContent {
     pub fn push_part(&mut self, part: MessagePart) {
         match self {
             Self::Plain { content } => match part {
-                MessagePart::Text { text } => {
+                MessagePart::Text {
+                    text,
+                    cache_control: None,
+                } => {
                     content.push_str(&text);
                 }
                 part => {
@@ -333,6 +750,7 @@ impl MessageContent {
                     } else {
                         vec![MessagePart::Text {
                             text: content.clone(),
+                            cache_control: None,
                         }]
                     };
                     parts.push(part);
@@ -344,29 +762,143 @@ impl MessageContent {
             }
         }
     }
+
+    /// Replaces multipart content with its text parts joined by newlines, for APIs that only
+    /// accept strings, so that text on either side of a dropped image doesn't run together.
+    /// Returns the number of parts other than text, such as images, that were dropped.
+    pub fn flatten(&mut self) -> usize {
+        let Self::Multipart { content } = self else {
+            return 0;
+        };
+        let mut texts = Vec::new();
+        let mut dropped_parts = 0;
+        for part in content.drain(..) {
+            match part {
+                MessagePart::Text { text, .. } => texts.push(text),
+                MessagePart::ImageUrl { .. } | MessagePart::File { .. } => dropped_parts += 1,
+            }
+        }
+        *self = Self::Plain {
+            content: texts.join("\n"),
+        };
+        dropped_parts
+    }
+
+    /// Appends the parts of `other` after this content's parts.
+    pub fn append(&mut self, other: MessageContent) {
+        match other {
+            Self::Plain { content } => {
+                if !content.is_empty() {
+                    self.push_part(MessagePart::Text {
+                        text: content,
+                        cache_control: None,
+                    });
+                }
+            }
+            Self::Multipart { content } => {
+                for part in content {
+                    self.push_part(part);
+                }
+            }
+        }
+    }
+
+    /// Marks the end of this content as a cache breakpoint, so that the prompt up to and
+    /// including it can be reused by later requests.
+    pub fn set_cache_control(&mut self, cache_control: CacheControl) {
+        if let Self::Plain { content } = self {
+            *self = Self::Multipart {
+                content: vec![MessagePart::Text {
+                    text: std::mem::take(content),
+                    cache_control: None,
+                }],
+            };
+        }
+        if let Self::Multipart { content } = self {
+            if let Some(
+                MessagePart::Text {
+                    cache_control: part_cache_control,
+                    ..
+                }
+                | MessagePart::ImageUrl {
+                    cache_control: part_cache_control,
+                    ..
+                },
+            ) = content.last_mut()
+            {
+                *part_cache_control = Some(cache_control);
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessagePart {
-    Text { text: String },
-    ImageUrl { image_url: String },
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    /// A file uploaded with [`upload_file`], referenced by its ID.
+    File { file_id: String },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ImageUrl {
+    /// The URL of the image, or its contents as a data URL.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+impl From<String> for ImageUrl {
+    fn from(url: String) -> Self {
+        Self { url, detail: None }
+    }
+}
+
+impl From<&str> for ImageUrl {
+    fn from(url: &str) -> Self {
+        Self::from(url.to_string())
+    }
+}
+
+/// How closely images are looked at. Lower detail uses fewer tokens per image.
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    Auto,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CacheControl {
+    Ephemeral,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ToolCall {
     pub id: String,
     #[serde(flatten)]
     pub content: ToolCallContent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ToolCallContent {
     Function { function: FunctionContent },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct FunctionContent {
     pub name: String,
     pub arguments: String,
@@ -387,21 +919,114 @@ pub struct Response {
     pub usage: Usage,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Usage {
     pub prompt_tokens: u64,
     pub completion_tokens: u64,
     pub total_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct PromptTokensDetails {
+    /// The prompt tokens that were read from the prompt cache, included in `prompt_tokens`.
+    #[serde(default)]
+    pub cached_tokens: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Choice {
     pub index: u32,
-    pub message: RequestMessage,
+    pub message: ResponseMessage,
     pub finish_reason: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The assistant message of a complete response. Unlike a [`RequestMessage`], its content can
+/// be a list of parts, which reasoning models use to return their thinking with the answer.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub struct ResponseMessage {
+    #[serde(default)]
+    pub content: Option<ResponseContent>,
+    #[serde(default, deserialize_with = "deserialize_null_as_default")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+}
+
+impl ResponseMessage {
+    /// Returns the text of the answer, leaving out any thinking.
+    pub fn text(&self) -> Option<String> {
+        match self.content.as_ref()? {
+            ResponseContent::Text(text) => Some(text.clone()),
+            ResponseContent::Parts(parts) => {
+                let text = parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ResponsePart::Text { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<String>();
+                (!text.is_empty()).then_some(text)
+            }
+        }
+    }
+
+    /// Returns the model's reasoning, whether it was sent as `reasoning_content` or as
+    /// thinking parts of the content.
+    pub fn reasoning(&self) -> Option<String> {
+        if let Some(reasoning) = &self.reasoning_content {
+            return Some(reasoning.clone());
+        }
+        let Some(ResponseContent::Parts(parts)) = &self.content else {
+            return None;
+        };
+        let reasoning = parts
+            .iter()
+            .filter_map(|part| match part {
+                ResponsePart::Thinking { thinking } => Some(thinking),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|part| match part {
+                ResponsePart::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<String>();
+        (!reasoning.is_empty()).then_some(reasoning)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum ResponseContent {
+    Text(String),
+    Parts(Vec<ResponsePart>),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponsePart {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: Vec<ResponsePart>,
+    },
+    /// Parts that can't be shown as text, such as references, are skipped.
+    #[serde(other)]
+    Other,
+}
+
+fn deserialize_null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct StreamResponse {
     pub id: String,
     pub object: String,
@@ -411,14 +1036,108 @@ pub struct StreamResponse {
     pub usage: Option<Usage>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct StreamChoice {
-    pub index: u32,
-    pub delta: StreamDelta,
-    pub finish_reason: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
+impl From<Response> for StreamResponse {
+    /// Converts a complete response into the equivalent single chunk of a streamed one.
+    fn from(response: Response) -> Self {
+        let choices = response
+            .choices
+            .into_iter()
+            .map(|choice| {
+                let content = choice.message.text();
+                let reasoning_content = choice.message.reasoning();
+                let tool_calls = choice
+                    .message
+                    .tool_calls
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, tool_call)| {
+                        let ToolCallContent::Function { function } = tool_call.content;
+                        ToolCallChunk {
+                            index,
+                            id: Some(tool_call.id),
+                            function: Some(FunctionChunk {
+                                name: Some(function.name),
+                                arguments: Some(function.arguments),
+                            }),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                StreamChoice {
+                    index: choice.index,
+                    delta: StreamDelta {
+                        role: Some(Role::Assistant),
+                        content,
+                        tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                        reasoning_content,
+                    },
+                    finish_reason: choice.finish_reason,
+                }
+            })
+            .collect();
+        Self {
+            id: response.id,
+            object: response.object,
+            created: response.created,
+            model: response.model,
+            choices,
+            usage: Some(response.usage),
+        }
+    }
+}
+
+/// Reconstructs the assistant message streamed in `chunks`, joining its text and the fragments
+/// of each of its tool calls, such as to record or replay raw responses. Only the first choice
+/// is read, and reasoning is left out.
+pub fn collect_message<'a>(chunks: impl IntoIterator<Item = &'a StreamResponse>) -> RequestMessage {
+    let mut content = None::<String>;
+    let mut tool_calls = BTreeMap::<usize, ToolCall>::new();
+    for chunk in chunks {
+        let Some(choice) = chunk.choices.iter().find(|choice| choice.index == 0) else {
+            continue;
+        };
+        if let Some(text) = &choice.delta.content {
+            content.get_or_insert_default().push_str(text);
+        }
+        for chunk in choice.delta.tool_calls.iter().flatten() {
+            let tool_call = tool_calls.entry(chunk.index).or_insert_with(|| ToolCall {
+                id: String::new(),
+                content: ToolCallContent::Function {
+                    function: FunctionContent {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                },
+            });
+            // The id and name are sent once, though some chunks repeat them or send them empty.
+            if let Some(id) = chunk.id.as_ref().filter(|id| !id.is_empty()) {
+                tool_call.id = id.clone();
+            }
+            let ToolCallContent::Function { function } = &mut tool_call.content;
+            if let Some(fragment) = &chunk.function {
+                if let Some(name) = fragment.name.as_ref().filter(|name| !name.is_empty()) {
+                    function.name = name.clone();
+                }
+                if let Some(arguments) = &fragment.arguments {
+                    function.arguments.push_str(arguments);
+                }
+            }
+        }
+    }
+    RequestMessage::Assistant {
+        content,
+        tool_calls: tool_calls.into_values().collect(),
+        prefix: false,
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct StreamChoice {
+    pub index: u32,
+    pub delta: StreamDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct StreamDelta {
     pub role: Option<Role>,
     pub content: Option<String>,
@@ -428,36 +1147,795 @@ pub struct StreamDelta {
     pub reasoning_content: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ToolCallChunk {
     pub index: usize,
     pub id: Option<String>,
     pub function: Option<FunctionChunk>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct FunctionChunk {
     pub name: Option<String>,
     pub arguments: Option<String>,
 }
 
-pub async fn stream_completion(
+#[derive(Deserialize, Debug)]
+pub struct ListModelsResponse {
+    pub data: Vec<ModelCard>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct ModelCard {
+    pub id: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub max_context_length: Option<u64>,
+    #[serde(default)]
+    pub capabilities: ModelCardCapabilities,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, PartialEq)]
+pub struct ModelCardCapabilities {
+    #[serde(default)]
+    pub completion_chat: bool,
+    #[serde(default)]
+    pub completion_fim: bool,
+    #[serde(default)]
+    pub function_calling: bool,
+    #[serde(default)]
+    pub vision: bool,
+}
+
+pub async fn list_models(
     client: &dyn HttpClient,
-    api_url: &str,
-    api_key: &str,
-    request: Request,
-) -> Result<BoxStream<'static, Result<StreamResponse>>> {
-    let uri = format!("{api_url}/chat/completions");
-    let request_builder = HttpRequest::builder()
+    connection: &Connection,
+) -> Result<Vec<ModelCard>> {
+    let uri = format!("{}/models", connection.api_url);
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json");
+    let body = send(client, request, AsyncBody::default(), connection).await?;
+    let response: ListModelsResponse = serde_json::from_str(&body)?;
+    Ok(response.data)
+}
+
+#[derive(Serialize, Debug)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingResponse {
+    pub data: Vec<Embedding>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Embedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// Embeds `texts` with [`EMBEDDING_MODEL`], splitting them into batches of
+/// [`EMBEDDING_BATCH_SIZE`]. Embeddings are returned in the same order as `texts`.
+pub async fn embed(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let uri = format!("{}/embeddings", connection.api_url);
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+        let request = EmbeddingRequest {
+            model: EMBEDDING_MODEL,
+            input: batch,
+        };
+        let body = AsyncBody::from(serde_json::to_string(&request)?);
+        let request = HttpRequest::builder()
+            .method(Method::POST)
+            .uri(uri.as_str())
+            .header("Content-Type", "application/json");
+        let body = send(client, request, body, connection).await?;
+        let mut response: EmbeddingResponse =
+            serde_json::from_str(&body).context("failed to parse Mistral embeddings response")?;
+        anyhow::ensure!(
+            response.data.len() == batch.len(),
+            "expected {} Mistral embeddings, got {}",
+            batch.len(),
+            response.data.len(),
+        );
+        response.data.sort_by_key(|embedding| embedding.index);
+        embeddings.extend(
+            response
+                .data
+                .into_iter()
+                .map(|embedding| embedding.embedding),
+        );
+    }
+    Ok(embeddings)
+}
+
+#[derive(Serialize, Debug)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: [&'a str; 1],
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModerationResult {
+    /// Whether the input was flagged for each category.
+    pub categories: ModerationCategories<bool>,
+    /// The classifier's confidence, between 0 and 1, that the input belongs to each category.
+    pub category_scores: ModerationCategories<f32>,
+}
+
+impl ModerationResult {
+    pub fn is_flagged(&self) -> bool {
+        let categories = &self.categories;
+        categories.sexual
+            || categories.hate_and_discrimination
+            || categories.violence_and_threats
+            || categories.dangerous_and_criminal_content
+            || categories.selfharm
+            || categories.health
+            || categories.financial
+            || categories.law
+            || categories.pii
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModerationCategories<T> {
+    pub sexual: T,
+    pub hate_and_discrimination: T,
+    pub violence_and_threats: T,
+    pub dangerous_and_criminal_content: T,
+    pub selfharm: T,
+    pub health: T,
+    pub financial: T,
+    pub law: T,
+    pub pii: T,
+}
+
+/// Classifies `text` with [`MODERATION_MODEL`].
+pub async fn moderate(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    text: &str,
+) -> Result<ModerationResult> {
+    let uri = format!("{}/moderations", connection.api_url);
+    let request = ModerationRequest {
+        model: MODERATION_MODEL,
+        input: [text],
+    };
+    let body = AsyncBody::from(serde_json::to_string(&request)?);
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    let body = send(client, request, body, connection).await?;
+    let response: ModerationResponse =
+        serde_json::from_str(&body).context("failed to parse Mistral moderation response")?;
+    response
+        .results
+        .into_iter()
+        .next()
+        .context("Mistral moderation response contained no results")
+}
+
+#[derive(Serialize, Debug)]
+struct ClassificationRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+struct ClassificationResponse {
+    results: Vec<Classification>,
+}
+
+/// The scores of one input, by the name of each of the classifier's heads.
+pub type Classification = BTreeMap<String, ClassifierScores>;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ClassifierScores {
+    pub scores: BTreeMap<String, f32>,
+}
+
+impl ClassifierScores {
+    /// The most likely label of a single-label classifier, whose scores sum to one.
+    pub fn top_label(&self) -> Option<(&str, f32)> {
+        self.scores
+            .iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(label, score)| (label.as_str(), *score))
+    }
+
+    /// The labels predicted by a multi-label classifier, whose labels are scored
+    /// independently, in descending order of score.
+    pub fn labels_above(&self, threshold: f32) -> Vec<(&str, f32)> {
+        let mut labels = self
+            .scores
+            .iter()
+            .filter(|(_, score)| **score >= threshold)
+            .map(|(label, score)| (label.as_str(), *score))
+            .collect::<Vec<_>>();
+        labels.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        labels
+    }
+}
+
+/// Runs the fine-tuned classifier `model` on each of `inputs`, returning their scores in
+/// the same order.
+pub async fn classify(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    model: &str,
+    inputs: &[String],
+) -> Result<Vec<Classification>> {
+    let request = ClassificationRequest {
+        model,
+        input: inputs,
+    };
+    let body = AsyncBody::from(serde_json::to_string(&request)?);
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(format!("{}/classifications", connection.api_url))
+        .header("Content-Type", "application/json");
+    let body = send(client, request, body, connection).await?;
+    let response: ClassificationResponse =
+        serde_json::from_str(&body).context("failed to parse Mistral classification response")?;
+    anyhow::ensure!(
+        response.results.len() == inputs.len(),
+        "Mistral returned {} classifications for {} inputs",
+        response.results.len(),
+        inputs.len()
+    );
+    Ok(response.results)
+}
+
+/// A document to extract text from. URLs may be `data:` URLs containing base64-encoded content.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OcrDocument {
+    DocumentUrl { document_url: String },
+    ImageUrl { image_url: String },
+}
+
+#[derive(Serialize, Debug)]
+struct OcrRequest<'a> {
+    model: &'a str,
+    document: OcrDocument,
+    #[serde(
+        rename = "document_page_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    page_limit: Option<u32>,
+    #[serde(
+        rename = "document_image_limit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    image_limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OcrResponse {
+    pub pages: Vec<OcrPage>,
+}
+
+impl OcrResponse {
+    /// The markdown of all pages, in page order.
+    pub fn markdown(&self) -> String {
+        self.pages
+            .iter()
+            .map(|page| page.markdown.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OcrPage {
+    pub index: usize,
+    pub markdown: String,
+    #[serde(default)]
+    pub dimensions: Option<OcrPageDimensions>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OcrPageDimensions {
+    pub dpi: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Extracts the text of `document` as markdown with [`OCR_MODEL`].
+pub async fn ocr(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    document: OcrDocument,
+    page_limit: Option<u32>,
+    image_limit: Option<u32>,
+) -> Result<OcrResponse> {
+    anyhow::ensure!(page_limit != Some(0), "OCR page limit must be positive");
+    anyhow::ensure!(image_limit != Some(0), "OCR image limit must be positive");
+
+    let uri = format!("{}/ocr", connection.api_url);
+    let request = OcrRequest {
+        model: OCR_MODEL,
+        document,
+        page_limit,
+        image_limit,
+    };
+    let body = AsyncBody::from(serde_json::to_string(&request)?);
+    let request = HttpRequest::builder()
         .method(Method::POST)
         .uri(uri)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key));
+        .header("Content-Type", "application/json");
+    let body = send(client, request, body, connection).await?;
+    let mut response: OcrResponse =
+        serde_json::from_str(&body).context("failed to parse Mistral OCR response")?;
+    response.pages.sort_by_key(|page| page.index);
+    Ok(response)
+}
+
+/// A line of the JSONL input file of a batch job.
+#[derive(Serialize, Debug)]
+pub struct BatchRequest {
+    /// Identifies the request's result in the output file.
+    pub custom_id: String,
+    pub body: Request,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FileObject {
+    pub id: String,
+    pub filename: String,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct CreateBatchJobRequest<'a> {
+    input_files: &'a [String],
+    endpoint: &'a str,
+    model: &'a str,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BatchJobStatus {
+    Queued,
+    Running,
+    Success,
+    Failed,
+    TimeoutExceeded,
+    CancellationRequested,
+    Cancelled,
+}
+
+impl BatchJobStatus {
+    /// Whether the job has stopped, successfully or not, so that polling can stop.
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Self::Queued | Self::Running | Self::CancellationRequested => false,
+            Self::Success | Self::Failed | Self::TimeoutExceeded | Self::Cancelled => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BatchJob {
+    pub id: String,
+    pub status: BatchJobStatus,
+    pub model: String,
+    #[serde(default)]
+    pub output_file: Option<String>,
+    #[serde(default)]
+    pub error_file: Option<String>,
+    #[serde(default)]
+    pub total_requests: u64,
+    #[serde(default)]
+    pub succeeded_requests: u64,
+    #[serde(default)]
+    pub failed_requests: u64,
+}
+
+/// A line of the JSONL output file of a batch job.
+#[derive(Deserialize, Debug)]
+pub struct BatchResult {
+    pub custom_id: String,
+    #[serde(default)]
+    pub response: Option<BatchResponse>,
+    #[serde(default)]
+    pub error: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchResponse {
+    pub status_code: u16,
+    pub body: Response,
+}
+
+/// Uploads `requests` as the input file of a new batch job of chat completions with `model`.
+/// Batch jobs run asynchronously at a reduced price; use [`get_batch_job`] to poll them.
+pub async fn create_batch_job(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    model: &str,
+    requests: Vec<BatchRequest>,
+) -> Result<BatchJob> {
+    let mut input = String::new();
+    for request in requests {
+        input.push_str(&serde_json::to_string(&request)?);
+        input.push('\n');
+    }
+    let file = upload_file(
+        client,
+        connection,
+        "batch.jsonl",
+        FilePurpose::Batch,
+        input.into_bytes(),
+    )
+    .await?;
+
+    let request = CreateBatchJobRequest {
+        input_files: &[file.id],
+        endpoint: "/v1/chat/completions",
+        model,
+    };
+    let body = AsyncBody::from(serde_json::to_string(&request)?);
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(format!("{}/batch/jobs", connection.api_url))
+        .header("Content-Type", "application/json");
+    let body = send(client, request, body, connection).await?;
+    serde_json::from_str(&body).context("failed to parse Mistral batch job")
+}
+
+pub async fn get_batch_job(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    job_id: &str,
+) -> Result<BatchJob> {
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(format!("{}/batch/jobs/{job_id}", connection.api_url))
+        .header("Accept", "application/json");
+    let body = send(client, request, AsyncBody::default(), connection).await?;
+    serde_json::from_str(&body).context("failed to parse Mistral batch job")
+}
+
+/// Downloads and parses the output file of a finished batch job.
+pub async fn get_batch_results(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    output_file: &str,
+) -> Result<Vec<BatchResult>> {
+    let request = HttpRequest::builder().method(Method::GET).uri(format!(
+        "{}/files/{output_file}/content",
+        connection.api_url
+    ));
+    let body = send(client, request, AsyncBody::default(), connection).await?;
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("failed to parse Mistral batch result"))
+        .collect()
+}
 
-    let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
+/// What an uploaded file will be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilePurpose {
+    Batch,
+    FineTune,
+    /// Documents to read with OCR or to reference from chat messages.
+    Ocr,
+}
+
+impl FilePurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Batch => "batch",
+            Self::FineTune => "fine-tune",
+            Self::Ocr => "ocr",
+        }
+    }
+}
+
+/// Uploads `contents` with the files API, so that it can be referenced by ID instead of being
+/// sent with every request.
+pub async fn upload_file(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    filename: &str,
+    purpose: FilePurpose,
+    contents: Vec<u8>,
+) -> Result<FileObject> {
+    anyhow::ensure!(
+        contents.len() as u64 <= MAX_FILE_SIZE,
+        "{filename} is {} bytes, more than the {MAX_FILE_SIZE} bytes Mistral accepts",
+        contents.len()
+    );
+
+    let boundary = multipart_boundary(&contents);
+    let purpose = purpose.as_str();
+    let filename = escape_filename(filename);
+    let mut body = format!(
+        "--{boundary}\r\n\
+         Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+         {purpose}\r\n\
+         --{boundary}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend(contents);
+    body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+    let body = AsyncBody::from(body);
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(format!("{}/files", connection.api_url))
+        .header(
+            "Content-Type",
+            format!("multipart/form-data; boundary={boundary}"),
+        );
+    let body = send(client, request, body, connection).await?;
+    serde_json::from_str(&body).context("failed to parse Mistral file upload response")
+}
+
+/// A random multipart boundary that doesn't occur in `contents`, where it would end the part
+/// early.
+fn multipart_boundary(contents: &[u8]) -> String {
+    loop {
+        // Every `RandomState` has its own random keys, so hashing with one gives a random number.
+        let random = RandomState::new().hash_one(0u8);
+        let boundary = format!("zed-mistral-upload-{random:016x}");
+        if !contents
+            .windows(boundary.len())
+            .any(|window| window == boundary.as_bytes())
+        {
+            return boundary;
+        }
+    }
+}
+
+/// Escapes `filename` for a quoted `Content-Disposition` parameter the way browsers encode
+/// form data, so that it can't end the parameter or the header.
+fn escape_filename(filename: &str) -> String {
+    filename
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Sends `request` with the headers that every request carries, and reads the body of a
+/// successful response.
+async fn send(
+    client: &dyn HttpClient,
+    request: RequestBuilder,
+    body: AsyncBody,
+    connection: &Connection,
+) -> Result<String> {
+    let request = build_request(request, body, connection)?;
     let mut response = client.send(request).await?;
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
 
     if response.status().is_success() {
+        Ok(body)
+    } else {
+        let retry_after = parse_retry_after(response.headers());
+        Err(ApiError::new(response.status(), body, retry_after).into())
+    }
+}
+
+pub async fn stream_completion(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    mut request: Request,
+) -> Result<BoxStream<'static, Result<StreamResponse>>> {
+    request.validate()?;
+    let uri = if request.agent_id.is_some() {
+        // Agents run with the model they were created with.
+        request.model.clear();
+        format!("{}/agents/completions", connection.api_url)
+    } else {
+        format!("{}/chat/completions", connection.api_url)
+    };
+    let body = serde_json::to_string(&request)?;
+    if request.metadata.is_empty() {
+        stream_request(client, uri, connection, body, request.stream).await
+    } else {
+        // A header configured by the user takes precedence over the metadata.
+        let mut connection = connection.clone();
+        connection
+            .extra_headers
+            .entry(METADATA_HEADER.to_string())
+            .or_insert(serde_json::to_string(&request.metadata)?);
+        stream_request(client, uri, &connection, body, request.stream).await
+    }
+}
+
+/// Streams a fill-in-the-middle completion, which Codestral uses for inline code completion.
+/// The response chunks have the same shape as chat completion chunks.
+pub async fn stream_fim_completion(
+    client: &dyn HttpClient,
+    connection: &Connection,
+    request: FimRequest,
+) -> Result<BoxStream<'static, Result<StreamResponse>>> {
+    let uri = format!("{}/fim/completions", connection.api_url);
+    let body = serde_json::to_string(&request)?;
+    stream_request(client, uri, connection, body, request.stream).await
+}
+
+/// An unsuccessful response from the Mistral API.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status_code: StatusCode,
+    /// The `message` from the error body, or the whole body if it isn't in the expected format.
+    pub message: String,
+    /// The `type` from the error body, such as `invalid_request_error`.
+    pub error_type: Option<String>,
+    pub code: Option<String>,
+    /// How long to wait before retrying, as reported by the `Retry-After` header.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: Option<Value>,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+    code: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status_code: StatusCode, body: String, retry_after: Option<Duration>) -> Self {
+        let Ok(error) = serde_json::from_str::<ErrorBody>(&body) else {
+            return Self {
+                status_code,
+                message: body,
+                error_type: None,
+                code: None,
+                retry_after,
+            };
+        };
+
+        // Validation errors report their details as an object rather than a string.
+        let message = match error.message {
+            Some(Value::String(message)) => message,
+            Some(message) => message.to_string(),
+            None => body,
+        };
+        let code = match error.code {
+            Some(Value::String(code)) => Some(code),
+            Some(Value::Number(code)) => Some(code.to_string()),
+            _ => None,
+        };
+        Self {
+            status_code,
+            message,
+            error_type: error.error_type,
+            code,
+            retry_after,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to connect to Mistral API: {} {}",
+            self.status_code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// The sizes reported by an error for a prompt that exceeds the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptTooLarge {
+    pub tokens: u64,
+    pub max_tokens: Option<u64>,
+}
+
+/// Parses the prompt size and context window from errors like "Prompt contains 40000 tokens
+/// and 0 draft tokens, too large for model with 32768 maximum context length".
+pub fn parse_prompt_too_large(message: &str) -> Option<PromptTooLarge> {
+    let (_, model) = message.split_once("too large for model")?;
+    let tokens = message
+        .strip_prefix("Prompt contains ")?
+        .split_once(' ')?
+        .0
+        .parse()
+        .ok()?;
+    let max_tokens = model
+        .trim_start()
+        .strip_prefix("with ")
+        .and_then(|model| model.split_once(' '))
+        .and_then(|(max_tokens, _)| max_tokens.parse().ok());
+    Some(PromptTooLarge { tokens, max_tokens })
+}
+
+/// Parses the `Retry-After` header as an integer number of seconds. Returns `None` if the header
+/// is not present or is in some other format, such as an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Finishes `request` with the headers that every request carries: the API key, the
+/// organization and the extra headers of `connection`. An extra header replaces a standard one of the same name,
+/// such as `Authorization` for a gateway with its own authentication, rather than being sent
+/// alongside it.
+fn build_request(
+    mut request: RequestBuilder,
+    body: AsyncBody,
+    connection: &Connection,
+) -> Result<HttpRequest<AsyncBody>> {
+    request = request.header(
+        "Authorization",
+        authorization(&connection.auth_scheme, &connection.api_key),
+    );
+    if let Some(organization_id) = &connection.organization_id {
+        request = request.header(ORGANIZATION_HEADER, organization_id);
+    }
+    let mut request = request.body(body)?;
+    for (name, value) in &connection.extra_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header name {name:?}"))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value for header {name}"))?;
+        request.headers_mut().insert(name, value);
+    }
+    Ok(request)
+}
+
+/// The value of the `Authorization` header for `api_key`. An empty scheme sends the key alone.
+fn authorization(auth_scheme: &str, api_key: &str) -> String {
+    if auth_scheme.is_empty() {
+        api_key.to_string()
+    } else {
+        format!("{auth_scheme} {api_key}")
+    }
+}
+
+/// Sends a completion request and streams its response. When `stream` is false, the request
+/// is answered with a single response, which is passed on as one chunk so that it can be
+/// handled like a streamed one.
+async fn stream_request(
+    client: &dyn HttpClient,
+    uri: String,
+    connection: &Connection,
+    body: String,
+    stream: bool,
+) -> Result<BoxStream<'static, Result<StreamResponse>>> {
+    let request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    let request = build_request(request, AsyncBody::from(body), connection)?;
+    let mut response = client.send(request).await?;
+
+    if response.status().is_success() && !stream {
+        let mut body = String::new();
+        response.body_mut().read_to_string(&mut body).await?;
+        let response = serde_json::from_str::<Response>(&body)
+            .context("failed to parse Mistral completion")?;
+        Ok(futures::stream::once(async move { Ok(response.into()) }).boxed())
+    } else if response.status().is_success() {
         let reader = BufReader::new(response.into_body());
         Ok(reader
             .lines()
@@ -479,12 +1957,862 @@ pub async fn stream_completion(
             })
             .boxed())
     } else {
+        let retry_after = parse_retry_after(response.headers());
         let mut body = String::new();
         response.body_mut().read_to_string(&mut body).await?;
-        anyhow::bail!(
-            "Failed to connect to Mistral API: {} {}",
-            response.status(),
-            body,
+        Err(ApiError::new(response.status(), body, retry_after).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_client::FakeHttpClient;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering::SeqCst},
+    };
+    use strum::IntoEnumIterator as _;
+
+    fn custom_model(name: &str) -> Model {
+        Model::Custom {
+            name: name.into(),
+            display_name: None,
+            max_tokens: 32000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            supports_tools: None,
+            supports_images: None,
+            supports_structured_output: None,
+            supports_thinking: None,
+            supports_prompt_cache: None,
+            max_temperature: None,
+            supports_prediction: None,
+            price_per_token: None,
+            knowledge_cutoff: None,
+            description: None,
+            default_stop_sequences: None,
+        }
+    }
+
+    #[test]
+    fn max_completion_tokens_precedence() {
+        let model_with_limits = |output_limit, completion_limit| {
+            let mut model = custom_model("custom");
+            if let Model::Custom {
+                max_output_tokens,
+                max_completion_tokens,
+                ..
+            } = &mut model
+            {
+                *max_output_tokens = output_limit;
+                *max_completion_tokens = completion_limit;
+            }
+            model
+        };
+        assert_eq!(
+            model_with_limits(Some(8192), Some(1024)).max_completion_tokens(),
+            Some(1024)
+        );
+        assert_eq!(
+            model_with_limits(Some(8192), None).max_completion_tokens(),
+            Some(8192)
+        );
+        assert_eq!(model_with_limits(None, None).max_completion_tokens(), None);
+        assert_eq!(Model::MistralSmallLatest.max_completion_tokens(), None);
+    }
+
+    #[test]
+    fn response_converts_to_single_chunk() {
+        let response = |message: Value| {
+            let response = serde_json::from_value::<Response>(serde_json::json!({
+                "id": "response-id",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "magistral-medium-latest",
+                "choices": [{ "index": 0, "message": message, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+            }))
+            .unwrap();
+            StreamResponse::from(response).choices.remove(0).delta
+        };
+
+        let delta = response(serde_json::json!({
+            "role": "assistant",
+            "content": [
+                { "type": "thinking", "thinking": [{ "type": "text", "text": "The user said hi." }] },
+                { "type": "text", "text": "Hello" },
+                { "type": "text", "text": " there" },
+            ],
+            "tool_calls": null,
+        }));
+        assert_eq!(delta.content.as_deref(), Some("Hello there"));
+        assert_eq!(
+            delta.reasoning_content.as_deref(),
+            Some("The user said hi.")
+        );
+        assert_eq!(delta.tool_calls, None);
+
+        let delta = response(serde_json::json!({
+            "role": "assistant",
+            "content": "Hello",
+            "reasoning_content": "The user said hi.",
+        }));
+        assert_eq!(delta.content.as_deref(), Some("Hello"));
+        assert_eq!(
+            delta.reasoning_content.as_deref(),
+            Some("The user said hi.")
+        );
+    }
+
+    #[test]
+    fn custom_model_image_support() {
+        let model_with_images = |name: &str, images| {
+            let mut model = custom_model(name);
+            if let Model::Custom {
+                supports_images, ..
+            } = &mut model
+            {
+                *supports_images = images;
+            }
+            model
+        };
+
+        assert!(custom_model("pixtral-custom").supports_images());
+        assert!(custom_model("acme-Vision-7b").supports_images());
+        assert!(!custom_model("mistral-custom").supports_images());
+        // An explicit setting takes precedence over the name.
+        assert!(!model_with_images("pixtral-custom", Some(false)).supports_images());
+        assert!(model_with_images("mistral-custom", Some(true)).supports_images());
+    }
+
+    #[test]
+    fn knowledge_cutoffs() {
+        assert_eq!(Model::CodestralLatest.knowledge_cutoff(), Some("2024-10"));
+        assert_eq!(
+            Model::MistralLargeLatest.knowledge_cutoff(),
+            Some("2024-09")
+        );
+        assert_eq!(
+            Model::MistralSmallLatest.knowledge_cutoff(),
+            Some("2025-03")
+        );
+        assert_eq!(Model::OpenMistralNemo.knowledge_cutoff(), Some("2024-04"));
+        assert_eq!(
+            Model::DevstralSmallLatest.knowledge_cutoff(),
+            Some("2025-04")
+        );
+        assert_eq!(Model::Pixtral12BLatest.knowledge_cutoff(), Some("2024-06"));
+        for model in Model::iter() {
+            if !matches!(model, Model::Custom { .. }) {
+                assert!(model.knowledge_cutoff().is_some(), "{}", model.id());
+                assert!(model.description().is_some(), "{}", model.id());
+            }
+        }
+
+        let mut model = custom_model("custom");
+        if let Model::Custom {
+            knowledge_cutoff, ..
+        } = &mut model
+        {
+            *knowledge_cutoff = Some("2025-01".into());
+        }
+        assert_eq!(model.knowledge_cutoff(), Some("2025-01"));
+        assert_eq!(model.description(), None);
+    }
+
+    #[test]
+    fn parse_list_models_response() {
+        let response = serde_json::json!({
+            "object": "list",
+            "data": [
+                {
+                    "id": "mistral-small-2503",
+                    "object": "model",
+                    "created": 1741651200,
+                    "owned_by": "mistralai",
+                    "capabilities": {
+                        "completion_chat": true,
+                        "completion_fim": false,
+                        "function_calling": true,
+                        "fine_tuning": true,
+                        "vision": true,
+                        "classification": false
+                    },
+                    "name": "mistral-small-2503",
+                    "description": "Our latest enterprise-grade small model.",
+                    "max_context_length": 131072,
+                    "aliases": ["mistral-small-latest"],
+                    "deprecation": null,
+                    "default_model_temperature": 0.3,
+                    "type": "base"
+                },
+                {
+                    "id": "mistral-embed",
+                    "object": "model",
+                    "capabilities": { "completion_chat": false },
+                    "max_context_length": 8192
+                }
+            ]
+        });
+
+        let response: ListModelsResponse = serde_json::from_value(response).unwrap();
+        assert_eq!(response.data.len(), 2);
+        assert_eq!(response.data[0].id, "mistral-small-2503");
+        assert_eq!(response.data[0].max_context_length, Some(131072));
+        assert!(response.data[0].capabilities.vision);
+        assert!(!response.data[1].capabilities.completion_chat);
+        assert!(response.data[1].aliases.is_empty());
+    }
+
+    #[test]
+    fn embed_batches_inputs() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let client = FakeHttpClient::create({
+            let request_count = request_count.clone();
+            move |request| {
+                let request_count = request_count.clone();
+                async move {
+                    request_count.fetch_add(1, SeqCst);
+                    assert_eq!(request.uri().path(), "/v1/embeddings");
+
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await?;
+                    let request: Value = serde_json::from_str(&body)?;
+                    assert_eq!(request["model"], EMBEDDING_MODEL);
+
+                    // Respond out of order to check that results are sorted by index.
+                    let data = request["input"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(index, input)| {
+                            serde_json::json!({
+                                "object": "embedding",
+                                "index": index,
+                                "embedding": [input.as_str().unwrap().len() as f32],
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(
+                            serde_json::json!({ "data": data }).to_string(),
+                        ))
+                        .unwrap())
+                }
+            }
+        });
+
+        let texts = (0..EMBEDDING_BATCH_SIZE + 2)
+            .map(|ix| "x".repeat(ix))
+            .collect::<Vec<_>>();
+        let embeddings = futures::executor::block_on(embed(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            &texts,
+        ))
+        .unwrap();
+
+        assert_eq!(request_count.load(SeqCst), 2);
+        assert_eq!(embeddings.len(), texts.len());
+        for (ix, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(embedding, &vec![ix as f32]);
+        }
+    }
+
+    #[test]
+    fn stream_completion_organization_header() {
+        let client = FakeHttpClient::create(|request| async move {
+            let organization_id = request
+                .headers()
+                .get(ORGANIZATION_HEADER)
+                .map(|value| value.to_str().unwrap().to_string());
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(AsyncBody::from(organization_id.unwrap_or_default()))
+                .unwrap())
+        });
+        let request = || Request {
+            model: "mistral-small-latest".into(),
+            stream: true,
+            ..Default::default()
+        };
+        let sent_organization_id = |organization_id: Option<&str>| {
+            let Err(error) = futures::executor::block_on(stream_completion(
+                client.as_ref(),
+                &Connection {
+                    organization_id: organization_id.map(str::to_string),
+                    ..Connection::new(MISTRAL_API_URL, "key")
+                },
+                request(),
+            )) else {
+                panic!("expected the request to fail");
+            };
+            error.downcast::<ApiError>().unwrap().message
+        };
+
+        assert_eq!(sent_organization_id(Some("org-123")), "org-123");
+        assert_eq!(sent_organization_id(None), "");
+    }
+
+    #[test]
+    fn endpoints_send_common_headers() {
+        let client = FakeHttpClient::create(|request| async move {
+            let header = |name| {
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|value| value.to_str().unwrap())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            let body = format!(
+                "{};{};{}",
+                header(ORGANIZATION_HEADER),
+                header("X-Gateway-Key"),
+                header("Authorization")
+            );
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(AsyncBody::from(body))
+                .unwrap())
+        });
+        let sent_headers = |extra_headers: &[(&str, &str)]| {
+            let extra_headers = extra_headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>();
+            let error = futures::executor::block_on(list_models(
+                client.as_ref(),
+                &Connection {
+                    organization_id: Some("org-123".into()),
+                    extra_headers,
+                    ..Connection::new(MISTRAL_API_URL, "key")
+                },
+            ))
+            .unwrap_err();
+            error.downcast::<ApiError>().unwrap().message
+        };
+
+        assert_eq!(
+            sent_headers(&[("X-Gateway-Key", "gateway-123")]),
+            "org-123;gateway-123;Bearer key"
+        );
+        // A configured header replaces the standard one instead of being sent alongside it.
+        assert_eq!(
+            sent_headers(&[("authorization", "Basic dXNlcjpwYXNz")]),
+            "org-123;;Basic dXNlcjpwYXNz"
+        );
+    }
+
+    #[test]
+    fn upload_file_encodes_multipart_body() {
+        let client = FakeHttpClient::create(|request| async move {
+            let content_type = request.headers()["Content-Type"].to_str()?.to_string();
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await?;
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(AsyncBody::from(format!("{content_type}\n{body}")))
+                .unwrap())
+        });
+        let upload = |filename| {
+            let error = futures::executor::block_on(upload_file(
+                client.as_ref(),
+                &Connection::new(MISTRAL_API_URL, "key"),
+                filename,
+                FilePurpose::Ocr,
+                b"--zed-mistral-upload\r\n".to_vec(),
+            ))
+            .unwrap_err();
+            let message = error.downcast::<ApiError>().unwrap().message;
+            let (content_type, body) = message.split_once('\n').unwrap();
+            let boundary = content_type
+                .strip_prefix("multipart/form-data; boundary=")
+                .unwrap()
+                .to_string();
+            (boundary, body.to_string())
+        };
+
+        let (boundary, body) = upload("a\"b\r\n.pdf");
+        assert!(body.contains("filename=\"a%22b%0D%0A.pdf\"\r\n"));
+        assert!(body.ends_with(&format!("--zed-mistral-upload\r\n\r\n--{boundary}--\r\n")));
+        assert_eq!(body.matches(boundary.as_str()).count(), 3);
+        assert_ne!(upload("report.pdf").0, boundary);
+    }
+
+    #[test]
+    fn stream_completion_extra_headers() {
+        let client = FakeHttpClient::create(|request| async move {
+            let header = |name| {
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|value| value.to_str().unwrap())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            let body = format!("{};{}", header("X-Gateway-Key"), header("Authorization"));
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(AsyncBody::from(body))
+                .unwrap())
+        });
+        let request = Request {
+            model: "mistral-small-latest".into(),
+            stream: true,
+            ..Default::default()
+        };
+        let extra_headers =
+            BTreeMap::from_iter([("X-Gateway-Key".to_string(), "gateway-123".to_string())]);
+
+        let Err(error) = futures::executor::block_on(stream_completion(
+            client.as_ref(),
+            &Connection {
+                extra_headers,
+                ..Connection::new(MISTRAL_API_URL, "key")
+            },
+            request,
+        )) else {
+            panic!("expected the request to fail");
+        };
+        assert_eq!(
+            error.downcast::<ApiError>().unwrap().message,
+            "gateway-123;Bearer key"
+        );
+    }
+
+    #[test]
+    fn stream_completion_with_agent() {
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let sent = sent.clone();
+            move |request| {
+                let sent = sent.clone();
+                async move {
+                    let path = request.uri().path().to_string();
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await?;
+                    let body: Value = serde_json::from_str(&body)?;
+                    sent.lock().unwrap().push((path, body));
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from("data: [DONE]\n\n"))
+                        .unwrap())
+                }
+            }
+        });
+        let request = |agent_id: Option<&str>| Request {
+            model: "mistral-small-latest".into(),
+            stream: true,
+            agent_id: agent_id.map(Into::into),
+            ..Default::default()
+        };
+
+        for agent_id in [Some("ag-123"), None] {
+            futures::executor::block_on(stream_completion(
+                client.as_ref(),
+                &Connection::new(MISTRAL_API_URL, "key"),
+                request(agent_id),
+            ))
+            .unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[0].0, "/v1/agents/completions");
+        assert_eq!(sent[0].1["agent_id"], "ag-123");
+        assert!(sent[0].1.get("model").is_none());
+        assert_eq!(sent[1].0, "/v1/chat/completions");
+        assert_eq!(sent[1].1["model"], "mistral-small-latest");
+        assert!(sent[1].1.get("agent_id").is_none());
+    }
+
+    #[test]
+    fn validate_min_tokens() {
+        let request = |min_tokens, max_tokens| Request {
+            model: "mistral-small-latest".into(),
+            stream: true,
+            max_tokens,
+            min_tokens,
+            ..Default::default()
+        };
+        assert!(request(Some(16), Some(1024)).validate().is_ok());
+        assert!(request(Some(16), None).validate().is_ok());
+        assert!(request(Some(1024), Some(1024)).validate().is_ok());
+
+        let error = request(Some(2048), Some(1024)).validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "min_tokens (2048) exceeds max_tokens (1024)"
+        );
+
+        let client = FakeHttpClient::create(|_| async move {
+            panic!("invalid requests should not be sent");
+        });
+        assert!(
+            futures::executor::block_on(stream_completion(
+                client.as_ref(),
+                &Connection::new(MISTRAL_API_URL, "key"),
+                request(Some(2048), Some(1024)),
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn create_batch_job_uploads_requests() {
+        let uploaded = Arc::new(std::sync::Mutex::new(String::new()));
+        let client = FakeHttpClient::create({
+            let uploaded = uploaded.clone();
+            move |request| {
+                let uploaded = uploaded.clone();
+                async move {
+                    let path = request.uri().path().to_string();
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await?;
+                    let response = match path.as_str() {
+                        "/v1/files" => {
+                            *uploaded.lock().unwrap() = body;
+                            serde_json::json!({
+                                "id": "file-in",
+                                "object": "file",
+                                "filename": "batch.jsonl",
+                                "bytes": 100,
+                                "purpose": "batch",
+                            })
+                        }
+                        "/v1/batch/jobs" => {
+                            let request: Value = serde_json::from_str(&body)?;
+                            assert_eq!(request["input_files"], serde_json::json!(["file-in"]));
+                            assert_eq!(request["endpoint"], "/v1/chat/completions");
+                            serde_json::json!({
+                                "id": "job-1",
+                                "status": "QUEUED",
+                                "model": request["model"],
+                                "total_requests": 2,
+                            })
+                        }
+                        path => panic!("unexpected request to {path}"),
+                    };
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(AsyncBody::from(response.to_string()))
+                        .unwrap())
+                }
+            }
+        });
+        let request = |custom_id: &str| BatchRequest {
+            custom_id: custom_id.into(),
+            body: Request {
+                model: "mistral-small-latest".into(),
+                messages: vec![RequestMessage::User {
+                    content: MessageContent::Plain {
+                        content: "Summarize this file".into(),
+                    },
+                }],
+                ..Default::default()
+            },
+        };
+
+        let job = futures::executor::block_on(create_batch_job(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            "mistral-small-latest",
+            vec![request("0"), request("1")],
+        ))
+        .unwrap();
+        assert_eq!(job.id, "job-1");
+        assert_eq!(job.status, BatchJobStatus::Queued);
+        assert!(!job.status.is_finished());
+
+        let uploaded = uploaded.lock().unwrap();
+        assert!(uploaded.contains("name=\"purpose\"\r\n\r\nbatch\r\n"));
+        let lines = uploaded
+            .lines()
+            .filter(|line| line.starts_with('{'))
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["custom_id"], "1");
+        assert_eq!(lines[1]["body"]["stream"], false);
+    }
+
+    #[test]
+    fn classify_single_and_multi_label() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/classifications");
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await?;
+            let request: Value = serde_json::from_str(&body)?;
+            assert_eq!(request["model"], "ft:classifier:intent");
+            assert_eq!(request["input"].as_array().unwrap().len(), 2);
+
+            let response = serde_json::json!({
+                "id": "cls-1",
+                "model": "ft:classifier:intent",
+                "results": [
+                    {
+                        "intent": { "scores": { "question": 0.7, "bug_report": 0.2, "spam": 0.1 } },
+                        "topics": { "scores": { "rust": 0.9, "ui": 0.6, "docs": 0.05 } }
+                    },
+                    {
+                        "intent": { "scores": { "question": 0.05, "bug_report": 0.05, "spam": 0.9 } },
+                        "topics": { "scores": { "rust": 0.1, "ui": 0.1, "docs": 0.1 } }
+                    }
+                ]
+            });
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))
+                .unwrap())
+        });
+
+        let results = futures::executor::block_on(classify(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            "ft:classifier:intent",
+            &["How do I split panes?".into(), "Buy now!".into()],
+        ))
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["intent"].top_label(), Some(("question", 0.7)));
+        assert_eq!(results[1]["intent"].top_label(), Some(("spam", 0.9)));
+        assert_eq!(
+            results[0]["topics"].labels_above(0.5),
+            vec![("rust", 0.9), ("ui", 0.6)]
+        );
+        assert!(results[1]["topics"].labels_above(0.5).is_empty());
+    }
+
+    #[test]
+    fn moderate_parses_category_scores() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/moderations");
+            let response = serde_json::json!({
+                "id": "mod-123",
+                "model": "mistral-moderation-latest",
+                "results": [{
+                    "categories": {
+                        "sexual": false,
+                        "hate_and_discrimination": false,
+                        "violence_and_threats": true,
+                        "dangerous_and_criminal_content": false,
+                        "selfharm": false,
+                        "health": false,
+                        "financial": false,
+                        "law": false,
+                        "pii": false
+                    },
+                    "category_scores": {
+                        "sexual": 0.001,
+                        "hate_and_discrimination": 0.002,
+                        "violence_and_threats": 0.93,
+                        "dangerous_and_criminal_content": 0.04,
+                        "selfharm": 0.0,
+                        "health": 0.0,
+                        "financial": 0.0,
+                        "law": 0.01,
+                        "pii": 0.0
+                    }
+                }]
+            });
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))
+                .unwrap())
+        });
+
+        let result = futures::executor::block_on(moderate(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            "some text",
+        ))
+        .unwrap();
+        assert!(result.is_flagged());
+        assert!(result.categories.violence_and_threats);
+        assert_eq!(result.category_scores.violence_and_threats, 0.93);
+    }
+
+    #[test]
+    fn endpoints_use_auth_scheme() {
+        let client = FakeHttpClient::create(|request| async move {
+            let authorization = request
+                .headers()
+                .get("Authorization")
+                .map(|value| value.to_str().unwrap().to_string());
+            Ok(http_client::Response::builder()
+                .status(401)
+                .body(AsyncBody::from(authorization.unwrap_or_default()))
+                .unwrap())
+        });
+        let authorization = |auth_scheme: &str| {
+            let error = futures::executor::block_on(get_batch_job(
+                client.as_ref(),
+                &Connection {
+                    auth_scheme: auth_scheme.into(),
+                    ..Connection::new(MISTRAL_API_URL, "key")
+                },
+                "job-1",
+            ))
+            .unwrap_err();
+            error.downcast::<ApiError>().unwrap().message
+        };
+
+        assert_eq!(authorization(DEFAULT_AUTH_SCHEME), "Bearer key");
+        assert_eq!(authorization("Token"), "Token key");
+        assert_eq!(authorization(""), "key");
+    }
+
+    #[test]
+    fn ocr_returns_pages() {
+        let client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/ocr");
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await?;
+            let request: Value = serde_json::from_str(&body)?;
+            assert_eq!(request["model"], OCR_MODEL);
+            assert_eq!(request["document"]["type"], "document_url");
+            assert!(request.get("document_page_limit").is_none());
+            assert!(request.get("document_image_limit").is_none());
+
+            let response = serde_json::json!({
+                "model": "mistral-ocr-2505",
+                "pages": [
+                    {
+                        "index": 1,
+                        "markdown": "## Results",
+                        "images": [],
+                        "dimensions": { "dpi": 200, "height": 2200, "width": 1700 }
+                    },
+                    {
+                        "index": 0,
+                        "markdown": "# Introduction",
+                        "images": [],
+                        "dimensions": { "dpi": 200, "height": 2200, "width": 1700 }
+                    }
+                ],
+                "usage_info": { "pages_processed": 2, "doc_size_bytes": 1024 }
+            });
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))
+                .unwrap())
+        });
+
+        let response = futures::executor::block_on(ocr(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            OcrDocument::DocumentUrl {
+                document_url: "https://example.com/paper.pdf".into(),
+            },
+            None,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(response.pages.len(), 2);
+        assert_eq!(response.pages[0].index, 0);
+        assert_eq!(
+            response.pages[0].dimensions,
+            Some(OcrPageDimensions {
+                dpi: 200,
+                width: 1700,
+                height: 2200,
+            })
+        );
+        assert_eq!(response.markdown(), "# Introduction\n\n## Results");
+    }
+
+    #[test]
+    fn ocr_limits() {
+        let client = FakeHttpClient::create(|request| async move {
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await?;
+            let request: Value = serde_json::from_str(&body)?;
+            assert_eq!(request["document_page_limit"], 5);
+            assert_eq!(request["document_image_limit"], 2);
+
+            let response = serde_json::json!({ "model": "mistral-ocr-2505", "pages": [] });
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(AsyncBody::from(response.to_string()))
+                .unwrap())
+        });
+        let document = OcrDocument::DocumentUrl {
+            document_url: "https://example.com/paper.pdf".into(),
+        };
+
+        let response = futures::executor::block_on(ocr(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            document.clone(),
+            Some(5),
+            Some(2),
+        ))
+        .unwrap();
+        assert!(response.pages.is_empty());
+
+        let error = futures::executor::block_on(ocr(
+            client.as_ref(),
+            &Connection::new(MISTRAL_API_URL, "key"),
+            document,
+            Some(0),
+            None,
+        ))
+        .unwrap_err();
+        assert_eq!(error.to_string(), "OCR page limit must be positive");
+    }
+
+    #[test]
+    fn collect_streamed_message() {
+        let recording = r#"
+            {"id":"1","object":"chat.completion.chunk","created":0,"model":"mistral-small-latest","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}]}
+            {"id":"1","object":"chat.completion.chunk","created":0,"model":"mistral-small-latest","choices":[{"index":0,"delta":{"content":"Let me "},"finish_reason":null}]}
+            {"id":"1","object":"chat.completion.chunk","created":0,"model":"mistral-small-latest","choices":[{"index":0,"delta":{"content":"look."},"finish_reason":null}]}
+            {"id":"1","object":"chat.completion.chunk","created":0,"model":"mistral-small-latest","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":1,"id":"call_b","function":{"name":"list_dir","arguments":"{\"path\":\".\"}"}},{"index":0,"id":"call_a","function":{"name":"read_file","arguments":"{\"path\":"}}]},"finish_reason":null}]}
+            {"id":"1","object":"chat.completion.chunk","created":0,"model":"mistral-small-latest","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"id":"","function":{"name":"","arguments":"\"a.rs\"}"}}]},"finish_reason":"tool_calls"}],"usage":{"prompt_tokens":10,"completion_tokens":5,"total_tokens":15}}
+        "#;
+        let chunks = recording
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<StreamResponse>(line.trim()).unwrap())
+            .collect::<Vec<_>>();
+
+        let tool_call = |id: &str, name: &str, arguments: &str| ToolCall {
+            id: id.into(),
+            content: ToolCallContent::Function {
+                function: FunctionContent {
+                    name: name.into(),
+                    arguments: arguments.into(),
+                },
+            },
+        };
+        assert_eq!(
+            collect_message(&chunks),
+            RequestMessage::Assistant {
+                content: Some("Let me look.".into()),
+                tool_calls: vec![
+                    tool_call("call_a", "read_file", r#"{"path":"a.rs"}"#),
+                    tool_call("call_b", "list_dir", r#"{"path":"."}"#),
+                ],
+                prefix: false,
+            }
+        );
+    }
+
+    #[test]
+    fn serialize_tool_choice() {
+        assert_eq!(
+            serde_json::to_value(ToolChoice::Any).unwrap(),
+            serde_json::json!("any")
+        );
+        assert_eq!(
+            serde_json::to_value(ToolChoice::function("search")).unwrap(),
+            serde_json::json!({ "type": "function", "function": { "name": "search" } })
         );
     }
 }
@@ -981,6 +981,11 @@ impl RulesLibrary {
                                     tool_choice: None,
                                     stop: Vec::new(),
                                     temperature: None,
+                                    top_p: None,
+                                    frequency_penalty: None,
+                                    presence_penalty: None,
+                                    response_schema: None,
+                                    continue_last_message: false,
                                     thinking_allowed: true,
                                 },
                                 cx,